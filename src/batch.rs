@@ -0,0 +1,88 @@
+use crate::client::Client;
+
+use anyhow::{bail, Context, Result};
+use futures_util::{stream, StreamExt};
+use serde_json::{json, Map, Value};
+use std::fs::{read_to_string, File};
+use std::io::{stdout, Write};
+
+/// Send every prompt of a file and write the replies as JSONL, in the order of the prompts
+pub fn run(
+    client: &dyn Client,
+    path: &str,
+    concurrency: usize,
+    output: Option<&str>,
+) -> Result<()> {
+    if concurrency == 0 {
+        bail!("--concurrency must be at least 1");
+    }
+    let content = read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    let items = parse_items(&content)?;
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => {
+            Box::new(File::create(path).with_context(|| format!("Failed to create {path}"))?)
+        }
+        None => Box::new(stdout()),
+    };
+    client.get_runtime().block_on(async {
+        let mut replies = stream::iter(items)
+            .map(|(input, mut item)| async move {
+                let (key, value) = match client.send_message_async(&input).await {
+                    Ok(output) => ("output", json!(output)),
+                    Err(err) => ("error", json!(format!("{err:#}"))),
+                };
+                item.insert(key.into(), value);
+                item
+            })
+            .buffered(concurrency);
+        while let Some(item) = replies.next().await {
+            writeln!(writer, "{}", Value::Object(item))?;
+            writer.flush()?;
+        }
+        Ok(())
+    })
+}
+
+/// Each non-empty line is a prompt, or a JSON object with an `input` field whose other
+/// fields, e.g. an id, are copied to the result
+fn parse_items(content: &str) -> Result<Vec<(String, Map<String, Value>)>> {
+    let mut items = vec![];
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('{') {
+            let item: Map<String, Value> = serde_json::from_str(line)
+                .with_context(|| format!("Invalid json at line {}", index + 1))?;
+            let input = match item.get("input").and_then(|v| v.as_str()) {
+                Some(v) => v.to_string(),
+                None => bail!("Miss `input` at line {}", index + 1),
+            };
+            items.push((input, item));
+        } else {
+            let mut item = Map::new();
+            item.insert("input".into(), json!(line));
+            items.push((line.to_string(), item));
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_items() {
+        let content = "translate hello\n\n{\"id\": 7, \"input\": \"translate bye\"}\n";
+        let items = parse_items(content).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].0, "translate hello");
+        assert_eq!(
+            Value::Object(items[1].1.clone()),
+            json!({"id": 7, "input": "translate bye"})
+        );
+        assert!(parse_items("{\"id\": 1}").is_err());
+    }
+}
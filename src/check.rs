@@ -0,0 +1,57 @@
+use crate::client::{init_client, ClientType};
+use crate::config::Config;
+
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Exit code when the config or roles cannot be loaded or hold invalid values
+pub const EXIT_INVALID_CONFIG: i32 = 1;
+/// Exit code when the service rejects the api key or cannot be reached
+pub const EXIT_REQUEST_FAILED: i32 = 2;
+
+/// Load the config and roles, validate the values and verify the api key by listing the models.
+/// Prints the outcome of each step and returns the exit code, 0 if all passed.
+pub fn run() -> i32 {
+    let config = match Config::init(false) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("error  {err:#}");
+            return EXIT_INVALID_CONFIG;
+        }
+    };
+    match Config::config_file() {
+        Ok(path) if path.exists() => println!("ok     {}", path.display()),
+        _ => println!("ok     no config file, using the environment"),
+    }
+    let roles = config.roles.iter().filter(|v| !v.is_builtin()).count();
+    println!("ok     roles loaded: {roles}");
+    let problems = config.validate();
+    for problem in &problems {
+        println!("error  {problem}");
+    }
+    if !problems.is_empty() {
+        return EXIT_INVALID_CONFIG;
+    }
+    let client_type = config.client;
+    if config.api_key.is_empty() && client_type != ClientType::Ollama && config.api_base.is_none() {
+        println!(
+            "error  no api key, set `api_key`, `api_key_cmd` or `keyring`, or $AICHAT_API_KEY"
+        );
+        return EXIT_INVALID_CONFIG;
+    }
+    if client_type == ClientType::AzureOpenAI {
+        println!("skip   {client_type} has no models to list, the api key is checked on the first message");
+        return 0;
+    }
+    let result = init_client(Arc::new(Mutex::new(config))).and_then(|client| client.list_models());
+    match result {
+        Ok(models) => {
+            println!("ok     {client_type} answered with {} models", models.len());
+            0
+        }
+        Err(err) => {
+            println!("error  {err:#}");
+            EXIT_REQUEST_FAILED
+        }
+    }
+}
@@ -1,7 +1,17 @@
-use clap::Parser;
+use clap::builder::PossibleValuesParser;
+use clap::{Arg, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use std::io::stdout;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    disable_help_subcommand = true
+)]
 pub struct Cli {
     /// Turn off highlight
     #[clap(short = 'H', long)]
@@ -9,17 +19,204 @@ pub struct Cli {
     /// No stream output
     #[clap(short = 'S', long)]
     pub no_stream: bool,
+    /// Attach files or urls to the prompt
+    #[clap(short, long = "file", value_name = "FILE")]
+    pub files: Vec<String>,
+    /// Write the reply to a file as well
+    #[clap(short, long, value_name = "FILE")]
+    pub output: Option<String>,
+    /// Output format of the reply
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+    /// Generate a shell command and run it after confirmation
+    #[clap(short = 'e', long)]
+    pub execute: bool,
+    /// Output only code, without fences or explanations
+    #[clap(short = 'c', long)]
+    pub code: bool,
+    /// Do not answer from or write to the reply cache
+    #[clap(long)]
+    pub no_cache: bool,
+    /// Write debug logs to <data_dir>/aichat.log
+    #[clap(short, long)]
+    pub verbose: bool,
+    /// Choose a LLM model
+    #[clap(short, long)]
+    pub model: Option<String>,
+    /// Sampling temperature, between 0 and 2
+    #[clap(short, long)]
+    pub temperature: Option<f64>,
+    /// Seed for deterministic sampling
+    #[clap(long)]
+    pub seed: Option<i64>,
+    /// Proxy of the requests, e.g. socks5://127.0.0.1:1080
+    #[clap(long)]
+    pub proxy: Option<String>,
+    /// Print the request instead of sending it
+    #[clap(long)]
+    pub dry_run: bool,
+    /// Resume the most recent conversation
+    #[clap(long)]
+    pub resume: bool,
     /// List all roles
     #[clap(long)]
     pub list_roles: bool,
+    /// List saved sessions
+    #[clap(long)]
+    pub list_sessions: bool,
+    /// Print the settings, files and usage
+    #[clap(long)]
+    pub info: bool,
+    /// Show the api key unmasked in --info
+    #[clap(long, requires = "info")]
+    pub full: bool,
     /// Select a role
     #[clap(short, long)]
     pub role: Option<String>,
+    /// Use a prompt as a temporary role, like `.prompt`
+    #[clap(short, long, conflicts_with_all = ["role", "execute", "code"])]
+    pub prompt: Option<String>,
+    /// Print the completion script of a shell
+    #[clap(long, value_name = "SHELL")]
+    pub completions: Option<Shell>,
     /// Input text
     text: Vec<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage document stores used with `.rag <name>`
+    #[command(subcommand)]
+    Rag(RagCommand),
+    /// Git helpers
+    #[command(subcommand)]
+    Git(GitCommand),
+    /// Save conversations of other chat apps as sessions
+    #[command(subcommand)]
+    Import(ImportCommand),
+    /// Validate config.yaml and roles.yaml and verify the api key, exit 1 if invalid, 2 if rejected
+    Check,
+    /// Store the api key in the OS keychain, read with `keyring: true`
+    #[command(subcommand)]
+    Keyring(KeyringCommand),
+    /// Share role packs listed in `role_repos`
+    #[command(subcommand)]
+    Roles(RolesCommand),
+    /// Send one prompt per line (or JSONL with an `input` field), write replies as JSONL
+    Batch {
+        /// File of prompts
+        file: String,
+        /// Number of requests in flight at once
+        #[clap(short = 'j', long, default_value_t = 4)]
+        concurrency: usize,
+        /// Write the replies to a file instead of stdout
+        #[clap(short, long, value_name = "FILE")]
+        output: Option<String>,
+    },
+    /// Transcribe speech in an audio file, e.g. mp3, m4a or wav
+    Transcribe {
+        /// Audio file
+        file: String,
+        /// Send the transcript to the model as a prompt
+        #[clap(short, long)]
+        chat: bool,
+    },
+    /// Generate an image from a prompt and save it as png
+    Image {
+        /// Description of the image
+        #[clap(required = true)]
+        prompt: Vec<String>,
+        /// File to save the image to, default image-<timestamp>.png
+        #[clap(short, long, value_name = "FILE")]
+        output: Option<String>,
+    },
+    /// Search saved messages and sessions
+    Search {
+        /// Words that all appear in an exchange, ignoring case
+        #[clap(required = true)]
+        query: Vec<String>,
+        /// Treat the query as a regex
+        #[clap(short = 'E', long)]
+        regex: bool,
+    },
+    /// Serve an OpenAI-compatible api at /v1/chat/completions
+    Serve {
+        /// Address to listen on
+        #[clap(short, long, default_value = "127.0.0.1:8000")]
+        address: String,
+        /// Role used when a request does not name one as its model
+        #[clap(short, long)]
+        role: Option<String>,
+        /// Let requests call the tools and MCP servers, if `tools` is enabled
+        #[clap(long)]
+        tools: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RagCommand {
+    /// Embed files and directories into a rag
+    Add {
+        /// Name of the rag
+        name: String,
+        /// Files or directories to embed
+        #[clap(required = true)]
+        paths: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GitCommand {
+    /// Write a commit message for the staged changes and offer to commit with it
+    CommitMsg,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ImportCommand {
+    /// Import the data export of ChatGPT, the zip or its conversations.json
+    Chatgpt {
+        /// Export file
+        file: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyringCommand {
+    /// Ask for the api key of the client and store it
+    Set,
+    /// Remove the stored api key of the client
+    Delete,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RolesCommand {
+    /// Download or update the role packs into the roles dir
+    Sync,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
 }
 
 impl Cli {
+    /// Write the completion script of the shell to stdout, completing `--role` with the roles
+    pub fn print_completions(shell: Shell, roles: &[String]) {
+        let mut command = Self::command();
+        if !roles.is_empty() {
+            let complete_roles = |arg: Arg| arg.value_parser(PossibleValuesParser::new(roles));
+            command = command
+                .mut_arg("role", complete_roles)
+                .mut_subcommand("serve", |v| v.mut_arg("role", complete_roles));
+        }
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut stdout());
+    }
+
     pub fn text(&self) -> Option<String> {
         let text = self
             .text
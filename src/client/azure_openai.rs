@@ -0,0 +1,135 @@
+use super::openai::{
+    openai_build_body, openai_embeddings, openai_send_message, openai_send_message_streaming,
+};
+use super::{build_http_client, Client, LazyRuntime};
+
+use crate::config::{SharedConfig, Usage};
+use crate::repl::ReplyStreamHandler;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use reqwest::RequestBuilder;
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+
+const API_VERSION: &str = "2024-02-01";
+
+#[derive(Debug)]
+pub struct AzureOpenAIClient {
+    config: SharedConfig,
+    runtime: LazyRuntime,
+}
+
+#[async_trait]
+impl Client for AzureOpenAIClient {
+    fn get_config(&self) -> &SharedConfig {
+        &self.config
+    }
+
+    fn get_runtime(&self) -> &Runtime {
+        self.runtime.get()
+    }
+
+    fn get_model(&self) -> String {
+        // Azure routes by deployment, the model field is only informational
+        let config = self.config.lock();
+        config
+            .get_model()
+            .or_else(|| config.deployment_id.clone())
+            .unwrap_or_default()
+    }
+
+    fn models(&self) -> Vec<String> {
+        self.config.lock().deployment_id.iter().cloned().collect()
+    }
+
+    fn get_embedding_model(&self) -> String {
+        // the name of an embedding deployment
+        self.config
+            .lock()
+            .embedding_model
+            .clone()
+            .unwrap_or_default()
+    }
+
+    async fn embeddings_inner(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let deployment_id = self.get_embedding_model();
+        if deployment_id.is_empty() {
+            bail!(
+                "Miss config.embedding_model for azure-openai, set it to an embedding deployment"
+            );
+        }
+        let url = self.deployment_url(&deployment_id, "embeddings")?;
+        let api_key = self.config.lock().get_api_key()?;
+        let builder = build_http_client(&self.config)?
+            .post(url)
+            .header("api-key", api_key)
+            .json(&json!({ "input": texts }));
+        openai_embeddings(builder).await
+    }
+
+    fn chat_request(&self, content: &str, stream: bool) -> Result<RequestBuilder> {
+        let body = openai_build_body(&self.config, content, &self.get_model(), stream)?;
+        self.request_builder(&body)
+    }
+
+    async fn send_message_inner(&self, content: &str) -> Result<(String, Option<Usage>)> {
+        let body = openai_build_body(&self.config, content, &self.get_model(), false)?;
+        openai_send_message(&self.config, body, |v| self.request_builder(v)).await
+    }
+
+    async fn send_message_streaming_inner(
+        &self,
+        content: &str,
+        handler: &mut ReplyStreamHandler,
+    ) -> Result<()> {
+        let body = openai_build_body(&self.config, content, &self.get_model(), true)?;
+        openai_send_message_streaming(&self.config, body, |v| self.request_builder(v), handler)
+            .await
+    }
+}
+
+impl AzureOpenAIClient {
+    pub fn init(config: SharedConfig) -> Result<Self> {
+        let s = Self {
+            config,
+            runtime: LazyRuntime::default(),
+        };
+        let _ = build_http_client(&s.config)?; // check error
+        let _ = s.api_url()?; // check error
+        Ok(s)
+    }
+
+    fn api_url(&self) -> Result<String> {
+        let deployment_id = self
+            .config
+            .lock()
+            .deployment_id
+            .clone()
+            .ok_or_else(|| anyhow!("Miss config.deployment_id for azure-openai"))?;
+        self.deployment_url(&deployment_id, "chat/completions")
+    }
+
+    fn deployment_url(&self, deployment_id: &str, path: &str) -> Result<String> {
+        let config = self.config.lock();
+        let api_base = config
+            .api_base
+            .as_ref()
+            .ok_or_else(|| anyhow!("Miss config.api_base for azure-openai"))?;
+        let api_version = config.api_version.as_deref().unwrap_or(API_VERSION);
+        Ok(format!(
+            "{}/openai/deployments/{deployment_id}/{path}?api-version={api_version}",
+            api_base.trim_end_matches('/')
+        ))
+    }
+
+    fn request_builder(&self, body: &Value) -> Result<RequestBuilder> {
+        let api_key = self.config.lock().get_api_key()?;
+        let builder = build_http_client(&self.config)?
+            .post(self.api_url()?)
+            .header("api-key", api_key)
+            .json(body);
+
+        Ok(builder)
+    }
+}
@@ -0,0 +1,284 @@
+use super::{
+    build_http_client, check_api_key, record_finish_reason, send_request, Client, LazyRuntime,
+};
+
+use crate::config::{split_data_url, Message, MessageRole, SharedConfig, Usage};
+use crate::repl::ReplyStreamHandler;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use eventsource_stream::Eventsource;
+use futures_util::StreamExt;
+use reqwest::RequestBuilder;
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+
+const API_URL: &str = "https://api.anthropic.com/v1/messages";
+const MODELS_API_URL: &str = "https://api.anthropic.com/v1/models";
+const API_VERSION: &str = "2023-06-01";
+pub const MODEL: &str = "claude-3-haiku-20240307";
+const MODELS: [&str; 3] = [
+    "claude-3-haiku-20240307",
+    "claude-3-sonnet-20240229",
+    "claude-3-opus-20240229",
+];
+/// Claude requires `max_tokens` on every request, used if not configured
+const MAX_TOKENS: usize = 4096;
+
+#[derive(Debug)]
+pub struct ClaudeClient {
+    config: SharedConfig,
+    runtime: LazyRuntime,
+}
+
+#[async_trait]
+impl Client for ClaudeClient {
+    fn get_config(&self) -> &SharedConfig {
+        &self.config
+    }
+
+    fn get_runtime(&self) -> &Runtime {
+        self.runtime.get()
+    }
+
+    fn get_model(&self) -> String {
+        self.config
+            .lock()
+            .get_model()
+            .unwrap_or_else(|| MODEL.into())
+    }
+
+    fn models(&self) -> Vec<String> {
+        MODELS.into_iter().map(|v| v.to_string()).collect()
+    }
+
+    async fn list_models_inner(&self) -> Result<Vec<String>> {
+        let api_key = self.config.lock().get_api_key()?;
+        let builder = build_http_client(&self.config)?
+            .get(MODELS_API_URL)
+            .header("x-api-key", api_key)
+            .header("anthropic-version", API_VERSION);
+        let data: Value = send_request(builder).await?.json().await?;
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+        let models = data["data"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?
+            .iter()
+            .filter_map(|v| v["id"].as_str().map(|v| v.to_string()))
+            .collect();
+        Ok(models)
+    }
+
+    fn chat_request(&self, content: &str, stream: bool) -> Result<RequestBuilder> {
+        self.request_builder(content, stream)
+    }
+
+    async fn send_message_inner(&self, content: &str) -> Result<(String, Option<Usage>)> {
+        let builder = self.request_builder(content, false)?;
+        let data: Value = check_api_key(send_request(builder).await?)
+            .await?
+            .json()
+            .await?;
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+
+        record_finish_reason(&self.config, &data["stop_reason"]);
+        let output = data["content"][0]["text"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?;
+        let usage = match (
+            data["usage"]["input_tokens"].as_u64(),
+            data["usage"]["output_tokens"].as_u64(),
+        ) {
+            (Some(input_tokens), Some(output_tokens)) => {
+                Some(Usage::new(input_tokens as usize, output_tokens as usize))
+            }
+            _ => None,
+        };
+
+        Ok((output.to_string(), usage))
+    }
+
+    async fn send_message_streaming_inner(
+        &self,
+        content: &str,
+        handler: &mut ReplyStreamHandler,
+    ) -> Result<()> {
+        let builder = self.request_builder(content, true)?;
+        let res = check_api_key(send_request(builder).await?).await?;
+        if !res.status().is_success() {
+            let data: Value = res.json().await?;
+            if let Some(err_msg) = data["error"]["message"].as_str() {
+                bail!("Request failed, {err_msg}");
+            }
+            bail!("Request failed");
+        }
+        let mut stream = res.bytes_stream().eventsource();
+        while let Some(part) = stream.next().await {
+            let event = part?;
+            handler.touch();
+            match event.event.as_str() {
+                "content_block_delta" => {
+                    let data: Value = serde_json::from_str(&event.data)?;
+                    let text = data["delta"]["text"].as_str().unwrap_or_default();
+                    if text.is_empty() {
+                        continue;
+                    }
+                    handler.text(text)?;
+                }
+                "message_delta" => {
+                    let data: Value = serde_json::from_str(&event.data)?;
+                    record_finish_reason(&self.config, &data["delta"]["stop_reason"]);
+                }
+                "message_stop" => break,
+                "error" => {
+                    let data: Value = serde_json::from_str(&event.data)?;
+                    let err_msg = data["error"]["message"].as_str().unwrap_or_default();
+                    bail!("Request failed, {err_msg}");
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ClaudeClient {
+    pub fn init(config: SharedConfig) -> Result<Self> {
+        let s = Self {
+            config,
+            runtime: LazyRuntime::default(),
+        };
+        let _ = build_http_client(&s.config)?; // check error
+        Ok(s)
+    }
+
+    fn request_builder(&self, content: &str, stream: bool) -> Result<RequestBuilder> {
+        let messages = self.config.lock().build_messages(content)?;
+        let max_tokens = self.config.lock().get_max_tokens().unwrap_or(MAX_TOKENS);
+        let mut body = build_body(&messages, &self.get_model(), max_tokens);
+
+        if let Some(v) = self.config.lock().get_temperature() {
+            body.as_object_mut()
+                .and_then(|m| m.insert("temperature".into(), json!(v)));
+        }
+
+        // Claude has no presence/frequency penalties nor seed
+        if let Some(v) = self.config.lock().get_top_p() {
+            body.as_object_mut()
+                .and_then(|m| m.insert("top_p".into(), json!(v)));
+        }
+
+        if let Some(v) = self.config.lock().get_stop() {
+            body.as_object_mut()
+                .and_then(|m| m.insert("stop_sequences".into(), json!(v)));
+        }
+
+        if stream {
+            body.as_object_mut()
+                .and_then(|m| m.insert("stream".into(), json!(true)));
+        }
+
+        let api_key = self.config.lock().get_api_key()?;
+        let builder = build_http_client(&self.config)?
+            .post(API_URL)
+            .header("x-api-key", api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(&body);
+
+        Ok(builder)
+    }
+}
+
+/// Claude takes the system prompt as a top-level field instead of a message
+fn build_body(messages: &[Message], model: &str, max_tokens: usize) -> Value {
+    let mut system = vec![];
+    let mut chat_messages = vec![];
+    for message in messages {
+        match message.role {
+            MessageRole::System => system.push(message.content.as_str()),
+            MessageRole::User | MessageRole::Assistant => {
+                chat_messages.push(build_message(message))
+            }
+        }
+    }
+    let mut body = json!({
+        "model": model,
+        "messages": chat_messages,
+        "max_tokens": max_tokens,
+    });
+    if !system.is_empty() {
+        body.as_object_mut()
+            .and_then(|m| m.insert("system".into(), json!(system.join("\n\n"))));
+    }
+    body
+}
+
+/// Images go in content blocks before the text
+fn build_message(message: &Message) -> Value {
+    if message.images.is_empty() {
+        return json!({ "role": message.role.as_str(), "content": message.content });
+    }
+    let mut content: Vec<Value> = message
+        .images
+        .iter()
+        .filter_map(|v| split_data_url(v))
+        .map(|(media_type, data)| {
+            json!({
+                "type": "image",
+                "source": { "type": "base64", "media_type": media_type, "data": data },
+            })
+        })
+        .collect();
+    content.push(json!({ "type": "text", "text": message.content }));
+    json!({ "role": message.role.as_str(), "content": content })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_body() {
+        let messages = vec![
+            Message {
+                role: MessageRole::System,
+                content: "You are a pirate".into(),
+                images: vec![],
+            },
+            Message::new("Hello"),
+        ];
+        assert_eq!(
+            build_body(&messages, MODEL, MAX_TOKENS),
+            json!({
+                "model": MODEL,
+                "system": "You are a pirate",
+                "messages": [{"role": "user", "content": "Hello"}],
+                "max_tokens": MAX_TOKENS,
+            })
+        );
+    }
+
+    #[test]
+    fn test_build_message_with_images() {
+        let mut message = Message::new("What is this?");
+        message.images = vec!["data:image/png;base64,iVBORw0KGgo=".into()];
+        assert_eq!(
+            build_message(&message),
+            json!({
+                "role": "user",
+                "content": [
+                    {
+                        "type": "image",
+                        "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo="},
+                    },
+                    {"type": "text", "text": "What is this?"},
+                ],
+            })
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use super::{
+    build_http_client, check_api_key, record_finish_reason, send_request, Client, LazyRuntime,
+};
+
+use crate::config::{split_data_url, Message, MessageRole, SharedConfig, Usage};
+use crate::repl::ReplyStreamHandler;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use eventsource_stream::Eventsource;
+use futures_util::StreamExt;
+use reqwest::RequestBuilder;
+use serde_json::{json, Map, Value};
+use tokio::runtime::Runtime;
+
+const API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+pub const MODEL: &str = "gemini-1.5-flash";
+const EMBEDDING_MODEL: &str = "text-embedding-004";
+const MODELS: [&str; 3] = ["gemini-1.5-flash", "gemini-1.5-pro", "gemini-1.0-pro"];
+
+#[derive(Debug)]
+pub struct GeminiClient {
+    config: SharedConfig,
+    runtime: LazyRuntime,
+}
+
+#[async_trait]
+impl Client for GeminiClient {
+    fn get_config(&self) -> &SharedConfig {
+        &self.config
+    }
+
+    fn get_runtime(&self) -> &Runtime {
+        self.runtime.get()
+    }
+
+    fn get_model(&self) -> String {
+        self.config
+            .lock()
+            .get_model()
+            .unwrap_or_else(|| MODEL.into())
+    }
+
+    fn models(&self) -> Vec<String> {
+        MODELS.into_iter().map(|v| v.to_string()).collect()
+    }
+
+    async fn list_models_inner(&self) -> Result<Vec<String>> {
+        let api_key = self.config.lock().get_api_key()?;
+        let builder = build_http_client(&self.config)?.get(format!("{API_BASE}?key={api_key}"));
+        let data: Value = send_request(builder).await?.json().await?;
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+        let models = data["models"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?
+            .iter()
+            .filter(|v| {
+                v["supportedGenerationMethods"]
+                    .as_array()
+                    .map(|methods| methods.iter().any(|m| m == "generateContent"))
+                    .unwrap_or_default()
+            })
+            .filter_map(|v| v["name"].as_str())
+            .map(|v| v.trim_start_matches("models/").to_string())
+            .collect();
+        Ok(models)
+    }
+
+    fn get_embedding_model(&self) -> String {
+        self.config
+            .lock()
+            .embedding_model
+            .clone()
+            .unwrap_or_else(|| EMBEDDING_MODEL.into())
+    }
+
+    async fn embeddings_inner(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let model = self.get_embedding_model();
+        let api_key = self.config.lock().get_api_key()?;
+        let url = format!("{API_BASE}/{model}:batchEmbedContents?key={api_key}");
+        let body = build_embeddings_body(&model, texts);
+        let builder = build_http_client(&self.config)?.post(url).json(&body);
+        let data: Value = check_api_key(send_request(builder).await?)
+            .await?
+            .json()
+            .await?;
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+        data["embeddings"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?
+            .iter()
+            .map(|v| {
+                serde_json::from_value(v["values"].clone())
+                    .map_err(|_| anyhow!("Unexpected response {data}"))
+            })
+            .collect()
+    }
+
+    fn chat_request(&self, content: &str, stream: bool) -> Result<RequestBuilder> {
+        self.request_builder(content, stream)
+    }
+
+    async fn send_message_inner(&self, content: &str) -> Result<(String, Option<Usage>)> {
+        let builder = self.request_builder(content, false)?;
+        let data: Value = check_api_key(send_request(builder).await?)
+            .await?
+            .json()
+            .await?;
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+
+        record_finish_reason(&self.config, &data["candidates"][0]["finishReason"]);
+        let output = data["candidates"][0]["content"]["parts"][0]["text"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?;
+        let usage = match (
+            data["usageMetadata"]["promptTokenCount"].as_u64(),
+            data["usageMetadata"]["candidatesTokenCount"].as_u64(),
+        ) {
+            (Some(prompt_tokens), Some(candidates_tokens)) => Some(Usage::new(
+                prompt_tokens as usize,
+                candidates_tokens as usize,
+            )),
+            _ => None,
+        };
+
+        Ok((output.to_string(), usage))
+    }
+
+    async fn send_message_streaming_inner(
+        &self,
+        content: &str,
+        handler: &mut ReplyStreamHandler,
+    ) -> Result<()> {
+        let builder = self.request_builder(content, true)?;
+        let res = check_api_key(send_request(builder).await?).await?;
+        if !res.status().is_success() {
+            let data: Value = res.json().await?;
+            if let Some(err_msg) = data["error"]["message"].as_str() {
+                bail!("Request failed, {err_msg}");
+            }
+            bail!("Request failed");
+        }
+        let mut stream = res.bytes_stream().eventsource();
+        while let Some(part) = stream.next().await {
+            let chunk = part?.data;
+            handler.touch();
+            let data: Value = serde_json::from_str(&chunk)?;
+            if let Some(err_msg) = data["error"]["message"].as_str() {
+                bail!("Request failed, {err_msg}");
+            }
+            record_finish_reason(&self.config, &data["candidates"][0]["finishReason"]);
+            let text = data["candidates"][0]["content"]["parts"][0]["text"]
+                .as_str()
+                .unwrap_or_default();
+            if text.is_empty() {
+                continue;
+            }
+            handler.text(text)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl GeminiClient {
+    pub fn init(config: SharedConfig) -> Result<Self> {
+        let s = Self {
+            config,
+            runtime: LazyRuntime::default(),
+        };
+        let _ = build_http_client(&s.config)?; // check error
+        Ok(s)
+    }
+
+    fn request_builder(&self, content: &str, stream: bool) -> Result<RequestBuilder> {
+        let messages = self.config.lock().build_messages(content)?;
+        let model = self.get_model();
+        let mut body = build_body(&messages);
+
+        let mut generation_config = Map::new();
+        if let Some(v) = self.config.lock().get_temperature() {
+            generation_config.insert("temperature".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().get_max_tokens() {
+            generation_config.insert("maxOutputTokens".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().get_top_p() {
+            generation_config.insert("topP".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().get_presence_penalty() {
+            generation_config.insert("presencePenalty".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().get_frequency_penalty() {
+            generation_config.insert("frequencyPenalty".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().seed {
+            generation_config.insert("seed".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().get_stop() {
+            generation_config.insert("stopSequences".into(), json!(v));
+        }
+        if !generation_config.is_empty() {
+            body.as_object_mut()
+                .and_then(|m| m.insert("generationConfig".into(), generation_config.into()));
+        }
+
+        let api_key = self.config.lock().get_api_key()?;
+        let url = if stream {
+            format!("{API_BASE}/{model}:streamGenerateContent?alt=sse&key={api_key}")
+        } else {
+            format!("{API_BASE}/{model}:generateContent?key={api_key}")
+        };
+        let builder = build_http_client(&self.config)?.post(url).json(&body);
+
+        Ok(builder)
+    }
+}
+
+/// Gemini names the assistant `model` and takes system prompts separately
+fn build_body(messages: &[Message]) -> Value {
+    let mut system = vec![];
+    let mut contents = vec![];
+    for message in messages {
+        let role = match message.role {
+            MessageRole::System => {
+                system.push(json!({ "text": message.content }));
+                continue;
+            }
+            MessageRole::User => "user",
+            MessageRole::Assistant => "model",
+        };
+        let mut parts = vec![json!({ "text": message.content })];
+        for (mime_type, data) in message.images.iter().filter_map(|v| split_data_url(v)) {
+            parts.push(json!({ "inline_data": { "mime_type": mime_type, "data": data } }));
+        }
+        contents.push(json!({ "role": role, "parts": parts }));
+    }
+    let mut body = json!({ "contents": contents });
+    if !system.is_empty() {
+        body.as_object_mut()
+            .and_then(|m| m.insert("systemInstruction".into(), json!({ "parts": system })));
+    }
+    body
+}
+
+fn build_embeddings_body(model: &str, texts: &[String]) -> Value {
+    let requests: Vec<Value> = texts
+        .iter()
+        .map(|text| {
+            json!({
+                "model": format!("models/{model}"),
+                "content": { "parts": [{ "text": text }] },
+            })
+        })
+        .collect();
+    json!({ "requests": requests })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_body() {
+        let messages = vec![
+            Message {
+                role: MessageRole::System,
+                content: "You are a pirate".into(),
+                images: vec![],
+            },
+            Message::new("Hello"),
+            Message {
+                role: MessageRole::Assistant,
+                content: "Ahoy".into(),
+                images: vec![],
+            },
+        ];
+        assert_eq!(
+            build_body(&messages),
+            json!({
+                "systemInstruction": { "parts": [{ "text": "You are a pirate" }] },
+                "contents": [
+                    { "role": "user", "parts": [{ "text": "Hello" }] },
+                    { "role": "model", "parts": [{ "text": "Ahoy" }] },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_build_embeddings_body() {
+        let body = build_embeddings_body("text-embedding-004", &["a".into(), "b".into()]);
+        assert_eq!(
+            body,
+            json!({
+                "requests": [
+                    {"model": "models/text-embedding-004", "content": {"parts": [{"text": "a"}]}},
+                    {"model": "models/text-embedding-004", "content": {"parts": [{"text": "b"}]}},
+                ]
+            })
+        );
+    }
+}
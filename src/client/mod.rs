@@ -0,0 +1,748 @@
+mod azure_openai;
+mod claude;
+mod gemini;
+mod ollama;
+mod openai;
+
+pub use self::azure_openai::AzureOpenAIClient;
+pub use self::claude::ClaudeClient;
+pub use self::gemini::GeminiClient;
+pub use self::ollama::OllamaClient;
+pub use self::openai::OpenAIClient;
+
+use crate::config::{mask_api_key, num_tokens_from_messages, SharedConfig, Usage};
+use crate::repl::{AbortSignal, ReplyStreamEvent, ReplyStreamHandler, SharedAbortSignal};
+use crate::utils::count_tokens;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use crossbeam::channel::{unbounded, Receiver};
+use futures_util::future::{poll_fn, BoxFuture, FutureExt};
+use futures_util::stream::{self, BoxStream, Stream, StreamExt};
+use log::{debug, log_enabled, warn, Level};
+use parking_lot::Mutex;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{
+    Certificate, Client as ReqwestClient, ClientBuilder, Identity, Proxy, Request, RequestBuilder,
+    Response, StatusCode, Url,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, OnceLock,
+};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::time::sleep;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Services cap the number of inputs of one embeddings request
+const EMBEDDINGS_BATCH_SIZE: usize = 96;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientType {
+    #[default]
+    OpenAI,
+    #[serde(rename = "azure-openai")]
+    AzureOpenAI,
+    Claude,
+    Ollama,
+    Gemini,
+}
+
+impl fmt::Display for ClientType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ClientType::OpenAI => "openai",
+            ClientType::AzureOpenAI => "azure-openai",
+            ClientType::Claude => "claude",
+            ClientType::Ollama => "ollama",
+            ClientType::Gemini => "gemini",
+        };
+        f.write_str(name)
+    }
+}
+
+impl ClientType {
+    /// Model used when none is configured
+    pub fn default_model(&self) -> Option<&'static str> {
+        match self {
+            ClientType::OpenAI => Some(openai::MODEL),
+            ClientType::AzureOpenAI => None,
+            ClientType::Claude => Some(claude::MODEL),
+            ClientType::Ollama => Some(ollama::MODEL),
+            ClientType::Gemini => Some(gemini::MODEL),
+        }
+    }
+}
+
+/// The service rejected the api key or rate limited it, another key may work
+#[derive(Debug)]
+pub struct ApiKeyError {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl fmt::Display for ApiKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request failed with {}, {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for ApiKeyError {}
+
+#[async_trait]
+pub trait Client: Sync {
+    fn get_config(&self) -> &SharedConfig;
+
+    fn get_runtime(&self) -> &Runtime;
+
+    /// Model used for the next request
+    fn get_model(&self) -> String;
+
+    /// Models supported by this client
+    fn models(&self) -> Vec<String>;
+
+    /// Models the configured api key can access
+    fn list_models(&self) -> Result<Vec<String>> {
+        self.get_runtime().block_on(async {
+            self.list_models_inner()
+                .await
+                .with_context(|| "Failed to list models")
+        })
+    }
+
+    /// Model used to embed documents and questions for rag
+    fn get_embedding_model(&self) -> String {
+        String::new()
+    }
+
+    /// Embed texts into vectors, large inputs are sent in batches
+    fn embeddings(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.get_runtime().block_on(async {
+            let mut vectors = vec![];
+            for batch in texts.chunks(EMBEDDINGS_BATCH_SIZE) {
+                let timeout = self.get_config().lock().timeout;
+                let batch_vectors = loop {
+                    match with_timeout(timeout, self.embeddings_inner(batch)).await {
+                        Err(err) if self.try_next_api_key(&err) => continue,
+                        ret => break ret.with_context(|| "Failed to embed")?,
+                    }
+                };
+                if batch_vectors.len() != batch.len() {
+                    bail!(
+                        "Failed to embed, got {} vectors for {} texts",
+                        batch_vectors.len(),
+                        batch.len()
+                    );
+                }
+                vectors.extend(batch_vectors);
+            }
+            Ok(vectors)
+        })
+    }
+
+    /// Turn the speech in an audio file into text
+    fn transcribe(&self, path: &Path) -> Result<String> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let file_name = path
+            .file_name()
+            .map(|v| v.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.get_runtime().block_on(async {
+            let timeout = self.get_config().lock().timeout;
+            loop {
+                match with_timeout(timeout, self.transcribe_inner(&file_name, data.clone())).await {
+                    Err(err) if self.try_next_api_key(&err) => continue,
+                    ret => break ret.with_context(|| "Failed to transcribe"),
+                }
+            }
+        })
+    }
+
+    /// A png image drawn from the prompt
+    fn generate_image(&self, prompt: &str) -> Result<Vec<u8>> {
+        self.get_runtime().block_on(async {
+            let timeout = self.get_config().lock().timeout;
+            loop {
+                match with_timeout(timeout, self.generate_image_inner(prompt)).await {
+                    Err(err) if self.try_next_api_key(&err) => continue,
+                    ret => break ret.with_context(|| "Failed to generate image"),
+                }
+            }
+        })
+    }
+
+    /// Audio of the text read aloud, None when the service has no text-to-speech
+    fn speech(&self, text: &str) -> Result<Option<Vec<u8>>> {
+        self.get_runtime().block_on(async {
+            let timeout = self.get_config().lock().timeout;
+            loop {
+                match with_timeout(timeout, self.speech_inner(text)).await {
+                    Err(err) if self.try_next_api_key(&err) => continue,
+                    ret => break ret.with_context(|| "Failed to fetch speech"),
+                }
+            }
+        })
+    }
+
+    fn send_message(&self, content: &str) -> Result<String> {
+        self.get_runtime()
+            .block_on(self.send_message_async(content))
+    }
+
+    /// Like `send_message`, for sending several messages concurrently on the client's runtime
+    async fn send_message_async(&self, content: &str) -> Result<String> {
+        if self.get_config().lock().dry_run {
+            return self.dry_run(content, false);
+        }
+        let model = self.get_model();
+        if let Some(output) = self.get_config().lock().get_cached_reply(&model, content)? {
+            return Ok(output);
+        }
+        self.get_config().lock().check_budget()?;
+        let timeout = self.get_config().lock().timeout;
+        self.get_config().lock().finish_reason = None;
+        let (output, usage) = loop {
+            match with_timeout(timeout, self.send_message_inner(content)).await {
+                Err(err) if self.try_next_api_key(&err) => continue,
+                ret => break ret.with_context(|| "Failed to fetch")?,
+            }
+        };
+        let usage = match usage {
+            Some(usage) => usage,
+            None => self.estimate_usage(content, &output)?,
+        };
+        self.record_usage(usage);
+        self.get_config()
+            .lock()
+            .cache_reply(&model, content, &output)?;
+        Ok(output)
+    }
+
+    fn send_message_streaming(
+        &self,
+        content: &str,
+        handler: &mut ReplyStreamHandler,
+    ) -> Result<()> {
+        async fn watch_abort(abort: SharedAbortSignal) {
+            loop {
+                if abort.aborted() {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+        async fn watch_first_token(received: Arc<AtomicBool>, timeout: Option<u64>) -> u64 {
+            let secs = match timeout {
+                Some(v) => v,
+                None => return std::future::pending().await,
+            };
+            sleep(Duration::from_secs(secs)).await;
+            if received.load(Ordering::SeqCst) {
+                return std::future::pending().await;
+            }
+            secs
+        }
+        let abort = handler.get_abort();
+        let received = handler.get_received();
+        let first_token_timeout = self.get_config().lock().stream_first_token_timeout;
+        self.get_runtime().block_on(async {
+            tokio::select! {
+                ret = self.send_message_streaming_async(content, handler) => {
+                    handler.done()?;
+                    ret.with_context(|| "Failed to fetch stream")
+                }
+                _ = watch_abort(abort.clone()) => {
+                    handler.done()?;
+                    Ok(())
+                 },
+                secs = watch_first_token(received, first_token_timeout) => {
+                    warn!("No reply within {secs}s");
+                    handler.done()?;
+                    bail!("Failed to fetch stream, no reply within {secs}s")
+                },
+                _ =  tokio::signal::ctrl_c() => {
+                    abort.set_ctrlc();
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    /// Stream the reply into the handler without blocking, abort and timeouts are up to the caller
+    async fn send_message_streaming_async(
+        &self,
+        content: &str,
+        handler: &mut ReplyStreamHandler,
+    ) -> Result<()> {
+        if self.get_config().lock().dry_run {
+            let content = self.dry_run(content, true)?;
+            handler.text(&content)?;
+            return Ok(());
+        }
+        let model = self.get_model();
+        let cached = self.get_config().lock().get_cached_reply(&model, content)?;
+        if let Some(output) = cached {
+            handler.text(&output)?;
+            return Ok(());
+        }
+        self.get_config().lock().check_budget()?;
+        let timeout = self.get_config().lock().timeout;
+        self.get_config().lock().finish_reason = None;
+        let last_event = handler.get_last_event();
+        loop {
+            // the wait for the first event is up to stream_first_token_timeout
+            *last_event.lock() = None;
+            let ret = tokio::select! {
+                ret = self.send_message_streaming_inner(content, handler) => ret,
+                secs = watch_idle(last_event.clone(), timeout) => {
+                    warn!("Stream stalled for {secs}s");
+                    Err(anyhow!("Stream stalled, no data for {secs}s"))
+                }
+            };
+            match ret {
+                Err(err) if self.try_next_api_key(&err) => continue,
+                ret => break ret?,
+            }
+        }
+        let usage = self.estimate_usage(content, handler.get_buffer())?;
+        self.record_usage(usage);
+        self.get_config()
+            .lock()
+            .cache_reply(&model, content, handler.get_buffer())?;
+        Ok(())
+    }
+
+    /// The reply as a stream of text chunks, for use inside an existing tokio runtime
+    fn send_message_stream<'a>(&'a self, content: &'a str) -> BoxStream<'a, Result<String>> {
+        let (tx, rx) = unbounded();
+        let producer = async move {
+            let mut handler = ReplyStreamHandler::new(Some(tx), false, AbortSignal::new());
+            self.send_message_streaming_async(content, &mut handler)
+                .await
+                .with_context(|| "Failed to fetch stream")
+        };
+        poll_stream(producer.boxed(), rx).boxed()
+    }
+
+    /// The request that would be sent, with api keys masked
+    fn dry_run(&self, content: &str, stream: bool) -> Result<String> {
+        let request = self
+            .chat_request(content, stream)?
+            .build()
+            .with_context(|| "Failed to build request")?;
+        let extra_headers = build_extra_headers(&self.get_config().lock().extra_headers)?;
+        Ok(format_request(&request, &extra_headers))
+    }
+
+    fn try_next_api_key(&self, err: &anyhow::Error) -> bool {
+        let rotated = err.downcast_ref::<ApiKeyError>().is_some()
+            && self.get_config().lock().rotate_api_key();
+        if rotated {
+            warn!("Retrying with the next api key, {err}");
+        }
+        rotated
+    }
+
+    /// Used when the service does not report usage, e.g. streaming
+    fn estimate_usage(&self, content: &str, output: &str) -> Result<Usage> {
+        let messages = self.get_config().lock().build_messages(content)?;
+        Ok(Usage::new(
+            num_tokens_from_messages(&messages),
+            count_tokens(output),
+        ))
+    }
+
+    fn record_usage(&self, mut usage: Usage) {
+        usage.estimate_cost(&self.get_model());
+        let mut config = self.get_config().lock();
+        config.record_usage(&usage);
+        if let Err(err) = config.record_spend(usage.cost) {
+            warn!("Failed to record spend, {err}");
+        }
+    }
+
+    async fn list_models_inner(&self) -> Result<Vec<String>> {
+        Ok(self.models())
+    }
+
+    async fn embeddings_inner(&self, _texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Err(anyhow!(
+            "Error: Embeddings are not supported by this client"
+        ))
+    }
+
+    async fn transcribe_inner(&self, _file_name: &str, _data: Vec<u8>) -> Result<String> {
+        Err(anyhow!(
+            "Error: Transcription is not supported by this client"
+        ))
+    }
+
+    async fn generate_image_inner(&self, _prompt: &str) -> Result<Vec<u8>> {
+        Err(anyhow!(
+            "Error: Image generation is not supported by this client"
+        ))
+    }
+
+    async fn speech_inner(&self, _text: &str) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// The chat completion request for `content`, also shown by `dry_run`
+    fn chat_request(&self, content: &str, stream: bool) -> Result<RequestBuilder>;
+
+    async fn send_message_inner(&self, content: &str) -> Result<(String, Option<Usage>)>;
+
+    async fn send_message_streaming_inner(
+        &self,
+        content: &str,
+        handler: &mut ReplyStreamHandler,
+    ) -> Result<()>;
+}
+
+/// Keep the finish reason of a reply, `value` is null until the service reports it
+pub fn record_finish_reason(config: &SharedConfig, value: &Value) {
+    if let Some(reason) = value.as_str() {
+        config.lock().finish_reason = Some(reason.to_string());
+    }
+}
+
+/// Give up on `future` after `timeout` seconds, if set
+async fn with_timeout<T>(
+    timeout: Option<u64>,
+    future: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), future)
+            .await
+            .map_err(|_| {
+                warn!("Request timed out after {secs}s");
+                anyhow!("Request timed out after {secs}s")
+            })?,
+        None => future.await,
+    }
+}
+
+/// Resolves once the started stream sent nothing for `timeout` secs, with the secs
+async fn watch_idle(last_event: Arc<Mutex<Option<Instant>>>, timeout: Option<u64>) -> u64 {
+    let secs = match timeout {
+        Some(v) => v,
+        None => return std::future::pending().await,
+    };
+    let limit = Duration::from_secs(secs);
+    loop {
+        let last_event = *last_event.lock();
+        match last_event.map(|v| v.elapsed()) {
+            Some(idle) if idle >= limit => return secs,
+            Some(idle) => sleep(limit - idle).await,
+            None => sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+pub fn init_client(config: SharedConfig) -> Result<Box<dyn Client>> {
+    let client_type = config.lock().client;
+    let client: Box<dyn Client> = match client_type {
+        ClientType::OpenAI => Box::new(OpenAIClient::init(config)?),
+        ClientType::AzureOpenAI => Box::new(AzureOpenAIClient::init(config)?),
+        ClientType::Claude => Box::new(ClaudeClient::init(config)?),
+        ClientType::Ollama => Box::new(OllamaClient::init(config)?),
+        ClientType::Gemini => Box::new(GeminiClient::init(config)?),
+    };
+    Ok(client)
+}
+
+pub fn build_http_client(config: &SharedConfig) -> Result<ReqwestClient> {
+    let headers = build_extra_headers(&config.lock().extra_headers)?;
+    let client = http_client_builder(config)?
+        .default_headers(headers)
+        .build()
+        .with_context(|| "Failed to build http client")?;
+    Ok(client)
+}
+
+/// Without `extra_headers`, for requests to hosts other than the LLM service
+pub fn build_plain_http_client(config: &SharedConfig) -> Result<ReqwestClient> {
+    let client = http_client_builder(config)?
+        .build()
+        .with_context(|| "Failed to build http client")?;
+    Ok(client)
+}
+
+fn http_client_builder(config: &SharedConfig) -> Result<ClientBuilder> {
+    let config = config.lock();
+    let mut builder = ClientBuilder::new();
+    if let Some(proxy) = config.proxy.as_ref() {
+        builder = builder.proxy(Proxy::all(proxy).with_context(|| "Invalid config.proxy")?);
+    }
+    if let Some(path) = config.ca_cert.as_ref() {
+        let pem = read_pem(path, "ca_cert")?;
+        let cert = Certificate::from_pem(&pem).with_context(|| "Invalid config.ca_cert")?;
+        builder = builder.add_root_certificate(cert);
+    }
+    match (config.client_cert.as_ref(), config.client_key.as_ref()) {
+        (Some(cert_path), Some(key_path)) => {
+            let mut pem = read_pem(cert_path, "client_cert")?;
+            pem.push(b'\n');
+            pem.extend(read_pem(key_path, "client_key")?);
+            let identity = Identity::from_pem(&pem)
+                .with_context(|| "Invalid config.client_cert or config.client_key")?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => bail!("config.client_cert and config.client_key must be set together"),
+    }
+    if config.insecure_skip_verify {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder.connect_timeout(CONNECT_TIMEOUT))
+}
+
+fn read_pem(path: &Path, name: &str) -> Result<Vec<u8>> {
+    std::fs::read(path)
+        .with_context(|| format!("Failed to read config.{name} at {}", path.display()))
+}
+
+fn build_extra_headers(extra_headers: &BTreeMap<String, String>) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in extra_headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid config.extra_headers, bad name `{name}`"))?;
+        let value = HeaderValue::from_str(value)
+            .with_context(|| format!("Invalid config.extra_headers, bad value of `{name}`"))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// Send a request to the LLM service. It is logged without headers, which carry api keys.
+pub async fn send_request(builder: RequestBuilder) -> Result<Response> {
+    if log_enabled!(Level::Debug) {
+        if let Some(request) = builder.try_clone().and_then(|v| v.build().ok()) {
+            let body = request
+                .body()
+                .and_then(|v| v.as_bytes())
+                .map(String::from_utf8_lossy)
+                .unwrap_or_default();
+            debug!("{} {} {body}", request.method(), redact_url(request.url()));
+        }
+    }
+    let start = Instant::now();
+    let res = builder.send().await;
+    let elapsed = start.elapsed().as_millis();
+    match &res {
+        Ok(res) => debug!(
+            "{} from {} in {elapsed}ms",
+            res.status(),
+            redact_url(res.url())
+        ),
+        Err(err) => debug!("Request failed after {elapsed}ms, {err}"),
+    }
+    Ok(res?)
+}
+
+/// Method, url, headers and pretty-printed body, api keys in the url or headers are masked.
+/// `extra_headers` are the default headers of the http client, which are not part of the request.
+fn format_request(request: &Request, extra_headers: &HeaderMap) -> String {
+    let mut url = request.url().clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| match k.as_ref() {
+            "key" | "api_key" => (k.to_string(), mask_api_key(&v)),
+            _ => (k.to_string(), v.to_string()),
+        })
+        .collect();
+    if !pairs.is_empty() {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    let mut output = format!("{} {url}\n", request.method());
+    for (name, value) in extra_headers.iter().chain(request.headers()) {
+        let value = value.to_str().unwrap_or_default();
+        let value = match name.as_str() {
+            "authorization" => match value.split_once(' ') {
+                Some((scheme, token)) => format!("{scheme} {}", mask_api_key(token)),
+                None => mask_api_key(value),
+            },
+            "x-api-key" | "api-key" | "x-goog-api-key" => mask_api_key(value),
+            _ => value.to_string(),
+        };
+        output.push_str(&format!("{name}: {value}\n"));
+    }
+    let body = request
+        .body()
+        .and_then(|v| v.as_bytes())
+        .unwrap_or_default();
+    let body = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(value) => serde_json::to_string_pretty(&value).unwrap_or_default(),
+        Err(_) => String::from_utf8_lossy(body).to_string(),
+    };
+    output.push_str(&format!("\n{body}\n"));
+    output
+}
+
+/// Drop the query, e.g. gemini takes the api key as `?key=`
+fn redact_url(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_query(None);
+    url.to_string()
+}
+
+/// Turn 401/429 responses into [`ApiKeyError`] so the next api key can be tried
+pub async fn check_api_key(res: Response) -> Result<Response> {
+    let status = res.status();
+    if matches!(
+        status,
+        StatusCode::UNAUTHORIZED | StatusCode::TOO_MANY_REQUESTS
+    ) {
+        let body = res.text().await.unwrap_or_default();
+        return Err(ApiKeyError { status, body }.into());
+    }
+    Ok(res)
+}
+
+/// Runtime of the blocking methods, built on first use so that a client only used through the
+/// async methods never owns one, which would panic when dropped inside another runtime
+#[derive(Debug, Default)]
+pub struct LazyRuntime(OnceLock<Runtime>);
+
+impl LazyRuntime {
+    pub fn get(&self) -> &Runtime {
+        self.0
+            .get_or_init(|| init_runtime().expect("Failed to init tokio"))
+    }
+}
+
+/// Chunks sent to `rx` while `producer` runs, the producer is polled only until one arrives
+fn poll_stream(
+    producer: BoxFuture<'_, Result<()>>,
+    rx: Receiver<ReplyStreamEvent>,
+) -> impl Stream<Item = Result<String>> + '_ {
+    stream::unfold((Some(producer), rx), |(mut producer, rx)| async move {
+        loop {
+            if let Ok(event) = rx.try_recv() {
+                match event {
+                    ReplyStreamEvent::Text(text) => return Some((Ok(text), (producer, rx))),
+                    ReplyStreamEvent::Done => continue,
+                }
+            }
+            let future = producer.as_mut()?;
+            let ret = poll_fn(|cx| match future.as_mut().poll(cx) {
+                Poll::Ready(ret) => Poll::Ready(Some(ret)),
+                Poll::Pending if !rx.is_empty() => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            })
+            .await;
+            if let Some(ret) = ret {
+                producer = None;
+                if let Err(err) = ret {
+                    return Some((Err(err), (producer, rx)));
+                }
+            }
+        }
+    })
+}
+
+pub fn init_runtime() -> Result<Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to init tokio")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_stream() {
+        let runtime = init_runtime().unwrap();
+        let (tx, rx) = unbounded();
+        let producer = async move {
+            for text in ["Hello", ", ", "world"] {
+                tx.send(ReplyStreamEvent::Text(text.into()))?;
+                tokio::task::yield_now().await;
+            }
+            tx.send(ReplyStreamEvent::Done)?;
+            Ok(())
+        };
+        let chunks: Vec<String> = runtime.block_on(
+            poll_stream(producer.boxed(), rx)
+                .map(|v| v.unwrap())
+                .collect(),
+        );
+        assert_eq!(chunks, ["Hello", ", ", "world"]);
+
+        let (tx, rx) = unbounded();
+        let producer = async move {
+            tx.send(ReplyStreamEvent::Text("partial".into()))?;
+            sleep(Duration::from_millis(10)).await;
+            bail!("broken")
+        };
+        let items: Vec<Result<String>> =
+            runtime.block_on(poll_stream(producer.boxed(), rx).collect());
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap(), "partial");
+        assert_eq!(items[1].as_ref().unwrap_err().to_string(), "broken");
+    }
+
+    #[test]
+    fn test_with_timeout() {
+        let runtime = init_runtime().unwrap();
+        let ret = runtime.block_on(with_timeout(Some(1), async {
+            sleep(Duration::from_secs(5)).await;
+            Ok(())
+        }));
+        assert_eq!(ret.unwrap_err().to_string(), "Request timed out after 1s");
+        let ret = runtime.block_on(with_timeout(None, async { Ok(1) }));
+        assert_eq!(ret.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_format_request() {
+        let request = ReqwestClient::new()
+            .post("https://example.com/v1/models?key=secret0123456789")
+            .bearer_auth("sk-0123456789abcdef")
+            .json(&serde_json::json!({"model": "gpt"}))
+            .build()
+            .unwrap();
+        let extra_headers =
+            build_extra_headers(&BTreeMap::from([("x-trace".to_string(), "on".to_string())]))
+                .unwrap();
+        assert_eq!(
+            format_request(&request, &extra_headers),
+            r#"POST https://example.com/v1/models?key=...6789
+x-trace: on
+authorization: Bearer sk-...cdef
+content-type: application/json
+
+{
+  "model": "gpt"
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_redact_url() {
+        let url = Url::parse("https://example.com/v1beta/models?key=secret").unwrap();
+        assert_eq!(redact_url(&url), "https://example.com/v1beta/models");
+    }
+
+    #[test]
+    fn test_build_extra_headers() {
+        let mut extra_headers = BTreeMap::new();
+        extra_headers.insert("X-Tenant".to_string(), "acme".to_string());
+        let headers = build_extra_headers(&extra_headers).unwrap();
+        assert_eq!(headers["x-tenant"], "acme");
+        extra_headers.insert("Bad Name".to_string(), "x".to_string());
+        assert!(build_extra_headers(&extra_headers).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+use super::{build_http_client, record_finish_reason, send_request, Client, LazyRuntime};
+
+use crate::config::{split_data_url, Message, SharedConfig, Usage};
+use crate::repl::ReplyStreamHandler;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use reqwest::RequestBuilder;
+use serde_json::{json, Map, Value};
+use tokio::runtime::Runtime;
+
+const API_BASE: &str = "http://localhost:11434";
+pub const MODEL: &str = "llama3";
+const EMBEDDING_MODEL: &str = "nomic-embed-text";
+
+#[derive(Debug)]
+pub struct OllamaClient {
+    config: SharedConfig,
+    runtime: LazyRuntime,
+}
+
+#[async_trait]
+impl Client for OllamaClient {
+    fn get_config(&self) -> &SharedConfig {
+        &self.config
+    }
+
+    fn get_runtime(&self) -> &Runtime {
+        self.runtime.get()
+    }
+
+    fn get_model(&self) -> String {
+        self.config
+            .lock()
+            .get_model()
+            .unwrap_or_else(|| MODEL.into())
+    }
+
+    fn models(&self) -> Vec<String> {
+        vec![MODEL.into()]
+    }
+
+    async fn list_models_inner(&self) -> Result<Vec<String>> {
+        let url = format!("{}/api/tags", self.api_base());
+        let builder = build_http_client(&self.config)?.get(url);
+        let data: Value = send_request(builder).await?.json().await?;
+        if let Some(err_msg) = data["error"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+        let models = data["models"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?
+            .iter()
+            .filter_map(|v| v["name"].as_str().map(|v| v.to_string()))
+            .collect();
+        Ok(models)
+    }
+
+    fn get_embedding_model(&self) -> String {
+        self.config
+            .lock()
+            .embedding_model
+            .clone()
+            .unwrap_or_else(|| EMBEDDING_MODEL.into())
+    }
+
+    async fn embeddings_inner(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let url = format!("{}/api/embed", self.api_base());
+        let body = json!({
+            "model": self.get_embedding_model(),
+            "input": texts,
+        });
+        let builder = build_http_client(&self.config)?.post(url).json(&body);
+        let data: Value = send_request(builder).await?.json().await?;
+        if let Some(err_msg) = data["error"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+        serde_json::from_value(data["embeddings"].clone())
+            .map_err(|_| anyhow!("Unexpected response {data}"))
+    }
+
+    fn chat_request(&self, content: &str, stream: bool) -> Result<RequestBuilder> {
+        self.request_builder(content, stream)
+    }
+
+    async fn send_message_inner(&self, content: &str) -> Result<(String, Option<Usage>)> {
+        let builder = self.request_builder(content, false)?;
+        let data: Value = send_request(builder).await?.json().await?;
+        if let Some(err_msg) = data["error"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+
+        record_finish_reason(&self.config, &data["done_reason"]);
+        let output = data["message"]["content"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?;
+        let usage = match (
+            data["prompt_eval_count"].as_u64(),
+            data["eval_count"].as_u64(),
+        ) {
+            (Some(prompt_tokens), Some(completion_tokens)) => Some(Usage::new(
+                prompt_tokens as usize,
+                completion_tokens as usize,
+            )),
+            _ => None,
+        };
+
+        Ok((output.to_string(), usage))
+    }
+
+    async fn send_message_streaming_inner(
+        &self,
+        content: &str,
+        handler: &mut ReplyStreamHandler,
+    ) -> Result<()> {
+        let builder = self.request_builder(content, true)?;
+        let res = send_request(builder).await?;
+        if !res.status().is_success() {
+            let data: Value = res.json().await?;
+            if let Some(err_msg) = data["error"].as_str() {
+                bail!("Request failed, {err_msg}");
+            }
+            bail!("Request failed");
+        }
+        // Ollama streams newline-delimited json objects rather than SSE
+        let mut stream = res.bytes_stream();
+        let mut buffer = vec![];
+        while let Some(chunk) = stream.next().await {
+            buffer.extend_from_slice(&chunk?);
+            handler.touch();
+            while let Some(index) = buffer.iter().position(|v| *v == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=index).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let data: Value = serde_json::from_str(line)?;
+                if let Some(err_msg) = data["error"].as_str() {
+                    bail!("Request failed, {err_msg}");
+                }
+                let text = data["message"]["content"].as_str().unwrap_or_default();
+                if !text.is_empty() {
+                    handler.text(text)?;
+                }
+                if data["done"].as_bool().unwrap_or_default() {
+                    record_finish_reason(&self.config, &data["done_reason"]);
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl OllamaClient {
+    pub fn init(config: SharedConfig) -> Result<Self> {
+        let s = Self {
+            config,
+            runtime: LazyRuntime::default(),
+        };
+        let _ = build_http_client(&s.config)?; // check error
+        Ok(s)
+    }
+
+    fn api_base(&self) -> String {
+        let api_base = self
+            .config
+            .lock()
+            .api_base
+            .clone()
+            .unwrap_or_else(|| API_BASE.into());
+        api_base.trim_end_matches('/').to_string()
+    }
+
+    fn request_builder(&self, content: &str, stream: bool) -> Result<RequestBuilder> {
+        let messages = self.config.lock().build_messages(content)?;
+        let messages: Vec<Value> = messages.iter().map(build_message).collect();
+        let mut body = json!({
+            "model": self.get_model(),
+            "messages": messages,
+            "stream": stream,
+        });
+
+        let mut options = Map::new();
+        if let Some(v) = self.config.lock().get_temperature() {
+            options.insert("temperature".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().get_max_tokens() {
+            options.insert("num_predict".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().get_top_p() {
+            options.insert("top_p".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().get_presence_penalty() {
+            options.insert("presence_penalty".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().get_frequency_penalty() {
+            options.insert("frequency_penalty".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().seed {
+            options.insert("seed".into(), json!(v));
+        }
+        if let Some(v) = self.config.lock().get_stop() {
+            options.insert("stop".into(), json!(v));
+        }
+        if !options.is_empty() {
+            body.as_object_mut()
+                .and_then(|m| m.insert("options".into(), options.into()));
+        }
+
+        let url = format!("{}/api/chat", self.api_base());
+        let builder = build_http_client(&self.config)?.post(url).json(&body);
+
+        Ok(builder)
+    }
+}
+
+/// Ollama takes images as plain base64 next to the content
+fn build_message(message: &Message) -> Value {
+    let mut value = json!({ "role": message.role.as_str(), "content": message.content });
+    let images: Vec<&str> = message
+        .images
+        .iter()
+        .filter_map(|v| split_data_url(v))
+        .map(|(_, data)| data)
+        .collect();
+    if !images.is_empty() {
+        value["images"] = json!(images);
+    }
+    value
+}
@@ -0,0 +1,512 @@
+use super::{
+    build_http_client, check_api_key, record_finish_reason, send_request, Client, LazyRuntime,
+};
+
+use crate::config::{Message, SharedConfig, ToolCall, Usage};
+use crate::repl::ReplyStreamHandler;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use eventsource_stream::Eventsource;
+use futures_util::StreamExt;
+use log::debug;
+use reqwest::multipart::{Form, Part};
+use reqwest::RequestBuilder;
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+
+const API_BASE: &str = "https://api.openai.com/v1";
+const EMBEDDING_MODEL: &str = "text-embedding-3-small";
+const TRANSCRIBE_MODEL: &str = "whisper-1";
+const IMAGE_MODEL: &str = "dall-e-3";
+const SPEECH_MODEL: &str = "tts-1";
+const VOICE: &str = "alloy";
+pub const MODEL: &str = "gpt-3.5-turbo";
+const MODELS: [&str; 3] = ["gpt-3.5-turbo", "gpt-4", "gpt-4-32k"];
+/// Give up when the model keeps calling tools
+const MAX_TOOL_ROUNDS: usize = 8;
+
+#[derive(Debug)]
+pub struct OpenAIClient {
+    config: SharedConfig,
+    runtime: LazyRuntime,
+}
+
+#[async_trait]
+impl Client for OpenAIClient {
+    fn get_config(&self) -> &SharedConfig {
+        &self.config
+    }
+
+    fn get_runtime(&self) -> &Runtime {
+        self.runtime.get()
+    }
+
+    fn get_model(&self) -> String {
+        self.config
+            .lock()
+            .get_model()
+            .unwrap_or_else(|| MODEL.into())
+    }
+
+    fn models(&self) -> Vec<String> {
+        MODELS.into_iter().map(|v| v.to_string()).collect()
+    }
+
+    async fn list_models_inner(&self) -> Result<Vec<String>> {
+        let builder = self.add_auth(build_http_client(&self.config)?.get(self.url("models")))?;
+        let data: Value = send_request(builder).await?.json().await?;
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+        let mut models: Vec<String> = data["data"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?
+            .iter()
+            .filter_map(|v| v["id"].as_str().map(|v| v.to_string()))
+            .collect();
+        models.sort();
+        Ok(models)
+    }
+
+    fn get_embedding_model(&self) -> String {
+        self.config
+            .lock()
+            .embedding_model
+            .clone()
+            .unwrap_or_else(|| EMBEDDING_MODEL.into())
+    }
+
+    async fn embeddings_inner(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let body = json!({
+            "model": self.get_embedding_model(),
+            "input": texts,
+        });
+        let builder =
+            self.add_auth(build_http_client(&self.config)?.post(self.url("embeddings")))?;
+        openai_embeddings(builder.json(&body)).await
+    }
+
+    async fn transcribe_inner(&self, file_name: &str, data: Vec<u8>) -> Result<String> {
+        let model = self
+            .config
+            .lock()
+            .transcribe_model
+            .clone()
+            .unwrap_or_else(|| TRANSCRIBE_MODEL.into());
+        let form = Form::new()
+            .text("model", model)
+            .part("file", Part::bytes(data).file_name(file_name.to_string()));
+        let builder =
+            self.add_auth(build_http_client(&self.config)?.post(self.url("audio/transcriptions")))?;
+        let data: Value = check_api_key(send_request(builder.multipart(form)).await?)
+            .await?
+            .json()
+            .await?;
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+        let text = data["text"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?;
+        Ok(text.trim().to_string())
+    }
+
+    async fn generate_image_inner(&self, prompt: &str) -> Result<Vec<u8>> {
+        let model = self
+            .config
+            .lock()
+            .image_model
+            .clone()
+            .unwrap_or_else(|| IMAGE_MODEL.into());
+        let body = json!({
+            "model": model,
+            "prompt": prompt,
+            "n": 1,
+            "response_format": "b64_json",
+        });
+        let builder =
+            self.add_auth(build_http_client(&self.config)?.post(self.url("images/generations")))?;
+        let data: Value = check_api_key(send_request(builder.json(&body)).await?)
+            .await?
+            .json()
+            .await?;
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+        let image = data["data"][0]["b64_json"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Unexpected response {data}"))?;
+        general_purpose::STANDARD
+            .decode(image)
+            .with_context(|| "Invalid image data")
+    }
+
+    async fn speech_inner(&self, text: &str) -> Result<Option<Vec<u8>>> {
+        let voice = self.config.lock().voice.clone();
+        let body = json!({
+            "model": SPEECH_MODEL,
+            "input": text,
+            "voice": voice.as_deref().unwrap_or(VOICE),
+            "response_format": "wav",
+        });
+        let builder =
+            self.add_auth(build_http_client(&self.config)?.post(self.url("audio/speech")))?;
+        let res = check_api_key(send_request(builder.json(&body)).await?).await?;
+        if !res.status().is_success() {
+            let data: Value = res.json().await?;
+            if let Some(err_msg) = data["error"]["message"].as_str() {
+                bail!("Request failed, {err_msg}");
+            }
+            bail!("Request failed");
+        }
+        Ok(Some(res.bytes().await?.to_vec()))
+    }
+
+    fn chat_request(&self, content: &str, stream: bool) -> Result<RequestBuilder> {
+        let body = openai_build_body(&self.config, content, &self.get_model(), stream)?;
+        self.request_builder(&body)
+    }
+
+    async fn send_message_inner(&self, content: &str) -> Result<(String, Option<Usage>)> {
+        let body = openai_build_body(&self.config, content, &self.get_model(), false)?;
+        openai_send_message(&self.config, body, |v| self.request_builder(v)).await
+    }
+
+    async fn send_message_streaming_inner(
+        &self,
+        content: &str,
+        handler: &mut ReplyStreamHandler,
+    ) -> Result<()> {
+        let body = openai_build_body(&self.config, content, &self.get_model(), true)?;
+        openai_send_message_streaming(&self.config, body, |v| self.request_builder(v), handler)
+            .await
+    }
+}
+
+impl OpenAIClient {
+    pub fn init(config: SharedConfig) -> Result<Self> {
+        let s = Self {
+            config,
+            runtime: LazyRuntime::default(),
+        };
+        let _ = build_http_client(&s.config)?; // check error
+        Ok(s)
+    }
+
+    fn request_builder(&self, body: &Value) -> Result<RequestBuilder> {
+        let builder =
+            self.add_auth(build_http_client(&self.config)?.post(self.url("chat/completions")))?;
+
+        Ok(builder.json(body))
+    }
+
+    /// Any OpenAI-compatible server can be used through `api_base`
+    fn url(&self, path: &str) -> String {
+        let api_base = self
+            .config
+            .lock()
+            .api_base
+            .clone()
+            .unwrap_or_else(|| API_BASE.into());
+        format!("{}/{path}", api_base.trim_end_matches('/'))
+    }
+
+    fn add_auth(&self, builder: RequestBuilder) -> Result<RequestBuilder> {
+        let config = self.config.lock();
+        // local servers usually take no api key
+        let mut builder = match config.get_api_key() {
+            Ok(api_key) => builder.bearer_auth(api_key),
+            Err(_) if config.api_base.is_some() => builder,
+            Err(err) => return Err(err),
+        };
+        if let Some(organization_id) = config.organization_id.as_ref() {
+            builder = builder.header("OpenAI-Organization", organization_id);
+        }
+        if let Some(project_id) = config.project_id.as_ref() {
+            builder = builder.header("OpenAI-Project", project_id);
+        }
+        Ok(builder)
+    }
+}
+
+pub async fn openai_send_message(
+    config: &SharedConfig,
+    mut body: Value,
+    build: impl Fn(&Value) -> Result<RequestBuilder> + Send + Sync,
+) -> Result<(String, Option<Usage>)> {
+    let mut total_usage: Option<Usage> = None;
+    for _ in 0..MAX_TOOL_ROUNDS {
+        let data: Value = check_api_key(send_request(build(&body)?).await?)
+            .await?
+            .json()
+            .await?;
+        if let Some(err_msg) = data["error"]["message"].as_str() {
+            bail!("Request failed, {err_msg}");
+        }
+
+        if let (Some(prompt_tokens), Some(completion_tokens)) = (
+            data["usage"]["prompt_tokens"].as_u64(),
+            data["usage"]["completion_tokens"].as_u64(),
+        ) {
+            let usage = Usage::new(prompt_tokens as usize, completion_tokens as usize);
+            total_usage.get_or_insert_with(Usage::default).add(&usage);
+        }
+
+        record_finish_reason(config, &data["choices"][0]["finish_reason"]);
+        let message = &data["choices"][0]["message"];
+        let mut tool_calls = vec![];
+        merge_tool_calls(&mut tool_calls, &message["tool_calls"]);
+        if tool_calls.is_empty() {
+            let output = message["content"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Unexpected response {data}"))?;
+            return Ok((output.to_string(), total_usage));
+        }
+        add_tool_results(config, &mut body, &tool_calls);
+    }
+    bail!("Error: Still calling tools after {MAX_TOOL_ROUNDS} rounds")
+}
+
+pub async fn openai_send_message_streaming(
+    config: &SharedConfig,
+    mut body: Value,
+    build: impl Fn(&Value) -> Result<RequestBuilder> + Send + Sync,
+    handler: &mut ReplyStreamHandler,
+) -> Result<()> {
+    for _ in 0..MAX_TOOL_ROUNDS {
+        let res = check_api_key(send_request(build(&body)?).await?).await?;
+        if !res.status().is_success() {
+            let data: Value = res.json().await?;
+            if let Some(err_msg) = data["error"]["message"].as_str() {
+                bail!("Request failed, {err_msg}");
+            }
+            bail!("Request failed");
+        }
+        let mut tool_calls = vec![];
+        let mut stream = res.bytes_stream().eventsource();
+        while let Some(part) = stream.next().await {
+            let chunk = part?.data;
+            handler.touch();
+            if chunk == "[DONE]" {
+                break;
+            } else {
+                let data: Value = serde_json::from_str(&chunk)?;
+                record_finish_reason(config, &data["choices"][0]["finish_reason"]);
+                let delta = &data["choices"][0]["delta"];
+                merge_tool_calls(&mut tool_calls, &delta["tool_calls"]);
+                let text = delta["content"].as_str().unwrap_or_default();
+                if text.is_empty() {
+                    continue;
+                }
+                handler.text(text)?;
+            }
+        }
+        if tool_calls.is_empty() {
+            return Ok(());
+        }
+        add_tool_results(config, &mut body, &tool_calls);
+        handler.touch();
+    }
+    bail!("Error: Still calling tools after {MAX_TOOL_ROUNDS} rounds")
+}
+
+pub async fn openai_embeddings(builder: RequestBuilder) -> Result<Vec<Vec<f32>>> {
+    let data: Value = check_api_key(send_request(builder).await?)
+        .await?
+        .json()
+        .await?;
+    if let Some(err_msg) = data["error"]["message"].as_str() {
+        bail!("Request failed, {err_msg}");
+    }
+    let mut items = data["data"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Unexpected response {data}"))?
+        .clone();
+    items.sort_by_key(|v| v["index"].as_u64());
+    items
+        .iter()
+        .map(|v| {
+            serde_json::from_value(v["embedding"].clone())
+                .map_err(|_| anyhow!("Unexpected response {data}"))
+        })
+        .collect()
+}
+
+/// Tool calls come whole in a reply but in pieces keyed by `index` when streaming
+fn merge_tool_calls(tool_calls: &mut Vec<ToolCall>, value: &Value) {
+    let items = match value.as_array() {
+        Some(v) => v,
+        None => return,
+    };
+    for (i, item) in items.iter().enumerate() {
+        let index = item["index"].as_u64().map(|v| v as usize).unwrap_or(i);
+        if tool_calls.len() <= index {
+            tool_calls.resize(index + 1, ToolCall::default());
+        }
+        let call = &mut tool_calls[index];
+        if let Some(id) = item["id"].as_str() {
+            call.id.push_str(id);
+        }
+        if let Some(name) = item["function"]["name"].as_str() {
+            call.name.push_str(name);
+        }
+        if let Some(arguments) = item["function"]["arguments"].as_str() {
+            call.arguments.push_str(arguments);
+        }
+    }
+}
+
+/// Run the tools and append the calls and their results to the messages of the next request
+fn add_tool_results(config: &SharedConfig, body: &mut Value, tool_calls: &[ToolCall]) {
+    let mut messages = vec![json!({
+        "role": "assistant",
+        "content": null,
+        "tool_calls": tool_calls.iter().map(|v| v.to_json()).collect::<Vec<_>>(),
+    })];
+    for call in tool_calls {
+        debug!("Calling tool {} with {}", call.name, call.arguments);
+        let tool = config.lock().find_tool(&call.name);
+        let content = match tool {
+            Some(tool) => tool.run(&call.arguments),
+            None => format!("Error: Unknown tool `{}`", call.name),
+        };
+        messages.push(json!({
+            "role": "tool",
+            "tool_call_id": call.id,
+            "content": content,
+        }));
+    }
+    if let Some(v) = body["messages"].as_array_mut() {
+        v.extend(messages);
+    }
+}
+
+/// Images go in content parts after the text
+fn build_message(message: &Message) -> Value {
+    if message.images.is_empty() {
+        return json!({ "role": message.role.as_str(), "content": message.content });
+    }
+    let mut content = vec![json!({ "type": "text", "text": message.content })];
+    for url in &message.images {
+        content.push(json!({ "type": "image_url", "image_url": { "url": url } }));
+    }
+    json!({ "role": message.role.as_str(), "content": content })
+}
+
+pub fn openai_build_body(
+    config: &SharedConfig,
+    content: &str,
+    model: &str,
+    stream: bool,
+) -> Result<Value> {
+    let messages = config.lock().build_messages(content)?;
+    let messages: Vec<Value> = messages.iter().map(build_message).collect();
+    let mut body = json!({
+        "model": model,
+        "messages": messages,
+    });
+
+    if let Some(v) = config.lock().get_temperature() {
+        body.as_object_mut()
+            .and_then(|m| m.insert("temperature".into(), json!(v)));
+    }
+
+    if let Some(v) = config.lock().get_max_tokens() {
+        body.as_object_mut()
+            .and_then(|m| m.insert("max_tokens".into(), json!(v)));
+    }
+
+    if let Some(v) = config.lock().get_top_p() {
+        body.as_object_mut()
+            .and_then(|m| m.insert("top_p".into(), json!(v)));
+    }
+
+    if let Some(v) = config.lock().get_presence_penalty() {
+        body.as_object_mut()
+            .and_then(|m| m.insert("presence_penalty".into(), json!(v)));
+    }
+
+    if let Some(v) = config.lock().get_frequency_penalty() {
+        body.as_object_mut()
+            .and_then(|m| m.insert("frequency_penalty".into(), json!(v)));
+    }
+
+    if let Some(v) = config.lock().seed {
+        body.as_object_mut()
+            .and_then(|m| m.insert("seed".into(), json!(v)));
+    }
+
+    if let Some(v) = config.lock().get_stop() {
+        body.as_object_mut()
+            .and_then(|m| m.insert("stop".into(), json!(v)));
+    }
+
+    if let Some(v) = config.lock().get_tools()? {
+        body.as_object_mut()
+            .and_then(|m| m.insert("tools".into(), v));
+    }
+
+    if stream {
+        body.as_object_mut()
+            .and_then(|m| m.insert("stream".into(), json!(true)));
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_message() {
+        let mut message = Message::new("What is this?");
+        assert_eq!(
+            build_message(&message),
+            json!({"role": "user", "content": "What is this?"})
+        );
+        message.images = vec!["data:image/png;base64,iVBORw0KGgo=".into()];
+        assert_eq!(
+            build_message(&message),
+            json!({
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "What is this?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_tool_calls() {
+        let mut tool_calls = vec![];
+        let chunks = [
+            json!([{"index": 0, "id": "call_1", "function": {"name": "get_weather", "arguments": ""}}]),
+            json!([{"index": 0, "function": {"arguments": "{\"city\":"}}]),
+            json!([{"index": 0, "function": {"arguments": "\"Paris\"}"}}]),
+            json!([{"index": 1, "id": "call_2", "function": {"name": "now", "arguments": "{}"}}]),
+        ];
+        for chunk in chunks.iter() {
+            merge_tool_calls(&mut tool_calls, chunk);
+        }
+        merge_tool_calls(&mut tool_calls, &Value::Null);
+        assert_eq!(
+            tool_calls,
+            vec![
+                ToolCall {
+                    id: "call_1".into(),
+                    name: "get_weather".into(),
+                    arguments: "{\"city\":\"Paris\"}".into(),
+                },
+                ToolCall {
+                    id: "call_2".into(),
+                    name: "now".into(),
+                    arguments: "{}".into(),
+                },
+            ]
+        );
+    }
+}
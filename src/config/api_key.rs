@@ -0,0 +1,60 @@
+use super::Config;
+
+use crate::utils::detect_shell;
+
+use anyhow::{Context, Result};
+use keyring::Entry;
+
+/// Service of the keychain entries, one entry per client, e.g. `aichat/openai`
+const KEYRING_SERVICE: &str = "aichat";
+
+impl Config {
+    /// Read the api key from `api_key_cmd` or the keychain when the config has none
+    pub fn load_api_key_from_secrets(&mut self) -> Result<()> {
+        if !self.api_key.is_empty() {
+            return Ok(());
+        }
+        if let Some(command) = &self.api_key_cmd {
+            let output = detect_shell()
+                .output(command)
+                .with_context(|| "Failed to get the api key from api_key_cmd")?;
+            // like `pass show`, the key is the first line
+            let api_key = output.lines().next().unwrap_or_default().trim();
+            if !api_key.is_empty() {
+                self.api_key = vec![api_key.to_string()];
+            }
+            return Ok(());
+        }
+        if self.keyring {
+            match self.keyring_entry()?.get_password() {
+                Ok(api_key) => self.api_key = vec![api_key],
+                Err(keyring::Error::NoEntry) => {}
+                Err(err) => {
+                    return Err(err).with_context(|| "Failed to get the api key from the keychain")
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the api key of the client in the keychain
+    pub fn set_keyring_api_key(&self, api_key: &str) -> Result<()> {
+        self.keyring_entry()?
+            .set_password(api_key)
+            .with_context(|| "Failed to store the api key in the keychain")
+    }
+
+    /// Remove the api key of the client from the keychain, returns false if there was none
+    pub fn delete_keyring_api_key(&self) -> Result<bool> {
+        match self.keyring_entry()?.delete_password() {
+            Ok(()) => Ok(true),
+            Err(keyring::Error::NoEntry) => Ok(false),
+            Err(err) => Err(err).with_context(|| "Failed to remove the api key from the keychain"),
+        }
+    }
+
+    fn keyring_entry(&self) -> Result<Entry> {
+        Entry::new(KEYRING_SERVICE, &self.client.to_string())
+            .with_context(|| "Failed to open the keychain")
+    }
+}
@@ -0,0 +1,101 @@
+use super::Config;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{create_dir_all, read_to_string};
+use std::path::PathBuf;
+
+const CACHE_DIR_NAME: &str = "cache";
+
+/// A reply stored at `<data_dir>/cache/<sha256 of key>.json`
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Kept to rule out hash collisions
+    key: Value,
+    output: String,
+}
+
+impl Config {
+    pub fn get_cached_reply(&self, model: &str, content: &str) -> Result<Option<String>> {
+        if !self.cache {
+            return Ok(None);
+        }
+        let key = self.cache_key(model, content)?;
+        let path = cache_file(&key)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load cache at {}", path.display()))?;
+        let entry: CacheEntry = match serde_json::from_str(&content) {
+            Ok(v) => v,
+            Err(_) => return Ok(None),
+        };
+        Ok(if entry.key == key {
+            Some(entry.output)
+        } else {
+            None
+        })
+    }
+
+    pub fn cache_reply(&self, model: &str, content: &str, output: &str) -> Result<()> {
+        if !self.cache || output.is_empty() {
+            return Ok(());
+        }
+        let key = self.cache_key(model, content)?;
+        let path = cache_file(&key)?;
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).with_context(|| "Failed to create cache dir")?;
+        }
+        let entry = CacheEntry {
+            key,
+            output: output.to_string(),
+        };
+        let content = serde_json::to_string(&entry).with_context(|| "Failed to serialize cache")?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write cache to {}", path.display()))
+    }
+
+    /// Everything that shapes the reply: model, messages and sampling parameters
+    fn cache_key(&self, model: &str, content: &str) -> Result<Value> {
+        Ok(json!({
+            "client": self.client.to_string(),
+            "api_base": self.api_base,
+            "model": model,
+            "messages": self.build_messages(content)?,
+            "temperature": self.get_temperature(),
+            "max_tokens": self.get_max_tokens(),
+            "top_p": self.get_top_p(),
+            "presence_penalty": self.get_presence_penalty(),
+            "frequency_penalty": self.get_frequency_penalty(),
+            "seed": self.seed,
+            "stop": self.get_stop(),
+        }))
+    }
+}
+
+fn cache_file(key: &Value) -> Result<PathBuf> {
+    let hash = Sha256::digest(key.to_string().as_bytes());
+    let name: String = hash.iter().map(|v| format!("{v:02x}")).collect();
+    let mut path = Config::data_file(CACHE_DIR_NAME)?;
+    path.push(format!("{name}.json"));
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        let mut config: Config = serde_yaml::from_str("{}").unwrap();
+        let key = config.cache_key("gpt-4", "hello").unwrap();
+        assert_eq!(key, config.cache_key("gpt-4", "hello").unwrap());
+        assert_ne!(key, config.cache_key("gpt-4", "hello!").unwrap());
+        assert_ne!(key, config.cache_key("gpt-3.5-turbo", "hello").unwrap());
+        config.temperature = Some(0.2);
+        assert_ne!(key, config.cache_key("gpt-4", "hello").unwrap());
+    }
+}
@@ -1,14 +1,66 @@
-use super::message::{num_tokens_from_messages, Message, MessageRole, MAX_TOKENS};
+use super::message::{num_tokens_from_messages, within_max_tokens_limit, Message, MessageRole};
 use super::role::Role;
+use super::usage::Usage;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Messages at the end left as they are by compaction, i.e. the last exchange
+const KEEP_MESSAGES: usize = 2;
+/// Chars of the first message shown as the title of a conversation
+const TITLE_WIDTH: usize = 50;
+/// Chars of each message of the first exchange sent to write a title
+const TITLE_EXCERPT: usize = 2000;
+
+/// What to do when a conversation no longer fits in the token limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextStrategy {
+    /// Refuse to send
+    Error,
+    /// Drop the oldest exchanges, the role messages are kept
+    Truncate,
+    /// Fold the earlier messages into a summary
+    #[default]
+    Summarize,
+}
+
+impl fmt::Display for ContextStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ContextStrategy::Error => "error",
+            ContextStrategy::Truncate => "truncate",
+            ContextStrategy::Summarize => "summarize",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for ContextStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(ContextStrategy::Error),
+            "truncate" => Ok(ContextStrategy::Truncate),
+            "summarize" => Ok(ContextStrategy::Summarize),
+            _ => bail!("Invalid value, expect error, truncate or summarize"),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Conversation {
     pub tokens: usize,
     pub role: Option<Role>,
     pub messages: Vec<Message>,
+    #[serde(default)]
+    pub usage: Usage,
+    /// Title written by the model after the first exchange
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
 }
 
 impl Conversation {
@@ -17,11 +69,48 @@ impl Conversation {
             tokens: 0,
             role,
             messages: vec![],
+            usage: Usage::default(),
+            title: None,
         };
         value.update_tokens();
         value
     }
 
+    /// A conversation carried over from elsewhere, e.g. an import
+    pub fn from_messages(messages: Vec<Message>, title: Option<String>) -> Self {
+        let mut value = Self::new(None);
+        value.tokens = num_tokens_from_messages(&messages);
+        value.messages = messages;
+        value.title = title;
+        value
+    }
+
+    /// The generated title, or else the first line of the first message from the user,
+    /// shortened, to tell conversations apart
+    pub fn title(&self) -> String {
+        if let Some(title) = self.title.as_ref() {
+            return title.clone();
+        }
+        let prefix = match self.role.as_ref() {
+            Some(role) if !role.embeded() => role.prefix_len(),
+            _ => 0,
+        };
+        let line = self
+            .messages
+            .iter()
+            .skip(prefix)
+            .find(|v| v.role == MessageRole::User)
+            .and_then(|v| v.content.lines().find(|v| !v.trim().is_empty()))
+            .unwrap_or_default()
+            .trim();
+        if line.chars().count() > TITLE_WIDTH {
+            let line: String = line.chars().take(TITLE_WIDTH - 1).collect();
+            format!("{line}…")
+        } else {
+            line.to_string()
+        }
+    }
+
     pub fn update_role(&mut self, role: &Role) -> Result<()> {
         if self.messages.is_empty() {
             self.role = Some(role.clone());
@@ -55,19 +144,158 @@ impl Conversation {
             self.messages.push(Message {
                 role: MessageRole::User,
                 content: input.to_string(),
+                images: vec![],
             });
         }
         self.messages.push(Message {
             role: MessageRole::Assistant,
             content: output.to_string(),
+            images: vec![],
         });
         self.tokens = num_tokens_from_messages(&self.messages);
         Ok(())
     }
 
-    pub fn echo_messages(&self, content: &str) -> String {
-        let messages = self.build_emssages(content);
-        serde_yaml::to_string(&messages).unwrap_or("Unable to echo message".into())
+    /// Append to the last reply, e.g. the rest of a reply cut off by the token limit
+    pub fn extend_last_reply(&mut self, text: &str) -> Result<()> {
+        match self.messages.last_mut() {
+            Some(message) if message.role == MessageRole::Assistant => {
+                message.content.push_str(text);
+            }
+            _ => bail!("Error: No reply to continue"),
+        }
+        self.tokens = num_tokens_from_messages(&self.messages);
+        Ok(())
+    }
+
+    /// Drop the last user message and the reply to it
+    pub fn pop_last_exchange(&mut self) -> Result<()> {
+        let prefix_len = self.role.as_ref().map(|v| v.prefix_len()).unwrap_or(0);
+        if self.messages.len() <= prefix_len {
+            bail!("Error: No message to remove")
+        }
+        while let Some(message) = self.messages.pop() {
+            if message.role == MessageRole::User {
+                break;
+            }
+        }
+        // only the role messages are left, they are rebuilt along with the next input
+        if self.messages.len() <= prefix_len {
+            self.messages.clear();
+            self.update_tokens();
+        } else {
+            self.tokens = num_tokens_from_messages(&self.messages);
+        }
+        Ok(())
+    }
+
+    /// Drop the oldest exchanges until the input fits, returns the number of dropped messages
+    pub fn truncate(&mut self, content: &str, max_input_tokens: usize) -> usize {
+        let prefix_len = self.role.as_ref().map(|v| v.prefix_len()).unwrap_or(0);
+        let mut dropped = 0;
+        while self.messages.len() > prefix_len
+            && within_max_tokens_limit(&self.build_emssages(content), max_input_tokens).is_err()
+        {
+            // drop up to the next user message, so that the rest starts with a question
+            loop {
+                self.messages.remove(prefix_len);
+                dropped += 1;
+                match self.messages.get(prefix_len) {
+                    Some(message) if message.role != MessageRole::User => {}
+                    _ => break,
+                }
+            }
+        }
+        if self.messages.len() <= prefix_len {
+            self.messages.clear();
+            self.update_tokens();
+        } else {
+            self.tokens = num_tokens_from_messages(&self.messages);
+        }
+        dropped
+    }
+
+    /// Ask for a title of the first exchange, None once titled or past the first exchange
+    pub fn title_prompt(&self) -> Option<String> {
+        let replies = self
+            .messages
+            .iter()
+            .filter(|v| v.role == MessageRole::Assistant)
+            .count();
+        if self.title.is_some() || replies != 1 {
+            return None;
+        }
+        let prefix = self.role.as_ref().map(|v| v.prefix_len()).unwrap_or(0);
+        let transcript = self
+            .messages
+            .iter()
+            .skip(prefix)
+            .map(|v| {
+                let content: String = v.content.trim().chars().take(TITLE_EXCERPT).collect();
+                format!("{}: {content}", v.role.as_str())
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        Some(format!(
+            "Write a title of at most 5 words for the conversation below. \
+Reply with the title only, without quotes.\n\n<conversation>\n{transcript}\n</conversation>"
+        ))
+    }
+
+    /// Keep the first line of the reply to `title_prompt`, without quotes and markup
+    pub fn set_title(&mut self, reply: &str) {
+        let line = reply
+            .lines()
+            .map(|v| v.trim_matches(|c: char| c.is_whitespace() || "\"'`*#.".contains(c)))
+            .find(|v| !v.is_empty())
+            .unwrap_or_default();
+        let title = line.trim_start_matches("Title:").trim();
+        if !title.is_empty() {
+            self.title = Some(title.chars().take(TITLE_WIDTH).collect());
+        }
+    }
+
+    /// Ask for a summary of the earlier messages, None if there are too few to compact
+    pub fn compact_prompt(&self) -> Option<String> {
+        let messages = &self.messages[self.compact_range()?];
+        let transcript = messages
+            .iter()
+            .map(|v| format!("{}: {}", v.role.as_str(), v.content.trim()))
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        Some(format!(
+            "Summarize the conversation below so that it can be continued from the summary alone. \
+Keep facts, decisions, names, code and open questions, drop pleasantries. Reply with the summary only.\n\n\
+<conversation>\n{transcript}\n</conversation>"
+        ))
+    }
+
+    /// Replace the messages summarized by `compact_prompt` with the summary
+    pub fn compact(&mut self, summary: &str) -> Result<()> {
+        let range = match self.compact_range() {
+            Some(v) => v,
+            None => bail!("Error: Not enough messages to compact"),
+        };
+        self.messages.splice(
+            range,
+            [Message {
+                role: MessageRole::System,
+                content: format!("Summary of the earlier conversation:\n{}", summary.trim()),
+                images: vec![],
+            }],
+        );
+        self.tokens = num_tokens_from_messages(&self.messages);
+        Ok(())
+    }
+
+    /// Messages after the role messages and before the last exchange
+    fn compact_range(&self) -> Option<std::ops::Range<usize>> {
+        let prefix_len = self.role.as_ref().map(|v| v.prefix_len()).unwrap_or(0);
+        let end = self.messages.len().saturating_sub(KEEP_MESSAGES);
+        if end < prefix_len + 2 {
+            return None;
+        }
+        Some(prefix_len..end)
     }
 
     pub fn build_emssages(&self, content: &str) -> Vec<Message> {
@@ -83,12 +311,135 @@ impl Conversation {
             messages.push(Message {
                 role: MessageRole::User,
                 content: content.into(),
+                images: vec![],
             });
         }
         messages
     }
 
-    pub fn reamind_tokens(&self) -> usize {
-        MAX_TOKENS.saturating_sub(self.tokens)
+    pub fn reamind_tokens(&self, max_input_tokens: usize) -> usize {
+        max_input_tokens.saturating_sub(self.tokens)
+    }
+
+    pub fn export_markdown(&self) -> String {
+        let mut output = String::new();
+        if let Some(role) = self.role.as_ref() {
+            if !role.is_temp() {
+                output.push_str(&format!("# {}\n\n", role.name));
+            }
+        }
+        for message in self.messages.iter() {
+            let title = match message.role {
+                MessageRole::System => "System",
+                MessageRole::User => "User",
+                MessageRole::Assistant => "Assistant",
+            };
+            output.push_str(&format!("## {title}\n\n{}\n\n", message.content.trim()));
+        }
+        output
+    }
+
+    pub fn export_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).with_context(|| "Failed to serialize conversation")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_markdown() {
+        let mut conversation = Conversation::new(None);
+        conversation.add_message("1 + 1 = ?", "2").unwrap();
+        assert_eq!(
+            conversation.export_markdown(),
+            "## User\n\n1 + 1 = ?\n\n## Assistant\n\n2\n\n"
+        );
+    }
+
+    #[test]
+    fn test_title() {
+        let mut conversation = Conversation::new(None);
+        assert!(conversation.title_prompt().is_none());
+        conversation
+            .add_message("How to sort a Vec?", "Use sort")
+            .unwrap();
+        assert_eq!(conversation.title(), "How to sort a Vec?");
+        let prompt = conversation.title_prompt().unwrap();
+        assert!(prompt.contains("user: How to sort a Vec?\n\nassistant: Use sort\n</conversation>"));
+        conversation.set_title("\n\"Sorting Vectors in Rust.\"\n");
+        assert_eq!(conversation.title(), "Sorting Vectors in Rust");
+        assert!(conversation.title_prompt().is_none());
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut conversation = Conversation::new(Some(Role::new("You are a calculator", None)));
+        conversation.add_message("1 + 1 = ?", "2").unwrap();
+        assert!(conversation.compact_prompt().is_none());
+        conversation.add_message("2 + 2 = ?", "4").unwrap();
+        let prompt = conversation.compact_prompt().unwrap();
+        assert!(prompt.contains("user: 1 + 1 = ?\n\nassistant: 2\n</conversation>"));
+        conversation.compact("1 + 1 is 2").unwrap();
+        let contents: Vec<&str> = conversation
+            .messages
+            .iter()
+            .map(|v| v.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "You are a calculator",
+                "Summary of the earlier conversation:\n1 + 1 is 2",
+                "2 + 2 = ?",
+                "4"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut conversation = Conversation::new(Some(Role::new("You are a parrot", None)));
+        let long = "word ".repeat(1500);
+        conversation.add_message(&long, &long).unwrap();
+        conversation.add_message("hi", "hi").unwrap();
+        assert_eq!(conversation.truncate("bye", 4096), 0);
+        assert_eq!(conversation.truncate(&long, 4096), 2);
+        let contents: Vec<&str> = conversation
+            .messages
+            .iter()
+            .map(|v| v.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["You are a parrot", "hi", "hi"]);
+        assert_eq!(
+            "truncate".parse::<ContextStrategy>().unwrap(),
+            ContextStrategy::Truncate
+        );
+        assert!("drop".parse::<ContextStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_extend_last_reply() {
+        let mut conversation = Conversation::new(None);
+        assert!(conversation.extend_last_reply("more").is_err());
+        conversation.add_message("count to 4", "1, 2").unwrap();
+        let tokens = conversation.tokens;
+        conversation.extend_last_reply(", 3, 4").unwrap();
+        assert_eq!(conversation.messages[1].content, "1, 2, 3, 4");
+        assert!(conversation.tokens > tokens);
+    }
+
+    #[test]
+    fn test_pop_last_exchange() {
+        let mut conversation = Conversation::new(Some(Role::new("You are a calculator", None)));
+        conversation.add_message("1 + 1 = ?", "2").unwrap();
+        conversation.add_message("2 + 2 = ?", "4").unwrap();
+        conversation.pop_last_exchange().unwrap();
+        assert_eq!(conversation.messages.len(), 3);
+        assert_eq!(conversation.messages[2].content, "2");
+        conversation.pop_last_exchange().unwrap();
+        assert!(conversation.messages.is_empty());
+        assert!(conversation.pop_last_exchange().is_err());
     }
 }
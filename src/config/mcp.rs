@@ -0,0 +1,309 @@
+use super::Tool;
+
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+/// Separates the server name from the tool name in the function name seen by the model
+const NAME_SEPARATOR: &str = "__";
+/// Seconds to wait for an answer of the server unless it sets `timeout`
+const DEFAULT_TIMEOUT: u64 = 60;
+
+/// An MCP server launched as a child process and spoken to over stdio
+#[derive(Debug, Clone, Deserialize)]
+pub struct McpServer {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Seconds to wait for an answer, 60 if absent
+    pub timeout: Option<u64>,
+}
+
+impl McpServer {
+    /// Start the server and list what it offers as tools
+    pub fn connect(&self, name: &str) -> Result<Vec<Tool>> {
+        let client = McpClient::spawn(self)
+            .with_context(|| format!("Failed to start MCP server `{name}`"))?;
+        let client = Arc::new(Mutex::new(client));
+        let mut tools = vec![];
+        let (has_tools, has_resources) = {
+            let client = client.lock();
+            (
+                client.capabilities.get("tools").is_some(),
+                client.capabilities.get("resources").is_some(),
+            )
+        };
+        if has_tools {
+            for item in client.lock().list("tools/list", "tools")? {
+                let tool_name = item["name"]
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Invalid tool from MCP server `{name}`"))?;
+                tools.push(Tool {
+                    name: format!("{name}{NAME_SEPARATOR}{tool_name}"),
+                    description: item["description"].as_str().unwrap_or_default().into(),
+                    parameters: item["inputSchema"].clone(),
+                    command: None,
+                    server: Some(McpTool {
+                        client: client.clone(),
+                        method: McpMethod::CallTool(tool_name.into()),
+                    }),
+                });
+            }
+        }
+        if has_resources {
+            let resources = client.lock().list("resources/list", "resources")?;
+            let uris: Vec<String> = resources
+                .iter()
+                .filter_map(|v| {
+                    let uri = v["uri"].as_str()?;
+                    Some(match v["name"].as_str() {
+                        Some(title) => format!("{uri} ({title})"),
+                        None => uri.to_string(),
+                    })
+                })
+                .collect();
+            tools.push(Tool {
+                name: format!("{name}{NAME_SEPARATOR}read_resource"),
+                description: format!(
+                    "Read a resource of {name} by uri. Available resources: {}",
+                    uris.join(", ")
+                ),
+                parameters: json!({
+                    "type": "object",
+                    "properties": { "uri": { "type": "string" } },
+                    "required": ["uri"],
+                }),
+                command: None,
+                server: Some(McpTool {
+                    client: client.clone(),
+                    method: McpMethod::ReadResource,
+                }),
+            });
+        }
+        Ok(tools)
+    }
+}
+
+pub type SharedMcpClient = Arc<Mutex<McpClient>>;
+
+/// What a tool backed by an MCP server does when called
+#[derive(Debug, Clone)]
+pub struct McpTool {
+    client: SharedMcpClient,
+    method: McpMethod,
+}
+
+#[derive(Debug, Clone)]
+enum McpMethod {
+    CallTool(String),
+    ReadResource,
+}
+
+impl McpTool {
+    pub fn run(&self, arguments: &str) -> Result<String> {
+        let arguments: Value = if arguments.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(arguments).with_context(|| "Invalid arguments")?
+        };
+        let mut client = self.client.lock();
+        match &self.method {
+            McpMethod::CallTool(name) => {
+                let result = client.request(
+                    "tools/call",
+                    json!({ "name": name, "arguments": arguments }),
+                )?;
+                let text = join_text(&result["content"]);
+                if result["isError"].as_bool().unwrap_or_default() {
+                    bail!("{text}");
+                }
+                Ok(text)
+            }
+            McpMethod::ReadResource => {
+                let result =
+                    client.request("resources/read", json!({ "uri": arguments["uri"] }))?;
+                Ok(join_text(&result["contents"]))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct McpClient {
+    child: Child,
+    stdin: ChildStdin,
+    /// Lines of stdout, read on a thread so that a hung server cannot block
+    lines: Receiver<String>,
+    timeout: Duration,
+    next_id: u64,
+    capabilities: Value,
+}
+
+impl McpClient {
+    fn spawn(server: &McpServer) -> Result<Self> {
+        let mut child = Command::new(&server.command)
+            .args(&server.args)
+            .envs(&server.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to run `{}`", server.command))?;
+        let stdin = child.stdin.take().ok_or_else(|| anyhow!("No stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("No stdout"))?;
+        let mut client = Self {
+            child,
+            stdin,
+            lines: read_lines(stdout),
+            timeout: Duration::from_secs(server.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            next_id: 0,
+            capabilities: Value::Null,
+        };
+        let result = client.request(
+            "initialize",
+            json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": {},
+                "clientInfo": {
+                    "name": env!("CARGO_CRATE_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            }),
+        )?;
+        client.capabilities = result["capabilities"].clone();
+        client.send(&json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/initialized",
+        }))?;
+        Ok(client)
+    }
+
+    /// Collect every page of a paginated list
+    fn list(&mut self, method: &str, key: &str) -> Result<Vec<Value>> {
+        let mut items = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match cursor.as_ref() {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let result = self.request(method, params)?;
+            if let Some(v) = result[key].as_array() {
+                items.extend(v.iter().cloned());
+            }
+            match result["nextCursor"].as_str() {
+                Some(v) => cursor = Some(v.to_string()),
+                None => break,
+            }
+        }
+        Ok(items)
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(&json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }))?;
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let line = match self
+                .lines
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    bail!("{method} got no answer in {}s", self.timeout.as_secs())
+                }
+                Err(RecvTimeoutError::Disconnected) => bail!("MCP server exited"),
+            };
+            let message: Value = match serde_json::from_str(&line) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            // skip notifications and requests from the server
+            if message["id"].as_u64() != Some(id) || message.get("method").is_some() {
+                continue;
+            }
+            if let Some(err_msg) = message["error"]["message"].as_str() {
+                bail!("{method} failed, {err_msg}");
+            }
+            return Ok(message["result"].clone());
+        }
+    }
+
+    fn send(&mut self, message: &Value) -> Result<()> {
+        writeln!(self.stdin, "{message}")?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for McpClient {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Forward the lines of stdout until the server exits
+fn read_lines(stdout: ChildStdout) -> Receiver<String> {
+    let (tx, rx) = unbounded();
+    std::thread::spawn(move || {
+        let mut stdout = BufReader::new(stdout);
+        loop {
+            let mut line = String::new();
+            match stdout.read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
+
+fn join_text(content: &Value) -> String {
+    content
+        .as_array()
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(|v| v["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_text() {
+        let content = json!([
+            {"type": "text", "text": "line 1"},
+            {"type": "image", "data": "..."},
+            {"type": "text", "text": "line 2"},
+        ]);
+        assert_eq!(join_text(&content), "line 1\nline 2");
+        assert_eq!(join_text(&Value::Null), "");
+    }
+}
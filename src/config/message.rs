@@ -3,12 +3,35 @@ use crate::utils::count_tokens;
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
+/// Token limit of models missing from `CONTEXT_SIZES`
 pub const MAX_TOKENS: usize = 4096;
+/// Context window of models, matched by model name prefix
+const CONTEXT_SIZES: [(&str, usize); 14] = [
+    ("gpt-3.5-turbo-instruct", 4096),
+    ("gpt-3.5-turbo", 16385),
+    ("gpt-4o", 128000),
+    ("gpt-4-turbo", 128000),
+    ("gpt-4-32k", 32768),
+    ("gpt-4", 8192),
+    ("claude-3", 200000),
+    ("claude-2", 100000),
+    ("gemini-1.5", 1048576),
+    ("gemini-1.0-pro", 30720),
+    ("llama3.1", 131072),
+    ("llama3", 8192),
+    ("mistral", 32768),
+    ("nomic-embed-text", 8192),
+];
+/// Every message follows <|start|>{role/name}\n{content}<|end|>\n
+const TOKENS_PER_MESSAGE: usize = 3;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Message {
     pub role: MessageRole,
     pub content: String,
+    /// Attached images as data urls, e.g. `data:image/png;base64,...`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
 }
 
 impl Message {
@@ -16,11 +39,19 @@ impl Message {
         Self {
             role: MessageRole::User,
             content: content.to_string(),
+            images: vec![],
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Media type and base64 data of a data url
+pub fn split_data_url(url: &str) -> Option<(&str, &str)> {
+    let (head, data) = url.strip_prefix("data:")?.split_once(',')?;
+    let mime_type = head.strip_suffix(";base64")?;
+    Some((mime_type, data))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum MessageRole {
     System,
@@ -28,22 +59,45 @@ pub enum MessageRole {
     User,
 }
 
-pub fn within_max_tokens_limit(messages: &[Message]) -> Result<()> {
+impl MessageRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageRole::System => "system",
+            MessageRole::Assistant => "assistant",
+            MessageRole::User => "user",
+        }
+    }
+}
+
+pub fn max_input_tokens(model: &str) -> usize {
+    CONTEXT_SIZES
+        .iter()
+        .find(|(name, _)| model.starts_with(name))
+        .map(|(_, size)| *size)
+        .unwrap_or(MAX_TOKENS)
+}
+
+pub fn within_max_tokens_limit(messages: &[Message], limit: usize) -> Result<()> {
     let tokens = num_tokens_from_messages(messages);
-    if tokens >= MAX_TOKENS {
-        bail!("Exceed max tokens limit")
+    if tokens >= limit {
+        bail!("Exceed max tokens limit of {limit}")
     }
     Ok(())
 }
 
+/// Count tokens the way OpenAI bills chat messages.
+///
+/// See https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
+/// Other services use their own tokenizers, cl100k_base is a close approximation for them.
 pub fn num_tokens_from_messages(messages: &[Message]) -> usize {
     let mut num_tokens = 0;
     for message in messages.iter() {
-        num_tokens += 4;
+        num_tokens += TOKENS_PER_MESSAGE;
+        num_tokens += count_tokens(message.role.as_str());
         num_tokens += count_tokens(&message.content);
-        num_tokens += 1; // role always take 1 token
     }
-    num_tokens += 2;
+    // every reply is primed with <|start|>assistant<|message|>
+    num_tokens += 3;
     num_tokens
 }
 
@@ -58,4 +112,28 @@ mod tests {
             "{\"role\":\"user\",\"content\":\"Hello World\"}"
         )
     }
+
+    #[test]
+    fn test_split_data_url() {
+        assert_eq!(
+            split_data_url("data:image/png;base64,iVBORw0KGgo="),
+            Some(("image/png", "iVBORw0KGgo="))
+        );
+        assert_eq!(split_data_url("https://example.com/cat.png"), None);
+    }
+
+    #[test]
+    fn test_max_input_tokens() {
+        assert_eq!(max_input_tokens("gpt-4"), 8192);
+        assert_eq!(max_input_tokens("gpt-4-32k-0613"), 32768);
+        assert_eq!(max_input_tokens("gpt-4o-mini"), 128000);
+        assert_eq!(max_input_tokens("claude-3-haiku-20240307"), 200000);
+        assert_eq!(max_input_tokens("my-finetune"), MAX_TOKENS);
+    }
+
+    #[test]
+    fn test_num_tokens_from_messages() {
+        let messages = vec![Message::new("Say this is a test!")];
+        assert_eq!(num_tokens_from_messages(&messages), 13);
+    }
 }
@@ -1,37 +1,104 @@
+mod api_key;
+mod cache;
 mod conversation;
+mod mcp;
 mod message;
 mod role;
+mod role_repo;
+mod spend;
+mod tool;
+mod usage;
+mod validate;
+mod watch;
 
-use self::message::Message;
-use self::role::Role;
-use self::{conversation::Conversation, message::within_max_tokens_limit};
+pub use self::conversation::{ContextStrategy, Conversation};
+use self::mcp::McpServer;
+use self::message::{max_input_tokens, within_max_tokens_limit, MAX_TOKENS};
+pub use self::message::{num_tokens_from_messages, split_data_url, Message, MessageRole};
+pub use self::role::Role;
+pub use self::role_repo::sync_role_repo;
+pub use self::spend::BudgetAction;
+pub use self::tool::{Tool, ToolCall};
+pub use self::usage::Usage;
+pub use self::watch::watch_config;
 
-use crate::utils::now;
+use crate::client::ClientType;
+use crate::rag::Rag;
+use crate::repl::KeybindingsType;
+use crate::utils::{count_tokens, edit_text, now};
 
 use anyhow::{anyhow, bail, Context, Result};
-use inquire::{Confirm, Text};
+use inquire::{Confirm, CustomType, Text};
+use log::{warn, LevelFilter};
 use parking_lot::Mutex;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 use std::{
-    env,
-    fs::{create_dir_all, read_to_string, File, OpenOptions},
+    collections::BTreeMap,
+    env, fmt,
+    fs::{create_dir_all, read_dir, read_to_string, File, OpenOptions},
     io::Write,
     path::{Path, PathBuf},
     process::exit,
+    str::FromStr,
     sync::Arc,
 };
 
 const CONFIG_FILE_NAME: &str = "config.yaml";
+const CONFIG_TOML_FILE_NAME: &str = "config.toml";
+const API_KEY_ENV_NAME: &str = "AICHAT_API_KEY";
 const ROLES_FILE_NAME: &str = "roles.yaml";
+const ROLES_TOML_FILE_NAME: &str = "roles.toml";
+const ROLES_DIR_NAME: &str = "roles";
 const HISTORY_FILE_NAME: &str = "history.txt";
+const LOG_FILE_NAME: &str = "aichat.log";
 const MESSAGE_FILE_NAME: &str = "messages.md";
-const SET_COMPLETIONS: [&str; 9] = [
+const MESSAGE_JSONL_FILE_NAME: &str = "messages.jsonl";
+const REPLRC_FILE_NAME: &str = "replrc";
+const RECALL_FILE_NAME: &str = "recall.bin";
+
+const TRUNCATED_MARK: &str = "(truncated, aborted by the user)";
+const SESSIONS_DIR_NAME: &str = "sessions";
+const THEMES_DIR_NAME: &str = "themes";
+const TOOLS_DIR_NAME: &str = "tools";
+const RAGS_DIR_NAME: &str = "rags";
+const AUTOSAVE_SESSION_NAME: &str = "last";
+const BUILTIN_ROLES: &str = include_str!("../../assets/roles.yaml");
+const SET_COMPLETIONS: [&str; 37] = [
     ".set api_key",
+    ".set model",
+    ".set api_base",
     ".set temperature",
+    ".set max_tokens",
+    ".set max_input_tokens",
+    ".set top_p",
+    ".set presence_penalty",
+    ".set frequency_penalty",
+    ".set seed",
     ".set save true",
     ".set save false",
+    ".set save_format markdown",
+    ".set save_format jsonl",
+    ".set monthly_budget",
+    ".set budget_action warn",
+    ".set budget_action refuse",
+    ".set cache true",
+    ".set cache false",
+    ".set stream true",
+    ".set stream false",
+    ".set speak true",
+    ".set speak false",
+    ".set voice",
     ".set highlight true",
     ".set highlight false",
+    ".set theme",
+    ".set light_theme true",
+    ".set light_theme false",
+    ".set tools true",
+    ".set tools false",
+    ".set context_strategy error",
+    ".set context_strategy truncate",
+    ".set context_strategy summarize",
     ".set proxy",
     ".set dry_run true",
     ".set dry_run false",
@@ -39,61 +106,400 @@ const SET_COMPLETIONS: [&str; 9] = [
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
-    /// Openai api key
-    pub api_key: String,
+    /// Which LLM service to use
+    #[serde(default)]
+    pub client: ClientType,
+    /// Api key of the LLM service, a list of keys is tried in order when one is rejected
+    #[serde(default, deserialize_with = "deserialize_api_keys")]
+    pub api_key: Vec<String>,
+    /// Command printing the api key, e.g. `pass show openai`, used when `api_key` is not set
+    pub api_key_cmd: Option<String>,
+    /// Read the api key from the OS keychain, where `aichat keyring set` stores it
+    #[serde(default)]
+    pub keyring: bool,
+    /// Index of the api key in use
+    #[serde(skip)]
+    pub api_key_index: usize,
+    /// LLM model, default depends on client
+    pub model: Option<String>,
+    /// Model used to embed documents for rag, default depends on client
+    pub embedding_model: Option<String>,
+    /// Model used to transcribe audio, default depends on client
+    pub transcribe_model: Option<String>,
+    /// Model used by `aichat image`, default depends on client
+    pub image_model: Option<String>,
+    /// Cheap model used to title conversations after the first exchange, default is the model in use
+    pub title_model: Option<String>,
+    /// Api base url, e.g. http://localhost:8000/v1 for an OpenAI-compatible server or https://{RESOURCE}.openai.azure.com
+    #[serde(default, deserialize_with = "deserialize_env_string")]
+    pub api_base: Option<String>,
+    /// Azure OpenAI deployment name
+    pub deployment_id: Option<String>,
+    /// Azure OpenAI api version
+    pub api_version: Option<String>,
+    /// OpenAI organization for users who belong to multiple organizations
+    pub organization_id: Option<String>,
+    /// OpenAI project the requests are billed to
+    pub project_id: Option<String>,
     /// What sampling temperature to use, between 0 and 2
     pub temperature: Option<f64>,
+    /// The maximum number of tokens to generate in the reply
+    pub max_tokens: Option<usize>,
+    /// Token limit of the messages sent, default depends on model
+    pub max_input_tokens: Option<usize>,
+    /// Nucleus sampling, between 0 and 1
+    pub top_p: Option<f64>,
+    /// Penalize tokens that already appeared, between -2 and 2
+    pub presence_penalty: Option<f64>,
+    /// Penalize tokens by how often they appeared, between -2 and 2
+    pub frequency_penalty: Option<f64>,
+    /// Sequences where the model will stop generating further tokens
+    pub stop: Option<Vec<String>>,
+    /// Sample deterministically if the service supports it
+    pub seed: Option<i64>,
     /// Whether to persistently save chat messages
     #[serde(default)]
     pub save: bool,
+    /// How saved messages are written: markdown to `messages.md` or jsonl to `messages.jsonl`
+    #[serde(default)]
+    pub save_format: SaveFormat,
+    /// Estimated spend in USD allowed per month, from the prices of known models
+    pub monthly_budget: Option<f64>,
+    /// What to do once `monthly_budget` is spent: warn or refuse
+    #[serde(default)]
+    pub budget_action: BudgetAction,
+    /// If set true, replies are cached on disk and identical requests are answered from the cache
+    #[serde(default)]
+    pub cache: bool,
+    /// If set false, fetch the whole reply and render it once instead of streaming
+    #[serde(default = "stream_value")]
+    pub stream: bool,
+    /// If set true, read replies aloud
+    #[serde(default)]
+    pub speak: bool,
+    /// Voice of the text-to-speech, default depends on client
+    pub voice: Option<String>,
+    /// Command reading text from stdin aloud, e.g. `espeak --stdin`, used instead of the client
+    pub speak_command: Option<String>,
     /// Whether to disable highlight
     #[serde(default = "highlight_value")]
     pub highlight: bool,
+    /// Highlight theme, loaded from `<config_dir>/themes/<theme>.theme.bin`
+    pub theme: Option<String>,
+    /// If set true, use the builtin light theme when no theme is set, detected from the terminal if absent
+    pub light_theme: Option<bool>,
+    /// Key bindings of the REPL line editor: emacs or vi
+    #[serde(default)]
+    pub keybindings: KeybindingsType,
+    /// Regexes of commands `--execute` runs without the extra confirmation of dangerous ones
+    #[serde(default)]
+    pub command_allowlist: Vec<String>,
+    /// Regexes of commands `--execute` treats as dangerous on top of the builtin patterns
+    #[serde(default)]
+    pub command_denylist: Vec<String>,
+    /// If set true, let the model call the functions declared in `<config_dir>/tools/`
+    #[serde(default)]
+    pub tools: bool,
+    /// MCP servers whose tools are offered along with the tools dir
+    #[serde(default)]
+    pub mcp_servers: BTreeMap<String, McpServer>,
+    /// Headers added to every request to the LLM service, e.g. for api gateways
+    #[serde(default)]
+    pub extra_headers: BTreeMap<String, String>,
+    /// Seconds to wait for a whole reply, or between the events of a streamed one
+    pub timeout: Option<u64>,
+    /// Seconds to wait for the first token of a streamed reply
+    pub stream_first_token_timeout: Option<u64>,
+    /// Cap of the bytes downloaded from a url attached with `-f` or `.file`
+    pub fetch_max_bytes: Option<usize>,
+    /// PEM file of an extra CA to trust, e.g. of a TLS intercepting proxy
+    pub ca_cert: Option<PathBuf>,
+    /// PEM file of the client certificate for mutual TLS
+    pub client_cert: Option<PathBuf>,
+    /// PEM file of the private key of `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// If set true, accept any server certificate. Dangerous, for self-signed endpoints only
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
     /// Set proxy
+    #[serde(default, deserialize_with = "deserialize_env_string")]
     pub proxy: Option<String>,
-    /// Used only for debugging
+    /// Print the request instead of sending it, for debugging
     #[serde(default)]
     pub dry_run: bool,
+    /// Level of the logs written to `<data_dir>/aichat.log`: off, error, warn, info, debug or trace
+    #[serde(default, deserialize_with = "deserialize_log_level")]
+    pub log_level: Option<LevelFilter>,
+    /// What to do when a conversation no longer fits in the token limit
+    #[serde(default)]
+    pub context_strategy: ContextStrategy,
+    /// Role active at start, optionally followed by its variables, e.g. `translator lang=fr`
+    pub default_role: Option<String>,
     /// If set ture, start a conversation immediately upon repl
     #[serde(default)]
     pub conversation_first: bool,
+    /// If set true, reload the most recent session upon repl
+    #[serde(default)]
+    pub resume_last_conversation: bool,
+    /// Git or https urls of shared role packs, fetched into the roles dir by `aichat roles sync`
+    #[serde(default)]
+    pub role_repos: Vec<String>,
     /// Predefined roles
     #[serde(skip)]
     pub roles: Vec<Role>,
+    /// Functions declared in the tools dir
+    #[serde(skip)]
+    pub functions: Vec<Tool>,
+    /// Whether the MCP servers were started, they are started on first use
+    #[serde(skip)]
+    pub mcp_connected: bool,
+    /// Settings of the command line, applied again after a reload
+    #[serde(skip)]
+    pub overrides: Overrides,
     /// Current selected role
     #[serde(skip)]
     pub role: Option<Role>,
+    /// Documents retrieved from for every question
+    #[serde(skip)]
+    pub rag: Option<Arc<Rag>>,
     /// Current conversation
     #[serde(skip)]
     pub conversation: Option<Conversation>,
+    /// Name of the session the current conversation was saved to or loaded from
+    #[serde(skip)]
+    pub session: Option<String>,
+    /// Id of the current conversation, ids count up from 1 during a run
+    #[serde(skip)]
+    pub conversation_id: usize,
+    /// Conversations set aside for another one, by id, with their session names
+    #[serde(skip)]
+    pub other_conversations: BTreeMap<usize, (Conversation, Option<String>)>,
+    #[serde(skip)]
+    last_conversation_id: usize,
+    /// Token usage accumulated during this run
+    #[serde(skip)]
+    pub usage: Usage,
+    /// Temperature of the next request only, e.g. `.regenerate --temperature 1.2`
+    #[serde(skip)]
+    pub next_temperature: Option<f64>,
+    /// Why the last reply stopped, as reported by the service, e.g. `length`
+    #[serde(skip)]
+    pub finish_reason: Option<String>,
+    /// Whether the warning of the spent `monthly_budget` was printed
+    #[serde(skip)]
+    pub budget_warned: bool,
+    /// Images attached to the next message as data urls, e.g. by `.paste-image`
+    #[serde(skip)]
+    pub images: Vec<String>,
 }
 
 pub type SharedConfig = Arc<Mutex<Config>>;
 
+/// Settings given on the command line, they win over the config file
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub seed: Option<i64>,
+    pub proxy: Option<String>,
+    pub dry_run: bool,
+    pub resume: bool,
+    pub no_cache: bool,
+    pub no_highlight: bool,
+    pub no_stream: bool,
+    /// Detected background of the terminal, used when the config sets no theme
+    pub light_theme: Option<bool>,
+}
+
+/// Format of the file saved messages are appended to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SaveFormat {
+    #[default]
+    Markdown,
+    /// One json object per exchange
+    Jsonl,
+}
+
+/// A line of `messages.jsonl`
+#[derive(Serialize)]
+struct SavedMessage<'a> {
+    timestamp: &'a str,
+    role: Option<&'a str>,
+    /// Prompt of a temporary role
+    prompt: Option<&'a str>,
+    model: Option<String>,
+    input: &'a str,
+    output: &'a str,
+    input_tokens: usize,
+    output_tokens: usize,
+}
+
+impl fmt::Display for SaveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SaveFormat::Markdown => "markdown",
+            SaveFormat::Jsonl => "jsonl",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for SaveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "markdown" => Ok(SaveFormat::Markdown),
+            "jsonl" => Ok(SaveFormat::Jsonl),
+            _ => bail!("Invalid value, expect markdown or jsonl"),
+        }
+    }
+}
+
 impl Config {
     pub fn init(is_interactive: bool) -> Result<Config> {
         let config_path = Config::config_file()?;
-        if is_interactive && !config_path.exists() {
+        // The default openai client works with just an api key from env
+        let env_only = !config_path.exists()
+            && [API_KEY_ENV_NAME, "OPENAI_API_KEY"]
+                .iter()
+                .any(|name| env::var(name).is_ok());
+        if is_interactive && !config_path.exists() && !env_only {
             create_config_file(&config_path)?;
         }
-        let content = read_to_string(&config_path)
-            .with_context(|| format!("Failed to load config at {}", config_path.display()))?;
-        let mut config: Config = serde_yaml::from_str(&content)
-            .with_context(|| format!("Invalid config at {}", config_path.display()))?;
+        Self::load(env_only)
+    }
+
+    fn load(env_only: bool) -> Result<Config> {
+        let config_path = Config::config_file()?;
+        let content = if env_only {
+            "{}".to_string()
+        } else {
+            read_to_string(&config_path)
+                .with_context(|| format!("Failed to load config at {}", config_path.display()))?
+        };
+        let mut config: Config = if is_toml(&config_path) {
+            toml::from_str(&content).map_err(anyhow::Error::from)
+        } else {
+            serde_yaml::from_str(&content).map_err(anyhow::Error::from)
+        }
+        .with_context(|| format!("Invalid config at {}", config_path.display()))?;
+        config.load_api_key_from_secrets()?;
+        config.load_api_key_from_env();
         config.load_roles()?;
+        config.load_builtin_roles();
+        config.functions = Tool::load_dir(&Self::tools_dir()?)?;
 
         Ok(config)
     }
 
+    /// Read `config.yaml` and the roles again, to be passed to `reload`.
+    /// Done without holding the config since `api_key_cmd` may take a while.
+    pub fn load_again() -> Result<Config> {
+        Self::load(!Self::config_file()?.exists())
+    }
+
+    /// Switch to the config read by `load_again`, keeping the role, rag, conversations, usage
+    /// and command line settings of this run. Settings changed by `.set` are lost.
+    pub fn reload(&mut self, mut config: Config) {
+        // an edited role takes effect, a removed one stays until cleared
+        config.role = match self.role.take() {
+            Some(role) if !role.is_temp() => config.find_role(&role.name).or(Some(role)),
+            role => role,
+        };
+        config.rag = self.rag.take();
+        config.conversation = self.conversation.take();
+        config.session = self.session.take();
+        config.conversation_id = self.conversation_id;
+        config.other_conversations = std::mem::take(&mut self.other_conversations);
+        config.last_conversation_id = self.last_conversation_id;
+        config.usage = std::mem::take(&mut self.usage);
+        config.budget_warned = self.budget_warned;
+        config.images = std::mem::take(&mut self.images);
+        if config.api_key == self.api_key {
+            config.api_key_index = self.api_key_index;
+        }
+        // the MCP servers keep running, their tools stay along with the reloaded tools dir
+        config
+            .functions
+            .extend(self.functions.drain(..).filter(|v| v.server.is_some()));
+        config.mcp_connected = self.mcp_connected;
+        config.apply_overrides(std::mem::take(&mut self.overrides));
+        *self = config;
+    }
+
+    /// Apply the command line settings and keep them for later reloads
+    pub fn apply_overrides(&mut self, overrides: Overrides) {
+        if let Some(model) = &overrides.model {
+            self.model = Some(model.clone());
+        }
+        if let Some(temperature) = overrides.temperature {
+            self.temperature = Some(temperature);
+        }
+        if let Some(seed) = overrides.seed {
+            self.seed = Some(seed);
+        }
+        if let Some(proxy) = &overrides.proxy {
+            self.proxy = Some(proxy.clone());
+        }
+        if overrides.dry_run {
+            self.dry_run = true;
+        }
+        if overrides.resume {
+            self.resume_last_conversation = true;
+        }
+        if overrides.no_cache {
+            self.cache = false;
+        }
+        if overrides.no_highlight {
+            self.highlight = false;
+        }
+        if overrides.no_stream {
+            self.stream = false;
+        }
+        if self.theme.is_none() && self.light_theme.is_none() {
+            self.light_theme = overrides.light_theme;
+        }
+        self.overrides = overrides;
+    }
+
     pub fn on_repl(&mut self) -> Result<()> {
+        if self.resume_last_conversation {
+            if let Some(name) = self.latest_session()? {
+                return self.load_session(&name);
+            }
+        }
         if self.conversation_first {
-            self.start_conversation()?;
+            self.start_conversation();
+        }
+        Ok(())
+    }
+
+    /// Keep the conversation around so it can be resumed next time
+    pub fn on_repl_exit(&mut self) -> Result<()> {
+        if !self.resume_last_conversation {
+            return Ok(());
+        }
+        let has_messages = self
+            .conversation
+            .as_ref()
+            .is_some_and(|v| !v.messages.is_empty());
+        if has_messages {
+            // always `last`, a loaded session is only written by `.session save`
+            self.save_session(AUTOSAVE_SESSION_NAME)?;
         }
         Ok(())
     }
 
+    /// Builtin roles are named like `%shell%` and can be selected as `shell`
     pub fn find_role(&self, name: &str) -> Option<Role> {
-        self.roles.iter().find(|v| v.name == name).cloned()
+        let builtin_name = format!("%{name}%");
+        self.roles
+            .iter()
+            .find(|v| v.name == name)
+            .or_else(|| self.roles.iter().find(|v| v.name == builtin_name))
+            .cloned()
     }
 
     pub fn config_dir() -> Result<PathBuf> {
@@ -123,6 +529,38 @@ impl Config {
         Ok(path)
     }
 
+    /// Dir of the files aichat writes, e.g. sessions, history and logs: `$AICHAT_DATA_DIR`,
+    /// the config dir when `$AICHAT_CONFIG_DIR` is set, or `$XDG_DATA_HOME/aichat`
+    pub fn data_dir() -> Result<PathBuf> {
+        let crate_name = env!("CARGO_CRATE_NAME").to_ascii_uppercase();
+        let path = match env::var(format!("{crate_name}_DATA_DIR")) {
+            Ok(v) => PathBuf::from(v),
+            Err(_) if env::var(format!("{crate_name}_CONFIG_DIR")).is_ok() => {
+                return Self::config_dir()
+            }
+            Err(_) => {
+                let mut dir = dirs::data_dir().ok_or_else(|| anyhow!("Not found data dir"))?;
+                dir.push(env!("CARGO_CRATE_NAME"));
+                dir
+            }
+        };
+        if !path.exists() {
+            create_dir_all(&path)
+                .map_err(|err| anyhow!("Failed to create data dir at {}, {err}", path.display()))?;
+        }
+        Ok(path)
+    }
+
+    /// A file or dir in the data dir, or in the config dir where older versions put it
+    pub fn data_file(name: &str) -> Result<PathBuf> {
+        let path = Self::data_dir()?.join(name);
+        let legacy = Self::local_file(name)?;
+        if !path.exists() && legacy.exists() {
+            return Ok(legacy);
+        }
+        Ok(path)
+    }
+
     pub fn save_message(&self, input: &str, output: &str) -> Result<()> {
         if !self.save {
             return Ok(());
@@ -132,6 +570,24 @@ impl Config {
             return Ok(());
         }
         let timestamp = now();
+        if self.save_format == SaveFormat::Jsonl {
+            let role = self.role.as_ref();
+            let model = self
+                .get_model()
+                .or_else(|| self.client.default_model().map(|v| v.to_string()));
+            let line = SavedMessage {
+                timestamp: &timestamp,
+                role: role.filter(|v| !v.is_temp()).map(|v| v.name.as_str()),
+                prompt: role.filter(|v| v.is_temp()).map(|v| v.prompt.as_str()),
+                model,
+                input,
+                output,
+                input_tokens: count_tokens(input),
+                output_tokens: count_tokens(output),
+            };
+            let line = serde_json::to_string(&line).with_context(|| "Failed to save message")?;
+            return writeln!(file, "{line}").with_context(|| "Failed to save message");
+        }
         let output = match self.role.as_ref() {
             None => {
                 format!("# CHAT:[{timestamp}]\n{input}\n--------\n{output}\n--------\n\n",)
@@ -154,25 +610,233 @@ impl Config {
             .with_context(|| "Failed to save message")
     }
 
+    /// `config.yaml`, or `config.toml` if only that one exists
     pub fn config_file() -> Result<PathBuf> {
-        Self::local_file(CONFIG_FILE_NAME)
+        Self::yaml_or_toml_file(CONFIG_FILE_NAME, CONFIG_TOML_FILE_NAME)
     }
 
+    /// `roles.yaml`, or `roles.toml` if only that one exists
     pub fn roles_file() -> Result<PathBuf> {
-        Self::local_file(ROLES_FILE_NAME)
+        Self::yaml_or_toml_file(ROLES_FILE_NAME, ROLES_TOML_FILE_NAME)
+    }
+
+    fn yaml_or_toml_file(yaml_name: &str, toml_name: &str) -> Result<PathBuf> {
+        let path = Self::local_file(yaml_name)?;
+        let toml_path = Self::local_file(toml_name)?;
+        if !path.exists() && toml_path.exists() {
+            return Ok(toml_path);
+        }
+        Ok(path)
+    }
+
+    pub fn roles_dir() -> Result<PathBuf> {
+        Self::local_file(ROLES_DIR_NAME)
+    }
+
+    pub fn tools_dir() -> Result<PathBuf> {
+        Self::local_file(TOOLS_DIR_NAME)
     }
 
     pub fn history_file() -> Result<PathBuf> {
-        Self::local_file(HISTORY_FILE_NAME)
+        Self::data_file(HISTORY_FILE_NAME)
+    }
+
+    pub fn log_file() -> Result<PathBuf> {
+        Self::data_file(LOG_FILE_NAME)
+    }
+
+    /// REPL commands run on start, e.g. `.role coder`
+    pub fn replrc_file() -> Result<PathBuf> {
+        Self::local_file(REPLRC_FILE_NAME)
+    }
+
+    /// History of the REPL, kept apart for each session while one is active
+    pub fn repl_history_file(&self) -> Result<PathBuf> {
+        match self.session.as_ref() {
+            Some(name) => {
+                let dir = Self::sessions_dir()?;
+                create_dir_all(&dir).with_context(|| "Failed to create sessions dir")?;
+                Ok(dir.join(format!("{name}.history")))
+            }
+            None => Self::history_file(),
+        }
     }
 
     pub fn messages_file() -> Result<PathBuf> {
-        Self::local_file(MESSAGE_FILE_NAME)
+        Self::data_file(MESSAGE_FILE_NAME)
+    }
+
+    pub fn messages_jsonl_file() -> Result<PathBuf> {
+        Self::data_file(MESSAGE_JSONL_FILE_NAME)
+    }
+
+    /// The file `save_message` appends to, depends on `save_format`
+    pub fn save_file(&self) -> Result<PathBuf> {
+        match self.save_format {
+            SaveFormat::Markdown => Self::messages_file(),
+            SaveFormat::Jsonl => Self::messages_jsonl_file(),
+        }
+    }
+
+    pub fn recall_file() -> Result<PathBuf> {
+        Self::data_file(RECALL_FILE_NAME)
+    }
+
+    pub fn sessions_dir() -> Result<PathBuf> {
+        Self::data_file(SESSIONS_DIR_NAME)
+    }
+
+    pub fn session_file(name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("Error: Invalid session name `{name}`");
+        }
+        let mut path = Self::sessions_dir()?;
+        path.push(format!("{name}.yaml"));
+        Ok(path)
+    }
+
+    pub fn theme_file(name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("Error: Invalid theme name `{name}`");
+        }
+        let mut path = Self::local_file(THEMES_DIR_NAME)?;
+        path.push(format!("{name}.theme.bin"));
+        Ok(path)
+    }
+
+    pub fn rag_file(name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("Error: Invalid rag name `{name}`");
+        }
+        let mut path = Self::data_file(RAGS_DIR_NAME)?;
+        path.push(format!("{name}.bin"));
+        Ok(path)
+    }
+
+    pub fn set_rag(&mut self, name: &str) -> Result<()> {
+        if !Self::rag_file(name)?.exists() {
+            bail!("Error: Unknown rag `{name}`, create it with `aichat rag add {name} <paths>`");
+        }
+        self.rag = Some(Arc::new(Rag::load(name)?));
+        Ok(())
+    }
+
+    pub fn save_session(&mut self, name: &str) -> Result<PathBuf> {
+        let conversation = match self.conversation.as_ref() {
+            Some(v) => v,
+            None => bail!("Error: No conversation to save"),
+        };
+        let path = Self::write_session(name, conversation)?;
+        self.session = Some(name.to_string());
+        Ok(path)
+    }
+
+    pub fn write_session(name: &str, conversation: &Conversation) -> Result<PathBuf> {
+        let path = Self::session_file(name)?;
+        create_dir_all(Self::sessions_dir()?).with_context(|| "Failed to create sessions dir")?;
+        let content =
+            serde_yaml::to_string(conversation).with_context(|| "Failed to serialize session")?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write session to {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// A free session name made from the title of the conversation, None if untitled
+    pub fn titled_session_name(&self) -> Result<Option<String>> {
+        match self.conversation.as_ref().and_then(|v| v.title.as_ref()) {
+            Some(title) => Self::free_session_name(title),
+            None => Ok(None),
+        }
+    }
+
+    /// A session name made from the title that is not taken yet, None if the title has no words
+    pub fn free_session_name(title: &str) -> Result<Option<String>> {
+        let slug = slugify(title);
+        if slug.is_empty() {
+            return Ok(None);
+        }
+        let mut name = slug.clone();
+        let mut index = 1;
+        while Self::session_file(&name)?.exists() {
+            index += 1;
+            name = format!("{slug}-{index}");
+        }
+        Ok(Some(name))
+    }
+
+    /// Title of a saved session, empty if it can not be read
+    pub fn session_title(name: &str) -> String {
+        Self::session_file(name)
+            .ok()
+            .and_then(|path| read_to_string(path).ok())
+            .and_then(|content| serde_yaml::from_str::<Conversation>(&content).ok())
+            .map(|v| v.title())
+            .unwrap_or_default()
+    }
+
+    pub fn load_session(&mut self, name: &str) -> Result<()> {
+        let path = Self::session_file(name)?;
+        if !path.exists() {
+            bail!("Error: Unknown session `{name}`");
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load session at {}", path.display()))?;
+        let conversation: Conversation =
+            serde_yaml::from_str(&content).with_context(|| "Invalid session")?;
+        self.role = conversation.role.clone();
+        self.push_conversation(conversation, Some(name.to_string()));
+        Ok(())
+    }
+
+    pub fn latest_session(&self) -> Result<Option<String>> {
+        let mut latest = None;
+        for name in self.list_sessions()? {
+            let modified = Self::session_file(&name)?.metadata()?.modified()?;
+            if latest.as_ref().is_none_or(|(time, _)| modified > *time) {
+                latest = Some((modified, name));
+            }
+        }
+        Ok(latest.map(|(_, name)| name))
+    }
+
+    pub fn list_sessions(&self) -> Result<Vec<String>> {
+        let dir = Self::sessions_dir()?;
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut names = vec![];
+        for entry in read_dir(&dir).with_context(|| "Failed to read sessions dir")? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|v| v == "yaml") {
+                if let Some(name) = path.file_stem().and_then(|v| v.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn export_conversation(&self, format: &str, path: &Path) -> Result<()> {
+        let conversation = match self.conversation.as_ref() {
+            Some(v) => v,
+            None => bail!("Error: No conversation to export"),
+        };
+        let content = match format {
+            "md" | "markdown" => conversation.export_markdown(),
+            "json" => conversation.export_json()?,
+            _ => bail!("Error: Unknown export format `{format}`"),
+        };
+        std::fs::write(path, content)
+            .with_context(|| format!("Failed to export conversation to {}", path.display()))
     }
 
-    pub fn change_role(&mut self, name: &str) -> Result<String> {
+    /// `input` is the role name optionally followed by its variables, e.g. `translator lang=fr`
+    pub fn change_role(&mut self, input: &str) -> Result<String> {
+        let (name, args) = input.split_once(' ').unwrap_or((input, ""));
         match self.find_role(name) {
-            Some(role) => {
+            Some(mut role) => {
+                role.set_variables(args)?;
                 if let Some(conversation) = self.conversation.as_mut() {
                     conversation.update_role(&role)?;
                 }
@@ -185,6 +849,45 @@ impl Config {
         }
     }
 
+    /// Split `%name% text` into the role, optionally followed by its variables, and the text.
+    /// None when the input does not start with the name of a role.
+    pub fn split_inline_role<'a>(&self, input: &'a str) -> Option<(&'a str, &'a str)> {
+        let (spec, text) = input.strip_prefix('%')?.split_once('%')?;
+        let name = spec.split(' ').next().unwrap_or_default();
+        if name.is_empty() || self.find_role(name).is_none() {
+            return None;
+        }
+        Some((spec, text.trim_start()))
+    }
+
+    /// The role of `%name% text` and the text, to send the text with the role only
+    pub fn inline_role(&self, input: &str) -> Result<Option<(Role, String)>> {
+        let (spec, text) = match self.split_inline_role(input) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let (name, args) = spec.split_once(' ').unwrap_or((spec, ""));
+        let mut role = self
+            .find_role(name)
+            .ok_or_else(|| anyhow!("Unknown role `{name}`"))?;
+        role.set_variables(args)?;
+        Ok(Some((role, text.to_string())))
+    }
+
+    /// The role of `default_role` with its variables, None if not set
+    pub fn find_default_role(&self) -> Result<Option<Role>> {
+        let input = match self.default_role.as_deref() {
+            Some(v) => v.trim(),
+            None => return Ok(None),
+        };
+        let (name, args) = input.split_once(' ').unwrap_or((input, ""));
+        let mut role = self
+            .find_role(name)
+            .ok_or_else(|| anyhow!("Unknown role `{name}` in default_role"))?;
+        role.set_variables(args)?;
+        Ok(Some(role))
+    }
+
     pub fn clear_role(&mut self) -> Result<()> {
         if let Some(conversation) = self.conversation.as_ref() {
             conversation.can_clear_role()?;
@@ -202,25 +905,179 @@ impl Config {
         Ok(())
     }
 
-    pub fn get_temperature(&self) -> Option<f64> {
+    /// The messages the prompt of `.prompt` turns the next input into, None without one
+    pub fn preview_prompt(&self) -> Option<String> {
+        let role = self.role.as_ref().filter(|v| v.is_temp())?;
+        let messages = role.build_emssages("<your message>");
+        let output: Vec<String> = messages
+            .iter()
+            .map(|v| format!("{}: {}", v.role.as_str(), v.content))
+            .collect();
+        Some(output.join("\n"))
+    }
+
+    fn load_api_key_from_env(&mut self) {
+        if !self.api_key.is_empty() {
+            return;
+        }
+        let client_env_name = match self.client {
+            ClientType::OpenAI => Some("OPENAI_API_KEY"),
+            ClientType::AzureOpenAI => Some("AZURE_OPENAI_API_KEY"),
+            ClientType::Claude => Some("ANTHROPIC_API_KEY"),
+            ClientType::Gemini => Some("GEMINI_API_KEY"),
+            ClientType::Ollama => None,
+        };
+        let api_key = env::var(API_KEY_ENV_NAME)
+            .ok()
+            .or_else(|| client_env_name.and_then(|name| env::var(name).ok()));
+        if let Some(api_key) = api_key.filter(|v| !v.is_empty()) {
+            self.api_key = vec![api_key];
+        }
+    }
+
+    pub fn get_api_key(&self) -> Result<String> {
+        self.api_key
+            .get(self.api_key_index)
+            .cloned()
+            .ok_or_else(|| anyhow!("Miss config.api_key for {}", self.client))
+    }
+
+    /// Switch to the next api key, returns false if there is none left
+    pub fn rotate_api_key(&mut self) -> bool {
+        if self.api_key_index + 1 < self.api_key.len() {
+            self.api_key_index += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn api_key_info(&self) -> String {
+        match self.api_key.get(self.api_key_index) {
+            Some(key) if self.api_key.len() > 1 => format!(
+                "{} ({}/{})",
+                mask_api_key(key),
+                self.api_key_index + 1,
+                self.api_key.len()
+            ),
+            Some(key) => mask_api_key(key),
+            None => "-".into(),
+        }
+    }
+
+    pub fn get_model(&self) -> Option<String> {
         self.role
             .as_ref()
-            .and_then(|v| v.temperature)
-            .or(self.temperature)
+            .and_then(|v| v.model.clone())
+            .or_else(|| self.model.clone())
     }
 
-    pub fn echo_messages(&self, content: &str) -> String {
-        if let Some(conversation) = self.conversation.as_ref() {
-            conversation.echo_messages(content)
-        } else if let Some(role) = self.role.as_ref() {
-            role.echo_messages(content)
+    pub fn get_max_tokens(&self) -> Option<usize> {
+        self.role
+            .as_ref()
+            .and_then(|v| v.max_tokens)
+            .or(self.max_tokens)
+    }
+
+    /// `max_input_tokens`, or the context size of the model in use
+    pub fn get_max_input_tokens(&self) -> usize {
+        if let Some(value) = self.max_input_tokens {
+            return value;
+        }
+        match self.get_model() {
+            Some(model) => max_input_tokens(&model),
+            None => self
+                .client
+                .default_model()
+                .map(max_input_tokens)
+                .unwrap_or(MAX_TOKENS),
+        }
+    }
+
+    pub fn get_top_p(&self) -> Option<f64> {
+        self.role.as_ref().and_then(|v| v.top_p).or(self.top_p)
+    }
+
+    pub fn get_presence_penalty(&self) -> Option<f64> {
+        self.role
+            .as_ref()
+            .and_then(|v| v.presence_penalty)
+            .or(self.presence_penalty)
+    }
+
+    pub fn get_frequency_penalty(&self) -> Option<f64> {
+        self.role
+            .as_ref()
+            .and_then(|v| v.frequency_penalty)
+            .or(self.frequency_penalty)
+    }
+
+    pub fn get_stop(&self) -> Option<Vec<String>> {
+        self.role
+            .as_ref()
+            .and_then(|v| v.stop.clone())
+            .or_else(|| self.stop.clone())
+    }
+
+    pub fn get_temperature(&self) -> Option<f64> {
+        self.next_temperature.or_else(|| {
+            self.role
+                .as_ref()
+                .and_then(|v| v.temperature)
+                .or(self.temperature)
+        })
+    }
+
+    /// Declarations of the tools sent along with the request, if enabled
+    pub fn get_tools(&mut self) -> Result<Option<Value>> {
+        if !self.tools {
+            return Ok(None);
+        }
+        if !self.mcp_connected {
+            // a broken server leaves out its tools only
+            for (name, server) in self.mcp_servers.iter() {
+                match server.connect(name) {
+                    Ok(tools) => self.functions.extend(tools),
+                    Err(err) => warn!("{err:#}"),
+                }
+            }
+            self.mcp_connected = true;
+        }
+        if self.functions.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.functions.iter().map(|v| v.to_json()).collect()))
+    }
+
+    pub fn find_tool(&self, name: &str) -> Option<Tool> {
+        self.functions.iter().find(|v| v.name == name).cloned()
+    }
+
+    fn extra_headers_info(&self) -> String {
+        if self.extra_headers.is_empty() {
+            return "-".into();
+        }
+        let names: Vec<&str> = self.extra_headers.keys().map(|v| v.as_str()).collect();
+        names.join(", ")
+    }
+
+    fn tools_info(&self) -> String {
+        let mut names: Vec<&str> = self
+            .functions
+            .iter()
+            .filter(|v| v.server.is_none())
+            .map(|v| v.name.as_str())
+            .collect();
+        names.extend(self.mcp_servers.keys().map(|v| v.as_str()));
+        if names.is_empty() {
+            self.tools.to_string()
         } else {
-            content.to_string()
+            format!("{} ({})", self.tools, names.join(", "))
         }
     }
 
     pub fn build_messages(&self, content: &str) -> Result<Vec<Message>> {
-        let messages = if let Some(conversation) = self.conversation.as_ref() {
+        let mut messages = if let Some(conversation) = self.conversation.as_ref() {
             conversation.build_emssages(content)
         } else if let Some(role) = self.role.as_ref() {
             role.build_emssages(content)
@@ -228,12 +1085,60 @@ impl Config {
             let message = Message::new(content);
             vec![message]
         };
-        within_max_tokens_limit(&messages)?;
+        within_max_tokens_limit(&messages, self.get_max_input_tokens())?;
+        if let Some(message) = messages.last_mut() {
+            message.images = self.images.clone();
+        }
 
         Ok(messages)
     }
 
-    pub fn info(&self) -> Result<String> {
+    /// Whether the conversation has grown too long to be sent along with the input
+    pub fn need_compact(&self, content: &str) -> bool {
+        match self.conversation.as_ref() {
+            Some(conversation) => within_max_tokens_limit(
+                &conversation.build_emssages(content),
+                self.get_max_input_tokens(),
+            )
+            .is_err(),
+            None => false,
+        }
+    }
+
+    /// Drop the oldest exchanges of the conversation until the input fits
+    pub fn truncate_conversation(&mut self, content: &str) -> usize {
+        let max_input_tokens = self.get_max_input_tokens();
+        match self.conversation.as_mut() {
+            Some(conversation) => conversation.truncate(content, max_input_tokens),
+            None => 0,
+        }
+    }
+
+    /// Continue messages sent by an api client, returns the input to send.
+    /// The role prompt goes before the earlier messages.
+    pub fn set_api_messages(&mut self, mut messages: Vec<Message>) -> Result<String> {
+        let input = match messages.pop() {
+            Some(message) if message.role == MessageRole::User => message.content,
+            _ => bail!("The last message must be from the user"),
+        };
+        if messages.is_empty() {
+            self.conversation = None;
+            return Ok(input);
+        }
+        let mut prefix = match self.role.as_ref() {
+            Some(role) => role.build_emssages(&input),
+            None => vec![Message::new(&input)],
+        };
+        let input = prefix.pop().map(|v| v.content).unwrap_or(input);
+        let mut conversation = Conversation::new(None);
+        conversation.messages = prefix;
+        conversation.messages.extend(messages);
+        self.conversation = Some(conversation);
+        Ok(input)
+    }
+
+    /// Settings, files and usage, with the api key masked unless `full_api_key`
+    pub fn info(&self, full_api_key: bool) -> Result<String> {
         let file_info = |path: &Path| {
             let state = if path.exists() { "" } else { " ⚠️" };
             format!("{}{state}", path.display())
@@ -243,29 +1148,137 @@ impl Config {
             .as_ref()
             .map(|v| v.to_string())
             .unwrap_or("-".into());
+        let api_key = match self.api_key.get(self.api_key_index) {
+            _ if !full_api_key => self.api_key_info(),
+            Some(key) if self.api_key.len() > 1 => {
+                format!("{key} ({}/{})", self.api_key_index + 1, self.api_key.len())
+            }
+            Some(key) => key.clone(),
+            None => "-".into(),
+        };
+        // the model requests go to, of the role or the default of the client if not configured
+        let model = self
+            .get_model()
+            .or_else(|| self.client.default_model().map(|v| v.to_string()))
+            .unwrap_or("-".into());
+        let api_base = self.api_base.clone().unwrap_or("-".into());
         let temperature = self
             .temperature
             .map(|v| v.to_string())
             .unwrap_or("-".into());
+        let max_tokens = self.max_tokens.map(|v| v.to_string()).unwrap_or("-".into());
         let items = vec![
             ("config_file", file_info(&Config::config_file()?)),
             ("roles_file", file_info(&Config::roles_file()?)),
-            ("messages_file", file_info(&Config::messages_file()?)),
-            ("api_key", self.api_key.clone()),
+            ("data_dir", file_info(&Config::data_dir()?)),
+            ("sessions_dir", file_info(&Config::sessions_dir()?)),
+            ("messages_file", file_info(&self.save_file()?)),
+            ("client", self.client.to_string()),
+            ("api_key", api_key),
+            ("model", model),
+            ("embedding_model", option_info(&self.embedding_model)),
+            ("transcribe_model", option_info(&self.transcribe_model)),
+            ("image_model", option_info(&self.image_model)),
+            ("title_model", option_info(&self.title_model)),
+            ("api_base", api_base),
+            ("organization_id", option_info(&self.organization_id)),
+            ("project_id", option_info(&self.project_id)),
             ("temperature", temperature),
+            ("max_tokens", max_tokens),
+            ("max_input_tokens", self.get_max_input_tokens().to_string()),
+            ("top_p", option_info(&self.top_p)),
+            ("presence_penalty", option_info(&self.presence_penalty)),
+            ("frequency_penalty", option_info(&self.frequency_penalty)),
+            ("seed", option_info(&self.seed)),
+            (
+                "stop",
+                option_info(&self.stop.as_ref().map(|v| v.join(", "))),
+            ),
             ("save", self.save.to_string()),
+            ("save_format", self.save_format.to_string()),
+            ("monthly_budget", option_info(&self.monthly_budget)),
+            ("budget_action", self.budget_action.to_string()),
+            ("cache", self.cache.to_string()),
+            ("stream", self.stream.to_string()),
+            ("speak", self.speak.to_string()),
+            ("voice", option_info(&self.voice)),
+            ("speak_command", option_info(&self.speak_command)),
             ("highlight", self.highlight.to_string()),
+            ("theme", option_info(&self.theme)),
+            ("light_theme", option_info(&self.light_theme)),
+            ("keybindings", self.keybindings.to_string()),
+            ("command_allowlist", list_info(&self.command_allowlist)),
+            ("command_denylist", list_info(&self.command_denylist)),
+            ("tools", self.tools_info()),
+            ("extra_headers", self.extra_headers_info()),
+            ("timeout", option_info(&self.timeout)),
+            (
+                "stream_first_token_timeout",
+                option_info(&self.stream_first_token_timeout),
+            ),
+            ("fetch_max_bytes", option_info(&self.fetch_max_bytes)),
             ("proxy", proxy),
+            (
+                "ca_cert",
+                option_info(&self.ca_cert.as_ref().map(|v| v.display())),
+            ),
+            (
+                "client_cert",
+                option_info(&self.client_cert.as_ref().map(|v| v.display())),
+            ),
+            (
+                "insecure_skip_verify",
+                self.insecure_skip_verify.to_string(),
+            ),
+            ("context_strategy", self.context_strategy.to_string()),
+            ("default_role", option_info(&self.default_role)),
             ("conversation_first", self.conversation_first.to_string()),
+            (
+                "resume_last_conversation",
+                self.resume_last_conversation.to_string(),
+            ),
             ("dry_run", self.dry_run.to_string()),
+            ("log_level", option_info(&self.log_level)),
+            ("usage", self.usage.summary()),
         ];
         let mut output = String::new();
         for (name, value) in items {
-            output.push_str(&format!("{name:<20}{value}\n"));
+            output.push_str(&format!("{name:<28}{value}\n"));
         }
         Ok(output)
     }
 
+    /// Keys `.set` can change, with their current values
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("api_key", self.api_key_info()),
+            ("model", option_info(&self.model)),
+            ("api_base", option_info(&self.api_base)),
+            ("temperature", option_info(&self.temperature)),
+            ("max_tokens", option_info(&self.max_tokens)),
+            ("max_input_tokens", self.get_max_input_tokens().to_string()),
+            ("top_p", option_info(&self.top_p)),
+            ("presence_penalty", option_info(&self.presence_penalty)),
+            ("frequency_penalty", option_info(&self.frequency_penalty)),
+            ("seed", option_info(&self.seed)),
+            ("save", self.save.to_string()),
+            ("save_format", self.save_format.to_string()),
+            ("monthly_budget", option_info(&self.monthly_budget)),
+            ("budget_action", self.budget_action.to_string()),
+            ("cache", self.cache.to_string()),
+            ("stream", self.stream.to_string()),
+            ("speak", self.speak.to_string()),
+            ("voice", option_info(&self.voice)),
+            ("highlight", self.highlight.to_string()),
+            ("theme", option_info(&self.theme)),
+            ("light_theme", option_info(&self.light_theme)),
+            ("tools", self.tools.to_string()),
+            ("context_strategy", self.context_strategy.to_string()),
+            ("proxy", option_info(&self.proxy)),
+            ("dry_run", self.dry_run.to_string()),
+        ]
+    }
+
     pub fn repl_completions(&self) -> Vec<String> {
         let mut completion: Vec<String> = self
             .roles
@@ -277,20 +1290,48 @@ impl Config {
         completion
     }
 
-    pub fn update(&mut self, data: &str) -> Result<()> {
+    pub fn update(&mut self, data: &str) -> Result<String> {
         let parts: Vec<&str> = data.split_whitespace().collect();
-        if parts.len() != 2 {
-            bail!("Usage: .set <key> <value>. If value is null, unset key.");
-        }
-        let key = parts[0];
-        let value = parts[1];
+        let (key, value) = match parts.as_slice() {
+            [] => {
+                let output: Vec<String> = self
+                    .settings()
+                    .into_iter()
+                    .map(|(key, value)| format!("{key:<28}{value}"))
+                    .collect();
+                return Ok(output.join("\n"));
+            }
+            [key] => {
+                return match self.settings().into_iter().find(|(v, _)| v == key) {
+                    Some((_, value)) => Ok(value),
+                    None => bail!("Error: Unknown key `{key}`"),
+                };
+            }
+            [key, value] => (*key, *value),
+            _ => bail!("Usage: .set [<key> [<value>]]. If value is null, unset key."),
+        };
         let unset = value == "null";
         match key {
             "api_key" => {
                 if unset {
                     bail!("Error: Not allowed");
                 } else {
-                    self.api_key = value.to_string();
+                    self.api_key = vec![value.to_string()];
+                    self.api_key_index = 0;
+                }
+            }
+            "model" => {
+                if unset {
+                    self.model = None;
+                } else {
+                    self.model = Some(value.to_string());
+                }
+            }
+            "api_base" => {
+                if unset {
+                    self.api_base = None;
+                } else {
+                    self.api_base = Some(value.to_string());
                 }
             }
             "temperature" => {
@@ -301,14 +1342,118 @@ impl Config {
                     self.temperature = Some(value);
                 }
             }
+            "max_tokens" => {
+                if unset {
+                    self.max_tokens = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.max_tokens = Some(value);
+                }
+            }
+            "max_input_tokens" => {
+                if unset {
+                    self.max_input_tokens = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.max_input_tokens = Some(value);
+                }
+            }
+            "top_p" => {
+                if unset {
+                    self.top_p = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.top_p = Some(value);
+                }
+            }
+            "presence_penalty" => {
+                if unset {
+                    self.presence_penalty = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.presence_penalty = Some(value);
+                }
+            }
+            "frequency_penalty" => {
+                if unset {
+                    self.frequency_penalty = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.frequency_penalty = Some(value);
+                }
+            }
+            "seed" => {
+                if unset {
+                    self.seed = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.seed = Some(value);
+                }
+            }
             "save" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.save = value;
             }
+            "cache" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.cache = value;
+            }
+            "stream" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.stream = value;
+            }
+            "speak" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.speak = value;
+            }
+            "voice" => {
+                if unset {
+                    self.voice = None;
+                } else {
+                    self.voice = Some(value.to_string());
+                }
+            }
             "highlight" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.highlight = value;
             }
+            "theme" => {
+                if unset {
+                    self.theme = None;
+                } else {
+                    Self::theme_file(value)?;
+                    self.theme = Some(value.to_string());
+                }
+            }
+            "light_theme" => {
+                if unset {
+                    self.light_theme = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.light_theme = Some(value);
+                }
+            }
+            "tools" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.tools = value;
+            }
+            "monthly_budget" => {
+                if unset {
+                    self.monthly_budget = None;
+                } else {
+                    let value = value.parse().with_context(|| "Invalid value")?;
+                    self.monthly_budget = Some(value);
+                }
+            }
+            "budget_action" => {
+                self.budget_action = value.parse()?;
+            }
+            "save_format" => {
+                self.save_format = value.parse()?;
+            }
+            "context_strategy" => {
+                self.context_strategy = value.parse()?;
+            }
             "proxy" => {
                 if unset {
                     self.proxy = None;
@@ -322,37 +1467,184 @@ impl Config {
             }
             _ => bail!("Error: Unknown key `{key}`"),
         }
-        Ok(())
+        Ok(String::new())
     }
 
-    pub fn start_conversation(&mut self) -> Result<()> {
-        if let Some(conversation) = self.conversation.as_ref() {
-            if conversation.reamind_tokens() > 0 {
-                let ans = Confirm::new("Already in a conversation, start a new one?")
-                    .with_default(true)
-                    .prompt()?;
-                if !ans {
-                    return Ok(());
+    /// Returns the id of the conversation set aside for the new one
+    pub fn start_conversation(&mut self) -> Option<usize> {
+        self.push_conversation(Conversation::new(self.role.clone()), None)
+    }
+
+    pub fn end_conversation(&mut self) {
+        self.conversation = None;
+        self.session = None;
+    }
+
+    /// Make the conversation current under a new id, setting the current one aside unless it
+    /// is empty. Returns the id of the conversation set aside.
+    fn push_conversation(
+        &mut self,
+        conversation: Conversation,
+        session: Option<String>,
+    ) -> Option<usize> {
+        let parked = self.park_conversation();
+        self.last_conversation_id += 1;
+        self.conversation_id = self.last_conversation_id;
+        self.conversation = Some(conversation);
+        self.session = session;
+        parked
+    }
+
+    fn park_conversation(&mut self) -> Option<usize> {
+        let session = self.session.take();
+        let conversation = self.conversation.take()?;
+        if conversation.messages.is_empty() {
+            return None;
+        }
+        self.other_conversations
+            .insert(self.conversation_id, (conversation, session));
+        Some(self.conversation_id)
+    }
+
+    /// Returns the id of the conversation set aside for this one
+    pub fn switch_conversation(&mut self, id: usize) -> Result<Option<usize>> {
+        if self.conversation.is_some() && id == self.conversation_id {
+            bail!("Error: Already in conversation #{id}");
+        }
+        let (conversation, session) = match self.other_conversations.remove(&id) {
+            Some(v) => v,
+            None => bail!("Error: Unknown conversation #{id}"),
+        };
+        let parked = self.park_conversation();
+        self.role = conversation.role.clone();
+        self.conversation_id = id;
+        self.conversation = Some(conversation);
+        self.session = session;
+        Ok(parked)
+    }
+
+    /// Close the conversation with the id, the current one without
+    pub fn close_conversation(&mut self, id: Option<usize>) -> Result<()> {
+        match id {
+            Some(id) if self.conversation.is_none() || id != self.conversation_id => {
+                if self.other_conversations.remove(&id).is_none() {
+                    bail!("Error: Unknown conversation #{id}");
+                }
+            }
+            _ => {
+                if self.conversation.is_none() {
+                    bail!("Error: Not in a conversation");
                 }
+                self.end_conversation();
             }
         }
-        self.conversation = Some(Conversation::new(self.role.clone()));
         Ok(())
     }
 
-    pub fn end_conversation(&mut self) {
-        self.conversation = None;
+    /// The live conversations of this run, the current one marked with `*`
+    pub fn list_conversations(&self) -> String {
+        let mut conversations: Vec<(usize, &Conversation, bool)> = self
+            .other_conversations
+            .iter()
+            .map(|(id, (conversation, _))| (*id, conversation, false))
+            .collect();
+        if let Some(conversation) = self.conversation.as_ref() {
+            conversations.push((self.conversation_id, conversation, true));
+        }
+        conversations.sort_by_key(|v| v.0);
+        conversations
+            .into_iter()
+            .map(|(id, conversation, current)| {
+                let mark = if current { "*" } else { " " };
+                let title = conversation.title();
+                let title = if title.is_empty() { "-".into() } else { title };
+                format!(
+                    "{mark} #{id:<3} {:>3} messages  {title}",
+                    conversation.messages.len()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn undo_conversation(&mut self) -> Result<()> {
+        match self.conversation.as_mut() {
+            Some(conversation) => conversation.pop_last_exchange(),
+            None => bail!("Error: Not in a conversation"),
+        }
+    }
+
+    /// Config for asking the rest of a reply cut off by the token limit, the exchange is put in
+    /// a conversation unless it is already part of the current one
+    pub fn continue_config(&self, input: &str, reply: &str) -> Result<Config> {
+        let mut config = self.clone();
+        config.model = config.get_model();
+        config.cache = false;
+        config.usage = Usage::default();
+        if config.conversation.is_none() {
+            let mut conversation = Conversation::new(config.role.clone());
+            conversation.add_message(input, reply)?;
+            config.conversation = Some(conversation);
+        }
+        Ok(config)
+    }
+
+    /// Add the rest of a continued reply to the conversation
+    pub fn extend_last_reply(&mut self, text: &str) -> Result<()> {
+        if let Some(conversation) = self.conversation.as_mut() {
+            conversation.extend_last_reply(text)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the last reply stopped at the max tokens limit, `.continue` gets the rest
+    pub fn reply_cut_off(&self) -> bool {
+        matches!(
+            self.finish_reason.as_deref(),
+            Some("length" | "max_tokens" | "MAX_TOKENS")
+        )
+    }
+
+    pub fn record_usage(&mut self, usage: &Usage) {
+        self.usage.add(usage);
+        if let Some(conversation) = self.conversation.as_mut() {
+            conversation.usage.add(usage);
+        }
+    }
+
+    pub fn usage_info(&self) -> Result<String> {
+        let mut output = String::new();
+        if let Some(conversation) = self.conversation.as_ref() {
+            output.push_str(&format!(
+                "{:<20}{}\n",
+                "conversation",
+                conversation.usage.summary()
+            ));
+        }
+        output.push_str(&format!("{:<20}{}\n", "session", self.usage.summary()));
+        output.push_str(&self.spend_info()?);
+        Ok(output)
     }
 
+    /// Also the end of the exchange the attached images were sent with
     pub fn save_conversation(&mut self, input: &str, output: &str) -> Result<()> {
+        let images = std::mem::take(&mut self.images);
         if let Some(conversation) = self.conversation.as_mut() {
             conversation.add_message(input, output)?;
+            let last_input = conversation
+                .messages
+                .iter_mut()
+                .rev()
+                .find(|v| v.role == MessageRole::User);
+            if let Some(message) = last_input {
+                message.images = images;
+            }
         }
         Ok(())
     }
 
     fn open_message_file(&self) -> Result<File> {
-        let path = Config::messages_file()?;
+        let path = self.save_file()?;
         OpenOptions::new()
             .create(true)
             .append(true)
@@ -360,18 +1652,128 @@ impl Config {
             .with_context(|| format!("Failed to create/append {}", path.display()))
     }
 
+    pub fn create_role(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            bail!("Error: Invalid role name `{name}`");
+        }
+        if self.find_role(name).is_some() {
+            bail!("Error: Role `{name}` already exists");
+        }
+        let prompt = Text::new("Prompt:").prompt()?;
+        let temperature = CustomType::<f64>::new("Temperature:")
+            .with_help_message("Leave empty to use the default")
+            .with_error_message("Please type a number between 0 and 2")
+            .prompt_skippable()?;
+        let mut role = Role::new(&prompt, temperature);
+        role.name = name.to_string();
+        // append so that comments in roles.yaml are kept
+        let path = Self::roles_file()?;
+        let content = serialize_roles(&path, &[&role])?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to create/append {}", path.display()))?;
+        file.write_all(format!("\n{content}").as_bytes())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        self.roles.push(role);
+        Ok(())
+    }
+
+    pub fn edit_role(&mut self, name: &str) -> Result<()> {
+        let index = self.role_index(name)?;
+        if let Some(path) = self.roles[index].file.clone() {
+            let content = read_to_string(&path)
+                .with_context(|| format!("Failed to load role at {}", path.display()))?;
+            let content = edit_text(&content)?;
+            std::fs::write(&path, content)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            self.roles[index] = Role::load_file(&path)?;
+            return Ok(());
+        }
+        let content = serde_yaml::to_string(&self.roles[index])
+            .with_context(|| "Failed to serialize role")?;
+        let content = edit_text(&content)?;
+        let role: Role = serde_yaml::from_str(&content).with_context(|| "Invalid role")?;
+        self.roles[index] = role;
+        self.save_roles()
+    }
+
+    pub fn delete_role(&mut self, name: &str) -> Result<()> {
+        let index = self.role_index(name)?;
+        let role = self.roles.remove(index);
+        match role.file {
+            Some(path) => std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display())),
+            None => self.save_roles(),
+        }
+    }
+
+    fn role_index(&self, name: &str) -> Result<usize> {
+        match self.roles.iter().position(|v| v.name == name) {
+            Some(index) if self.roles[index].is_builtin() => {
+                bail!("Error: Builtin role `{name}` cannot be changed")
+            }
+            Some(index) => Ok(index),
+            None => bail!("Error: Unknown role `{name}`"),
+        }
+    }
+
+    fn save_roles(&self) -> Result<()> {
+        let path = Self::roles_file()?;
+        let roles: Vec<&Role> = self
+            .roles
+            .iter()
+            .filter(|v| v.file.is_none() && !v.is_builtin())
+            .collect();
+        let content = serialize_roles(&path, &roles)?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
     fn load_roles(&mut self) -> Result<()> {
         let path = Self::roles_file()?;
-        if !path.exists() {
+        if path.exists() {
+            let content = read_to_string(&path)
+                .with_context(|| format!("Failed to load roles at {}", path.display()))?;
+            self.roles = parse_roles(&path, &content)
+                .with_context(|| format!("Invalid roles config at {}", path.display()))?;
+        }
+        let dir = Self::roles_dir()?;
+        if !dir.exists() {
             return Ok(());
         }
-        let content = read_to_string(&path)
-            .with_context(|| format!("Failed to load roles at {}", path.display()))?;
-        let roles: Vec<Role> =
-            serde_yaml::from_str(&content).with_context(|| "Invalid roles config")?;
-        self.roles = roles;
+        // the subdirs hold the role packs of `role_repos`
+        let mut paths = role_files(&dir)?;
+        let mut subdirs = vec![];
+        for entry in read_dir(&dir).with_context(|| "Failed to read roles dir")? {
+            let path = entry?.path();
+            if path.is_dir() && !is_hidden(&path) {
+                subdirs.push(path);
+            }
+        }
+        subdirs.sort();
+        for subdir in subdirs {
+            paths.extend(role_files(&subdir)?);
+        }
+        for path in paths {
+            let role = Role::load_file(&path)?;
+            // roles.yaml takes precedence over the roles dir
+            if self.find_role(&role.name).is_none() {
+                self.roles.push(role);
+            }
+        }
         Ok(())
     }
+
+    fn load_builtin_roles(&mut self) {
+        let roles: Vec<Role> = serde_yaml::from_str(BUILTIN_ROLES).expect("invalid builtin roles");
+        for role in roles {
+            if self.find_role(&role.name).is_none() {
+                self.roles.push(role);
+            }
+        }
+    }
 }
 
 fn create_config_file(config_path: &Path) -> Result<()> {
@@ -410,6 +1812,391 @@ fn create_config_file(config_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// The `.md` and `.yaml` files of the dir that define roles, sorted by name
+fn role_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    let entries =
+        read_dir(dir).with_context(|| format!("Failed to read roles dir {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let is_role = path.extension().is_some_and(|v| v == "md" || v == "yaml")
+            && !is_hidden(&path)
+            && !path
+                .file_stem()
+                .is_some_and(|v| v.eq_ignore_ascii_case("readme"));
+        if is_role && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|v| v.to_string_lossy().starts_with('.'))
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|v| v == "toml")
+}
+
+/// roles.toml holds the roles as `[[roles]]` tables
+#[derive(Deserialize, Serialize)]
+struct TomlRoles<T> {
+    roles: Vec<T>,
+}
+
+fn parse_roles(path: &Path, content: &str) -> Result<Vec<Role>> {
+    if is_toml(path) {
+        let value: TomlRoles<Role> = toml::from_str(content)?;
+        return Ok(value.roles);
+    }
+    Ok(serde_yaml::from_str(content)?)
+}
+
+fn serialize_roles(path: &Path, roles: &[&Role]) -> Result<String> {
+    let content = if is_toml(path) {
+        toml::to_string(&TomlRoles {
+            roles: roles.to_vec(),
+        })
+        .map_err(anyhow::Error::from)
+    } else {
+        serde_yaml::to_string(roles).map_err(anyhow::Error::from)
+    };
+    content.with_context(|| "Failed to serialize roles")
+}
+
+fn deserialize_api_keys<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ApiKeys {
+        One(String),
+        Many(Vec<String>),
+    }
+    let keys = match Option::<ApiKeys>::deserialize(deserializer)? {
+        Some(ApiKeys::One(key)) => vec![key],
+        Some(ApiKeys::Many(keys)) => keys,
+        None => vec![],
+    };
+    keys.iter()
+        .map(|v| expand_env_vars(v).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn deserialize_env_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) => expand_env_vars(&value)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Replace each `${VAR}` with the value of the environment variable
+fn expand_env_vars(value: &str) -> Result<String, String> {
+    let mut output = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = &rest[start + 2..end];
+        let value =
+            env::var(name).map_err(|_| format!("environment variable `{name}` is not set"))?;
+        output.push_str(&rest[..start]);
+        output.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn deserialize_log_level<'de, D>(deserializer: D) -> Result<Option<LevelFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid log_level `{value}`"))),
+        None => Ok(None),
+    }
+}
+
+/// A reply cut short by Ctrl-C, as saved to `messages.md` and the conversation
+pub fn truncated_reply(output: &str) -> String {
+    format!("{}\n\n{TRUNCATED_MARK}", output.trim_end())
+}
+
+/// Keep the prefix and last 4 chars, e.g. `sk-...abcd`
+pub fn mask_api_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() <= 8 {
+        return "*".repeat(chars.len());
+    }
+    let prefix: String = match key.find('-') {
+        Some(index) if index <= 4 => key[..=index].to_string(),
+        _ => String::new(),
+    };
+    let suffix: String = chars[chars.len() - 4..].iter().collect();
+    format!("{prefix}...{suffix}")
+}
+
+/// Lowercase words of the title joined by `-`, usable as a session name
+fn slugify(title: &str) -> String {
+    title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_lowercase())
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+fn option_info<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(|v| v.to_string()).unwrap_or("-".into())
+}
+
+fn list_info(values: &[String]) -> String {
+    if values.is_empty() {
+        "-".into()
+    } else {
+        values.join(", ")
+    }
+}
+
+fn stream_value() -> bool {
+    true
+}
+
 fn highlight_value() -> bool {
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_env_vars() {
+        env::set_var("AICHAT_TEST_HOST", "localhost");
+        assert_eq!(
+            expand_env_vars("http://${AICHAT_TEST_HOST}:8000/v1").unwrap(),
+            "http://localhost:8000/v1"
+        );
+        assert_eq!(expand_env_vars("sk-${").unwrap(), "sk-${");
+        assert!(expand_env_vars("${AICHAT_TEST_UNSET}").is_err());
+        let config: Config = serde_yaml::from_str("api_key: [a, '${AICHAT_TEST_HOST}']").unwrap();
+        assert_eq!(config.api_key, vec!["a", "localhost"]);
+    }
+
+    #[test]
+    fn test_toml() {
+        let config: Config =
+            toml::from_str("api_key = \"sk-1\"\ntemperature = 0.5\nlog_level = \"debug\"").unwrap();
+        assert_eq!(config.api_key, vec!["sk-1"]);
+        assert_eq!(config.temperature, Some(0.5));
+        assert_eq!(config.log_level, Some(LevelFilter::Debug));
+        let path = Path::new("roles.toml");
+        let roles = parse_roles(path, "[[roles]]\nname = \"shell\"\nprompt = \"mine\"").unwrap();
+        assert_eq!(roles[0].name, "shell");
+        let content = serialize_roles(path, &[&roles[0]]).unwrap();
+        assert_eq!(content, "[[roles]]\nname = \"shell\"\nprompt = \"mine\"\n");
+    }
+
+    #[test]
+    fn test_builtin_roles() {
+        let mut config: Config = serde_yaml::from_str("{}").unwrap();
+        config.roles = serde_yaml::from_str("[{name: shell, prompt: mine}]").unwrap();
+        config.load_builtin_roles();
+        assert_eq!(config.find_role("shell").unwrap().prompt, "mine");
+        assert_eq!(config.find_role("code").unwrap().name, "%code%");
+        assert!(config.find_role("%explain%").unwrap().is_builtin());
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut config: Config = serde_yaml::from_str("api_key: [sk-1, sk-2]").unwrap();
+        config.apply_overrides(Overrides {
+            model: Some("gpt-4o".into()),
+            dry_run: true,
+            ..Default::default()
+        });
+        config.api_key_index = 1;
+        let fresh: Config =
+            serde_yaml::from_str("{api_key: [sk-1, sk-2], model: gpt-3.5-turbo}").unwrap();
+        config.reload(fresh);
+        assert_eq!(config.model.as_deref(), Some("gpt-4o"));
+        assert!(config.dry_run);
+        assert_eq!(config.api_key_index, 1);
+        let fresh: Config = serde_yaml::from_str("api_key: sk-3").unwrap();
+        config.reload(fresh);
+        assert_eq!(config.api_key_index, 0);
+        assert!(config.dry_run);
+    }
+
+    #[test]
+    fn test_preview_prompt() {
+        let mut config: Config = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(config.preview_prompt(), None);
+        config.create_temp_role("You are a pirate").unwrap();
+        assert_eq!(
+            config.preview_prompt().unwrap(),
+            "system: You are a pirate\nuser: <your message>"
+        );
+        config
+            .create_temp_role("Fix the grammar: __INPUT__")
+            .unwrap();
+        assert_eq!(
+            config.preview_prompt().unwrap(),
+            "user: Fix the grammar: <your message>"
+        );
+    }
+
+    #[test]
+    fn test_inline_role() {
+        let mut config: Config = serde_yaml::from_str("{}").unwrap();
+        config.roles =
+            serde_yaml::from_str("[{name: translator, prompt: 'Translate to {{lang}}'}]").unwrap();
+        config.load_builtin_roles();
+        assert_eq!(
+            config.split_inline_role("%shell% list listening ports"),
+            Some(("shell", "list listening ports"))
+        );
+        assert_eq!(config.split_inline_role("%nope% 5% more"), None);
+        assert_eq!(config.split_inline_role("50% done"), None);
+        let (role, text) = config
+            .inline_role("%translator lang=fr% hello")
+            .unwrap()
+            .unwrap();
+        assert_eq!(role.variables["lang"], "fr");
+        assert_eq!(text, "hello");
+    }
+
+    #[test]
+    fn test_conversations() {
+        let mut config: Config = serde_yaml::from_str("{}").unwrap();
+        let add = |config: &mut Config, input: &str| {
+            let conversation = config.conversation.as_mut().unwrap();
+            conversation.add_message(input, "reply").unwrap();
+        };
+        assert_eq!(config.start_conversation(), None);
+        add(&mut config, "first topic");
+        assert_eq!(config.start_conversation(), Some(1));
+        add(&mut config, "second topic\nmore");
+        assert_eq!(
+            config.list_conversations(),
+            "  #1     2 messages  first topic\n* #2     2 messages  second topic"
+        );
+        assert_eq!(config.switch_conversation(1).unwrap(), Some(2));
+        assert!(config.switch_conversation(1).is_err());
+        assert!(config.switch_conversation(3).is_err());
+        config.close_conversation(Some(2)).unwrap();
+        assert!(config.other_conversations.is_empty());
+        config.close_conversation(None).unwrap();
+        assert!(config.conversation.is_none());
+        assert!(config.close_conversation(None).is_err());
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut config: Config = serde_yaml::from_str("temperature: 0.7").unwrap();
+        assert_eq!(config.update("temperature").unwrap(), "0.7");
+        assert_eq!(config.update("seed").unwrap(), "-");
+        assert!(config
+            .update("")
+            .unwrap()
+            .contains("\nstream                      true\n"));
+        assert!(config.update("nope").is_err());
+        // every listed key can be set
+        for (key, value) in config.settings() {
+            if let Err(err) = config.update(&format!("{key} {value}")) {
+                assert!(!err.to_string().contains("Unknown key"), "{key}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_api_key() {
+        let config: Config = serde_yaml::from_str("api_key: sk-1").unwrap();
+        assert_eq!(config.api_key, vec!["sk-1"]);
+        let mut config: Config = serde_yaml::from_str("api_key: [sk-1, sk-2]").unwrap();
+        assert_eq!(config.get_api_key().unwrap(), "sk-1");
+        assert!(config.rotate_api_key());
+        assert_eq!(config.get_api_key().unwrap(), "sk-2");
+        assert!(!config.rotate_api_key());
+    }
+
+    #[test]
+    fn test_set_api_messages() {
+        let mut config: Config = serde_yaml::from_str("{}").unwrap();
+        config.role = Some(Role::new("You are a calculator", None));
+        let message = |role, content: &str| Message {
+            role,
+            content: content.into(),
+            images: vec![],
+        };
+        let input = config
+            .set_api_messages(vec![
+                message(MessageRole::User, "1 + 1 = ?"),
+                message(MessageRole::Assistant, "2"),
+                message(MessageRole::User, "2 + 2 = ?"),
+            ])
+            .unwrap();
+        assert_eq!(input, "2 + 2 = ?");
+        let messages = config.build_messages(&input).unwrap();
+        let contents: Vec<&str> = messages.iter().map(|v| v.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec!["You are a calculator", "1 + 1 = ?", "2", "2 + 2 = ?"]
+        );
+        assert!(config
+            .set_api_messages(vec![message(MessageRole::Assistant, "2")])
+            .is_err());
+    }
+
+    #[test]
+    fn test_reply_cut_off() {
+        let mut config: Config = serde_yaml::from_str("{}").unwrap();
+        assert!(!config.reply_cut_off());
+        config.finish_reason = Some("stop".into());
+        assert!(!config.reply_cut_off());
+        for reason in ["length", "max_tokens", "MAX_TOKENS"] {
+            config.finish_reason = Some(reason.into());
+            assert!(config.reply_cut_off());
+        }
+    }
+
+    #[test]
+    fn test_truncated_reply() {
+        assert_eq!(
+            truncated_reply("The answer is\n"),
+            "The answer is\n\n(truncated, aborted by the user)"
+        );
+    }
+
+    #[test]
+    fn test_mask_api_key() {
+        assert_eq!(mask_api_key("sk-0123456789abcdef"), "sk-...cdef");
+        assert_eq!(mask_api_key("0123456789abcdef"), "...cdef");
+        assert_eq!(mask_api_key("short"), "*****");
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(
+            slugify("Sorting Vectors in Rust"),
+            "sorting-vectors-in-rust"
+        );
+        assert_eq!(slugify("C++ / Go: a comparison"), "c-go-a-comparison");
+        assert_eq!(slugify("?!"), "");
+    }
+}
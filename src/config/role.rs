@@ -1,9 +1,19 @@
 use super::message::{Message, MessageRole};
 
+use crate::utils::{detect_os, detect_shell};
+
+use anyhow::{anyhow, bail, Context, Result};
+use inquire::Text;
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::collections::{btree_map::Entry, BTreeMap};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
 
 const TEMP_NAME: &str = "Ｐ";
 const INPUT_PLACEHOLDER: &str = "__INPUT__";
+const OS_PLACEHOLDER: &str = "__OS__";
+const SHELL_PLACEHOLDER: &str = "__SHELL__";
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Role {
@@ -13,9 +23,38 @@ pub struct Role {
     ///
     /// If prmopt contains __INPUT___, it's embeded prompt
     /// If prmopt don't contain __INPUT___, it's system prompt
+    #[serde(default)]
     pub prompt: String,
+    /// Example messages sent before the input, e.g. alternating user/assistant for few-shot
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<Message>,
     /// What sampling temperature to use, between 0 and 2
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f64>,
+    /// Override the model while the role is active
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// The maximum number of tokens to generate in the reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    /// Nucleus sampling, between 0 and 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    /// Penalize tokens that already appeared, between -2 and 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    /// Penalize tokens by how often they appeared, between -2 and 2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    /// Sequences where the model will stop generating further tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    /// Values of the `{{name}}` variables in the prompt
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+    /// File in the roles dir this role comes from, roles.yaml if absent
+    #[serde(skip)]
+    pub file: Option<PathBuf>,
 }
 
 impl Role {
@@ -23,44 +62,144 @@ impl Role {
         Self {
             name: TEMP_NAME.into(),
             prompt: prompt.into(),
+            messages: vec![],
             temperature,
+            model: None,
+            max_tokens: None,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            stop: None,
+            variables: BTreeMap::new(),
+            file: None,
         }
     }
 
-    pub fn is_temp(&self) -> bool {
-        self.name == TEMP_NAME
+    /// The file name is the role name, a `.md` file holds the prompt and a `.yaml` file the other fields
+    pub fn load_file(path: &Path) -> Result<Self> {
+        let name = path
+            .file_stem()
+            .and_then(|v| v.to_str())
+            .ok_or_else(|| anyhow!("Invalid role file {}", path.display()))?;
+        let content = read_to_string(path)
+            .with_context(|| format!("Failed to load role at {}", path.display()))?;
+        let mut role = if path.extension().is_some_and(|v| v == "md") {
+            let mut role = Role::new(content.trim(), None);
+            role.name = name.to_string();
+            role
+        } else {
+            let mut value: Value = serde_yaml::from_str(&content)
+                .with_context(|| format!("Invalid role at {}", path.display()))?;
+            if let Value::Mapping(map) = &mut value {
+                map.insert("name".into(), name.into());
+            }
+            serde_yaml::from_value(value)
+                .with_context(|| format!("Invalid role at {}", path.display()))?
+        };
+        role.file = Some(path.to_path_buf());
+        Ok(role)
     }
 
-    pub fn embeded(&self) -> bool {
-        self.prompt.contains(INPUT_PLACEHOLDER)
+    /// Fill the prompt variables from `key=value` args, asking for the missing ones
+    pub fn set_variables(&mut self, args: &str) -> Result<()> {
+        let names = self.variable_names();
+        for arg in args.split_whitespace() {
+            match arg.split_once('=') {
+                Some((key, value)) if names.iter().any(|v| v == key) => {
+                    self.variables.insert(key.to_string(), value.to_string());
+                }
+                _ => bail!("Error: Unknown role variable `{arg}`"),
+            }
+        }
+        for name in names {
+            if let Entry::Vacant(entry) = self.variables.entry(name) {
+                let value = Text::new(&format!("{}:", entry.key())).prompt()?;
+                entry.insert(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of the `{{name}}` variables in the prompt, in order of appearance
+    pub fn variable_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        let mut rest = self.prompt.as_str();
+        while let Some((name, remain)) = next_variable(rest) {
+            if !names.iter().any(|v| v == name) {
+                names.push(name.to_string());
+            }
+            rest = remain;
+        }
+        names
     }
 
-    pub fn echo_messages(&self, content: &str) -> String {
+    /// Messages the role puts ahead of the conversation
+    pub fn prefix_len(&self) -> usize {
         if self.embeded() {
-            merge_prompt_content(&self.prompt, content)
+            0
         } else {
-            format!("{}\n{content}", self.prompt)
+            self.build_emssages("").len() - 1
+        }
+    }
+
+    fn render_prompt(&self) -> String {
+        self.render(&self.prompt)
+    }
+
+    fn render(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        if text.contains(OS_PLACEHOLDER) {
+            text = text.replace(OS_PLACEHOLDER, detect_os());
+        }
+        if text.contains(SHELL_PLACEHOLDER) {
+            text = text.replace(SHELL_PLACEHOLDER, &detect_shell().name);
+        }
+        for (name, value) in &self.variables {
+            text = text.replace(&format!("{{{{{name}}}}}"), value);
         }
+        text
+    }
+
+    pub fn is_temp(&self) -> bool {
+        self.name == TEMP_NAME
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        self.name.len() > 2 && self.name.starts_with('%') && self.name.ends_with('%')
+    }
+
+    pub fn embeded(&self) -> bool {
+        self.prompt.contains(INPUT_PLACEHOLDER)
     }
 
     pub fn build_emssages(&self, content: &str) -> Vec<Message> {
         if self.embeded() {
-            let content = merge_prompt_content(&self.prompt, content);
+            let content = merge_prompt_content(&self.render_prompt(), content);
             vec![Message {
                 role: MessageRole::User,
                 content,
+                images: vec![],
             }]
         } else {
-            vec![
-                Message {
+            let mut messages = vec![];
+            if !self.prompt.is_empty() || self.messages.is_empty() {
+                messages.push(Message {
                     role: MessageRole::System,
-                    content: self.prompt.clone(),
-                },
-                Message {
-                    role: MessageRole::User,
-                    content: content.to_string(),
-                },
-            ]
+                    content: self.render_prompt(),
+                    images: vec![],
+                });
+            }
+            messages.extend(self.messages.iter().map(|v| Message {
+                role: v.role,
+                content: self.render(&v.content),
+                images: vec![],
+            }));
+            messages.push(Message {
+                role: MessageRole::User,
+                content: content.to_string(),
+                images: vec![],
+            });
+            messages
         }
     }
 }
@@ -68,3 +207,54 @@ impl Role {
 pub fn merge_prompt_content(prompt: &str, content: &str) -> String {
     prompt.replace(INPUT_PLACEHOLDER, content)
 }
+
+/// Find the next `{{name}}`, returning the name and the text after it
+fn next_variable(text: &str) -> Option<(&str, &str)> {
+    let mut text = text;
+    loop {
+        let start = text.find("{{")? + 2;
+        let end = start + text[start..].find("}}")?;
+        let name = &text[start..end];
+        if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Some((name, &text[end + 2..]));
+        }
+        text = &text[start..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variables() {
+        let mut role = Role::new("Translate {{lang}} to {{ lang }} or {{lang}}, {{}}", None);
+        assert_eq!(role.variable_names(), vec!["lang"]);
+        role.set_variables("lang=fr").unwrap();
+        assert_eq!(
+            role.build_emssages("hi")[0].content,
+            "Translate fr to {{ lang }} or fr, {{}}"
+        );
+        assert!(role.set_variables("tone=formal").is_err());
+    }
+
+    #[test]
+    fn test_few_shot_messages() {
+        let role: Role = serde_yaml::from_str(
+            r#"
+name: emoji
+messages:
+  - role: user
+    content: happy
+  - role: assistant
+    content: 😀
+"#,
+        )
+        .unwrap();
+        let messages = role.build_emssages("sad");
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1].content, "😀");
+        assert_eq!(messages[2].content, "sad");
+        assert_eq!(role.prefix_len(), 2);
+    }
+}
@@ -0,0 +1,131 @@
+use super::{parse_roles, role_files, Config, SharedConfig};
+
+use crate::client::{build_plain_http_client, init_runtime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::{create_dir_all, remove_file};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Fetch a role pack of `role_repos` into `<roles_dir>/<name>`, returns the number of roles.
+/// A git url is cloned, then pulled; an https url serves the roles in the format of `roles.yaml`.
+pub fn sync_role_repo(config: &SharedConfig, url: &str) -> Result<usize> {
+    let name = repo_name(url).ok_or_else(|| anyhow!("Invalid role repo `{url}`"))?;
+    let roles_dir = Config::roles_dir()?;
+    create_dir_all(&roles_dir)
+        .with_context(|| format!("Failed to create {}", roles_dir.display()))?;
+    let dir = roles_dir.join(name);
+    if is_git_url(url) {
+        sync_git(url, &dir)?;
+    } else {
+        download_roles(config, url, &dir)?;
+    }
+    Ok(role_files(&dir)?.len())
+}
+
+fn sync_git(url: &str, dir: &Path) -> Result<()> {
+    let mut command = Command::new("git");
+    if dir.join(".git").exists() {
+        command
+            .arg("-C")
+            .arg(dir)
+            .args(["pull", "--ff-only", "--quiet"]);
+    } else {
+        command
+            .args(["clone", "--depth", "1", "--quiet", url])
+            .arg(dir);
+    }
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| "Failed to run git, is it installed?")?;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        bail!("git failed, {}", err.trim());
+    }
+    Ok(())
+}
+
+fn download_roles(config: &SharedConfig, url: &str, dir: &Path) -> Result<()> {
+    let client = build_plain_http_client(config)?;
+    let content = init_runtime()?
+        .block_on(async {
+            let res = client.get(url).send().await?;
+            let status = res.status();
+            if !status.is_success() {
+                bail!("Request failed with {status}");
+            }
+            Ok(res.text().await?)
+        })
+        .with_context(|| format!("Failed to fetch {url}"))?;
+    let roles =
+        parse_roles(Path::new(url), &content).with_context(|| format!("Invalid roles at {url}"))?;
+    if let Some(role) = roles
+        .iter()
+        .find(|v| v.name.is_empty() || v.name.starts_with('.') || v.name.contains(['/', '\\']))
+    {
+        bail!("Invalid role name `{}` at {url}", role.name);
+    }
+    create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    // roles dropped from the pack go away
+    for path in role_files(dir)? {
+        remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+    for role in &roles {
+        let path = dir.join(format!("{}.yaml", role.name));
+        let content = serde_yaml::to_string(role).with_context(|| "Failed to serialize role")?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn is_git_url(url: &str) -> bool {
+    url.ends_with(".git")
+        || url.starts_with("git@")
+        || url.starts_with("git://")
+        || url.starts_with("ssh://")
+}
+
+/// Dir of the pack in the roles dir, the last segment of the url without extension
+fn repo_name(url: &str) -> Option<&str> {
+    let url = url.trim_end_matches('/');
+    let (url, is_git) = match url.strip_suffix(".git") {
+        Some(url) => (url.trim_end_matches('/'), true),
+        None => (url, false),
+    };
+    let segment = url.rsplit(['/', ':']).next()?;
+    let name = match segment.rsplit_once('.') {
+        Some((stem, _)) if !is_git => stem,
+        _ => segment,
+    };
+    if name.is_empty() || name.starts_with('.') {
+        return None;
+    }
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repo_name() {
+        assert_eq!(
+            repo_name("https://github.com/acme/prompts.git"),
+            Some("prompts")
+        );
+        assert_eq!(
+            repo_name("git@github.com:acme/prompts.git"),
+            Some("prompts")
+        );
+        assert_eq!(
+            repo_name("https://example.com/team/writing.yaml"),
+            Some("writing")
+        );
+        assert_eq!(repo_name("https://example.com/roles/"), Some("roles"));
+        assert_eq!(repo_name("https://"), None);
+        assert!(is_git_url("git@github.com:acme/prompts.git"));
+        assert!(!is_git_url("https://example.com/team/writing.yaml"));
+    }
+}
@@ -0,0 +1,172 @@
+use super::Config;
+
+use anyhow::{bail, Context, Result};
+use chrono::{Duration, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::read_to_string;
+use std::str::FromStr;
+
+const SPEND_FILE_NAME: &str = "spend.yaml";
+/// Days kept in the spend file, enough for this month and the last
+const KEEP_DAYS: i64 = 62;
+
+/// What to do once `monthly_budget` is spent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetAction {
+    /// Print a warning and send anyway
+    Warn,
+    /// Refuse to send
+    #[default]
+    Refuse,
+}
+
+impl fmt::Display for BudgetAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            BudgetAction::Warn => "warn",
+            BudgetAction::Refuse => "refuse",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for BudgetAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(BudgetAction::Warn),
+            "refuse" => Ok(BudgetAction::Refuse),
+            _ => bail!("Invalid value, expect warn or refuse"),
+        }
+    }
+}
+
+/// Estimated cost in USD of each day, stored at `<data_dir>/spend.yaml`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Spend {
+    /// By date, e.g. `2024-04-01`
+    days: BTreeMap<String, f64>,
+}
+
+impl Spend {
+    fn load() -> Result<Self> {
+        let path = Config::data_file(SPEND_FILE_NAME)?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load spend at {}", path.display()))?;
+        serde_yaml::from_str(&content).with_context(|| "Invalid spend file")
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Config::data_file(SPEND_FILE_NAME)?;
+        let content = serde_yaml::to_string(self).with_context(|| "Failed to serialize spend")?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write spend to {}", path.display()))
+    }
+
+    /// Add to the day and forget the days past `KEEP_DAYS`
+    fn add(&mut self, day: NaiveDate, cost: f64) {
+        *self.days.entry(date_key(day)).or_default() += cost;
+        let oldest = date_key(day - Duration::days(KEEP_DAYS));
+        self.days.retain(|v, _| *v > oldest);
+    }
+
+    fn day_total(&self, day: NaiveDate) -> f64 {
+        self.days.get(&date_key(day)).copied().unwrap_or_default()
+    }
+
+    fn month_total(&self, day: NaiveDate) -> f64 {
+        let month = day.format("%Y-%m-").to_string();
+        self.days
+            .iter()
+            .filter(|(v, _)| v.starts_with(&month))
+            .map(|(_, v)| v)
+            .sum()
+    }
+}
+
+fn date_key(day: NaiveDate) -> String {
+    day.format("%Y-%m-%d").to_string()
+}
+
+impl Config {
+    pub fn record_spend(&self, cost: f64) -> Result<()> {
+        if cost <= 0.0 {
+            return Ok(());
+        }
+        let mut spend = Spend::load()?;
+        spend.add(Local::now().date_naive(), cost);
+        spend.save()
+    }
+
+    /// Refuse to send, or warn once, when the spend of this month reached `monthly_budget`
+    pub fn check_budget(&mut self) -> Result<()> {
+        let budget = match self.monthly_budget {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let spent = Spend::load()?.month_total(Local::now().date_naive());
+        if spent < budget {
+            return Ok(());
+        }
+        let message = format!("Spent ${spent:.2} of the monthly budget of ${budget:.2}");
+        match self.budget_action {
+            BudgetAction::Refuse => {
+                bail!("Error: {message}, raise `monthly_budget` to keep going")
+            }
+            BudgetAction::Warn => {
+                if !self.budget_warned {
+                    self.budget_warned = true;
+                    eprintln!("Warning: {message}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Spend of today and this month, with what is left of `monthly_budget`
+    pub fn spend_info(&self) -> Result<String> {
+        let spend = Spend::load()?;
+        let today = Local::now().date_naive();
+        let month = spend.month_total(today);
+        let month = match self.monthly_budget {
+            Some(budget) => format!(
+                "${month:.4} of ${budget:.2}, ${:.4} left",
+                (budget - month).max(0.0)
+            ),
+            None => format!("${month:.4}"),
+        };
+        Ok(format!(
+            "{:<20}${:.4}\n{:<20}{month}\n",
+            "today",
+            spend.day_total(today),
+            "this month"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spend() {
+        let day = |v: &str| NaiveDate::parse_from_str(v, "%Y-%m-%d").unwrap();
+        let mut spend = Spend::default();
+        spend.add(day("2024-03-31"), 1.0);
+        spend.add(day("2024-04-01"), 0.5);
+        spend.add(day("2024-04-01"), 0.25);
+        assert_eq!(spend.day_total(day("2024-04-01")), 0.75);
+        assert_eq!(spend.month_total(day("2024-04-20")), 0.75);
+        assert_eq!(spend.month_total(day("2024-03-01")), 1.0);
+        spend.add(day("2024-06-01"), 2.0);
+        assert_eq!(spend.days.len(), 2);
+        assert_eq!(spend.day_total(day("2024-03-31")), 0.0);
+    }
+}
@@ -0,0 +1,141 @@
+use super::mcp::McpTool;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A function the model may call, declared by `<config_dir>/tools/<name>.json`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tool {
+    #[serde(skip)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// JSON schema of the arguments
+    #[serde(default = "empty_parameters")]
+    pub parameters: Value,
+    /// Executable run with the arguments json, `<config_dir>/tools/<name>` if absent
+    pub command: Option<PathBuf>,
+    /// Set for tools offered by an MCP server
+    #[serde(skip)]
+    pub server: Option<McpTool>,
+}
+
+impl Tool {
+    pub fn load_dir(dir: &Path) -> Result<Vec<Self>> {
+        let mut tools = vec![];
+        if !dir.exists() {
+            return Ok(tools);
+        }
+        for entry in read_dir(dir).with_context(|| "Failed to read tools dir")? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|v| v == "json") {
+                tools.push(Self::load_file(&path)?);
+            }
+        }
+        tools.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(tools)
+    }
+
+    fn load_file(path: &Path) -> Result<Self> {
+        let name = path
+            .file_stem()
+            .and_then(|v| v.to_str())
+            .ok_or_else(|| anyhow!("Invalid tool file {}", path.display()))?;
+        let content = read_to_string(path)
+            .with_context(|| format!("Failed to load tool at {}", path.display()))?;
+        let mut tool: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid tool at {}", path.display()))?;
+        tool.name = name.to_string();
+        let command = match tool.command.take() {
+            Some(command) if command.is_relative() => path.with_file_name(command),
+            Some(command) => command,
+            None => path.with_extension(""),
+        };
+        tool.command = Some(command);
+        Ok(tool)
+    }
+
+    /// Declaration sent along with the request
+    pub fn to_json(&self) -> Value {
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "description": self.description,
+                "parameters": self.parameters,
+            }
+        })
+    }
+
+    /// Run the command with the arguments json, failures are reported back to the model
+    pub fn run(&self, arguments: &str) -> String {
+        if let Some(server) = self.server.as_ref() {
+            return server
+                .run(arguments)
+                .unwrap_or_else(|err| format!("Error: {err}"));
+        }
+        let command = self.command.clone().unwrap_or_default();
+        let output = match Command::new(&command).arg(arguments).output() {
+            Ok(output) => output,
+            Err(err) => return format!("Error: Failed to run {}, {err}", command.display()),
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if output.status.success() {
+            stdout.trim_end().to_string()
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            format!("Error: {} {}", output.status, stderr.trim_end())
+        }
+    }
+}
+
+/// A call requested by the model, streamed calls are accumulated into it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+impl ToolCall {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "arguments": self.arguments,
+            }
+        })
+    }
+}
+
+fn empty_parameters() -> Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_file() {
+        let dir = std::env::temp_dir().join(format!("aichat-tools-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("get_weather.json");
+        std::fs::write(
+            &path,
+            r#"{"description": "Get the weather", "command": "weather.sh"}"#,
+        )
+        .unwrap();
+        let tool = Tool::load_file(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(tool.name, "get_weather");
+        assert_eq!(tool.command, Some(dir.join("weather.sh")));
+        assert_eq!(tool.to_json()["function"]["parameters"]["type"], "object");
+    }
+}
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+/// USD per 1M prompt/completion tokens, matched by model name prefix
+const PRICES: [(&str, f64, f64); 10] = [
+    ("gpt-3.5-turbo", 0.5, 1.5),
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4o", 5.0, 15.0),
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-4-32k", 60.0, 120.0),
+    ("gpt-4", 30.0, 60.0),
+    ("claude-3-haiku", 0.25, 1.25),
+    ("claude-3-sonnet", 3.0, 15.0),
+    ("claude-3-opus", 15.0, 75.0),
+    ("gemini-1.5-flash", 0.35, 1.05),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Estimated cost in USD
+    #[serde(default)]
+    pub cost: f64,
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            cost: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost += other.cost;
+    }
+
+    pub fn estimate_cost(&mut self, model: &str) {
+        if let Some((_, prompt_price, completion_price)) =
+            PRICES.iter().find(|(name, _, _)| model.starts_with(name))
+        {
+            self.cost = (self.prompt_tokens as f64 * prompt_price
+                + self.completion_tokens as f64 * completion_price)
+                / 1_000_000.0;
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} prompt + {} completion tokens, ${:.4}",
+            self.prompt_tokens, self.completion_tokens, self.cost
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_cost() {
+        let mut usage = Usage::new(1_000_000, 1_000_000);
+        usage.estimate_cost("gpt-4-0613");
+        assert_eq!(usage.cost, 90.0);
+        let mut usage = Usage::new(1000, 1000);
+        usage.estimate_cost("unknown-model");
+        assert_eq!(usage.cost, 0.0);
+    }
+}
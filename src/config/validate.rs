@@ -0,0 +1,96 @@
+use super::Config;
+
+use reqwest::Url;
+
+impl Config {
+    /// Values out of range, malformed urls and missing files, one message each
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut check_range = |name: &str, value: Option<f64>, min: f64, max: f64| {
+            if let Some(value) = value.filter(|v| !(min..=max).contains(v)) {
+                problems.push(format!(
+                    "{name}: {value} is out of range, expect {min} to {max}"
+                ));
+            }
+        };
+        check_range("temperature", self.temperature, 0.0, 2.0);
+        check_range("top_p", self.top_p, 0.0, 1.0);
+        check_range("presence_penalty", self.presence_penalty, -2.0, 2.0);
+        check_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0);
+        for role in &self.roles {
+            let name = format!("role `{}`: temperature", role.name);
+            check_range(&name, role.temperature, 0.0, 2.0);
+        }
+        if let Some(name) = self
+            .default_role
+            .as_deref()
+            .and_then(|v| v.split_whitespace().next())
+        {
+            if self.find_role(name).is_none() {
+                problems.push(format!("default_role: unknown role `{name}`"));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            problems.push("max_tokens: expect at least 1".into());
+        }
+        if let Some(budget) = self.monthly_budget.filter(|v| *v < 0.0) {
+            problems.push(format!("monthly_budget: {budget} is negative"));
+        }
+        if let Some(proxy) = &self.proxy {
+            if !is_url(proxy, &["http", "https", "socks5", "socks5h"]) {
+                problems.push(format!(
+                    "proxy: `{proxy}` is not a url like http://127.0.0.1:8080 or socks5://127.0.0.1:1080"
+                ));
+            }
+        }
+        if let Some(api_base) = &self.api_base {
+            if !is_url(api_base, &["http", "https"]) {
+                problems.push(format!(
+                    "api_base: `{api_base}` is not a url like http://localhost:8000/v1"
+                ));
+            }
+        }
+        for (name, path) in [
+            ("ca_cert", &self.ca_cert),
+            ("client_cert", &self.client_cert),
+            ("client_key", &self.client_key),
+        ] {
+            if let Some(path) = path.as_ref().filter(|v| !v.exists()) {
+                problems.push(format!("{name}: {} does not exist", path.display()));
+            }
+        }
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            problems.push("client_cert and client_key must be set together".into());
+        }
+        problems
+    }
+}
+
+fn is_url(value: &str, schemes: &[&str]) -> bool {
+    Url::parse(value).is_ok_and(|v| schemes.contains(&v.scheme()) && v.has_host())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let config: Config = serde_yaml::from_str(
+            "{temperature: 3, top_p: 0.5, default_role: 'coder lang=rust', proxy: '127.0.0.1:1080', api_base: 'http://localhost:8000/v1', client_key: /nonexistent.key}",
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate(),
+            vec![
+                "temperature: 3 is out of range, expect 0 to 2",
+                "default_role: unknown role `coder`",
+                "proxy: `127.0.0.1:1080` is not a url like http://127.0.0.1:8080 or socks5://127.0.0.1:1080",
+                "client_key: /nonexistent.key does not exist",
+                "client_cert and client_key must be set together",
+            ]
+        );
+        let config: Config = serde_yaml::from_str("{proxy: 'socks5://127.0.0.1:1080'}").unwrap();
+        assert!(config.validate().is_empty());
+    }
+}
@@ -0,0 +1,39 @@
+use super::{Config, SharedConfig};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// Reload the config when `config.yaml` or `roles.yaml` changes, for as long as the watcher lives
+pub fn watch_config(config: SharedConfig) -> Result<RecommendedWatcher> {
+    let names = [Config::config_file()?, Config::roles_file()?]
+        .map(|v| v.file_name().map(|v| v.to_os_string()));
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => return warn!("Failed to watch the config, {err}"),
+        };
+        // editors may remove the file before writing it again
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            return;
+        }
+        let changed = event
+            .paths
+            .iter()
+            .any(|path| names.contains(&path.file_name().map(|v| v.to_os_string())));
+        if changed {
+            match Config::load_again() {
+                Ok(fresh) => {
+                    config.lock().reload(fresh);
+                    info!("Reloaded the config");
+                }
+                Err(err) => warn!("Failed to reload the config, {err:#}"),
+            }
+        }
+    })
+    .with_context(|| "Failed to watch the config")?;
+    watcher
+        .watch(&Config::config_dir()?, RecursiveMode::NonRecursive)
+        .with_context(|| "Failed to watch the config dir")?;
+    Ok(watcher)
+}
@@ -0,0 +1,202 @@
+use crate::config::{Config, Conversation, Message, MessageRole};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_to_string, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+#[derive(Debug, Deserialize)]
+struct ChatGptConversation {
+    title: Option<String>,
+    update_time: Option<f64>,
+    /// Last node of the branch shown in ChatGPT, earlier nodes are reached through `parent`
+    current_node: Option<String>,
+    #[serde(default)]
+    mapping: HashMap<String, ChatGptNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptNode {
+    message: Option<ChatGptMessage>,
+    parent: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptMessage {
+    author: ChatGptAuthor,
+    content: ChatGptContent,
+    #[serde(default)]
+    metadata: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptAuthor {
+    role: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptContent {
+    content_type: String,
+    #[serde(default)]
+    parts: Vec<Value>,
+}
+
+/// Save the conversations of a ChatGPT data export, the zip or its `conversations.json`,
+/// as sessions. Returns the new session files and the number of conversations skipped
+/// because they were imported before.
+pub fn import_chatgpt(config: &Config, path: &Path) -> Result<(Vec<PathBuf>, usize)> {
+    let content = read_export(path)?;
+    let conversations: Vec<ChatGptConversation> = serde_json::from_str(&content)
+        .with_context(|| format!("Invalid ChatGPT export {}", path.display()))?;
+    let mut existing = HashSet::new();
+    for name in config.list_sessions()? {
+        if let Ok(content) = read_to_string(Config::session_file(&name)?) {
+            existing.insert(content);
+        }
+    }
+    let mut paths = vec![];
+    let mut skipped = 0;
+    for value in &conversations {
+        let messages = chatgpt_messages(value);
+        if messages.is_empty() {
+            continue;
+        }
+        let title = value.title.clone().filter(|v| !v.trim().is_empty());
+        let conversation = Conversation::from_messages(messages, title.clone());
+        let content = serde_yaml::to_string(&conversation)?;
+        if existing.contains(&content) {
+            skipped += 1;
+            continue;
+        }
+        let name = match Config::free_session_name(title.as_deref().unwrap_or_default())? {
+            Some(name) => name,
+            None => Config::free_session_name("chatgpt")?.unwrap_or_default(),
+        };
+        let path = Config::write_session(&name, &conversation)?;
+        // keep the time of the conversation, so that `--resume` is not taken over by the import
+        if let Some(time) = value.update_time {
+            let time = UNIX_EPOCH + Duration::from_secs_f64(time.max(0.0));
+            File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(time))
+                .with_context(|| format!("Failed to set the time of {}", path.display()))?;
+        }
+        existing.insert(content);
+        paths.push(path);
+    }
+    Ok((paths, skipped))
+}
+
+fn read_export(path: &Path) -> Result<String> {
+    let read = || -> Result<String> {
+        if path.extension().is_some_and(|v| v == "zip") {
+            let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+            let mut content = String::new();
+            archive
+                .by_name("conversations.json")?
+                .read_to_string(&mut content)?;
+            Ok(content)
+        } else {
+            Ok(read_to_string(path)?)
+        }
+    };
+    read().with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// Text messages of the branch shown in ChatGPT, in order, hidden and tool messages left out
+fn chatgpt_messages(conversation: &ChatGptConversation) -> Vec<Message> {
+    let mut nodes = vec![];
+    let mut id = conversation.current_node.as_deref();
+    while let Some(node) = id.and_then(|v| conversation.mapping.get(v)) {
+        if nodes.len() > conversation.mapping.len() {
+            break;
+        }
+        nodes.push(node);
+        id = node.parent.as_deref();
+    }
+    let mut messages: Vec<Message> = vec![];
+    for message in nodes.iter().rev().filter_map(|v| v.message.as_ref()) {
+        let role = match message.author.role.as_str() {
+            "system" => MessageRole::System,
+            "user" => MessageRole::User,
+            "assistant" => MessageRole::Assistant,
+            _ => continue,
+        };
+        let hidden = message
+            .metadata
+            .get("is_visually_hidden_from_conversation")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        if hidden
+            || !matches!(
+                message.content.content_type.as_str(),
+                "text" | "multimodal_text"
+            )
+        {
+            continue;
+        }
+        let parts: Vec<&str> = message
+            .content
+            .parts
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        let text = parts.join("\n");
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        // replies split by tool calls are joined back
+        match messages.last_mut() {
+            Some(last) if last.role == role => {
+                last.content.push_str("\n\n");
+                last.content.push_str(text);
+            }
+            _ => messages.push(Message {
+                role,
+                content: text.to_string(),
+                images: vec![],
+            }),
+        }
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chatgpt_messages() {
+        let export = r#"{
+            "title": "Sorting in Rust",
+            "current_node": "d",
+            "mapping": {
+                "root": {"message": null, "parent": null},
+                "s": {"message": {"author": {"role": "system"}, "content": {"content_type": "text", "parts": [""]}, "metadata": {"is_visually_hidden_from_conversation": true}}, "parent": "root"},
+                "a": {"message": {"author": {"role": "user"}, "content": {"content_type": "text", "parts": ["How to sort a Vec?"]}}, "parent": "s"},
+                "b": {"message": {"author": {"role": "assistant"}, "content": {"content_type": "text", "parts": ["Discarded answer"]}}, "parent": "a"},
+                "c": {"message": {"author": {"role": "assistant"}, "content": {"content_type": "code", "parts": []}}, "parent": "a"},
+                "t": {"message": {"author": {"role": "tool"}, "content": {"content_type": "text", "parts": ["output"]}}, "parent": "c"},
+                "d": {"message": {"author": {"role": "assistant"}, "content": {"content_type": "text", "parts": ["Use sort"]}}, "parent": "t"}
+            }
+        }"#;
+        let conversation: ChatGptConversation = serde_json::from_str(export).unwrap();
+        let messages: Vec<(MessageRole, String)> = chatgpt_messages(&conversation)
+            .into_iter()
+            .map(|v| (v.role, v.content))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (MessageRole::User, "How to sort a Vec?".into()),
+                (MessageRole::Assistant, "Use sort".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,32 @@
+//! Client and config handling of aichat, for programs that embed it.
+//!
+//! ```no_run
+//! use aichat::{init_client, Config};
+//! use parking_lot::Mutex;
+//! use std::sync::Arc;
+//!
+//! let config = Arc::new(Mutex::new(Config::init(false)?));
+//! let client = init_client(config)?;
+//! println!("{}", client.send_message("hello")?);
+//! # anyhow::Ok(())
+//! ```
+
+pub mod batch;
+pub mod check;
+pub mod client;
+pub mod config;
+pub mod import;
+pub mod logger;
+pub mod rag;
+pub mod recall;
+pub mod render;
+pub mod repl;
+pub mod search;
+pub mod serve;
+pub mod speech;
+pub mod term;
+#[macro_use]
+pub mod utils;
+
+pub use crate::client::{init_client, Client};
+pub use crate::config::{Config, Conversation, Message, MessageRole, Role, SharedConfig};
@@ -0,0 +1,56 @@
+use crate::config::Config;
+use crate::utils::now;
+
+use anyhow::{Context, Result};
+use log::{LevelFilter, Log, Metadata, Record};
+use parking_lot::Mutex;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+/// Appends the records of aichat itself to `<data_dir>/aichat.log`
+struct FileLogger {
+    file: Mutex<File>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // dependencies such as reqwest log too, only our own records are of interest
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let _ = writeln!(
+            self.file.lock(),
+            "{} {:<5} {}",
+            now(),
+            record.level(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().flush();
+    }
+}
+
+/// Start logging, nothing is written at `LevelFilter::Off`
+pub fn init(level: LevelFilter) -> Result<()> {
+    if level == LevelFilter::Off {
+        return Ok(());
+    }
+    let path = Config::log_file()?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    log::set_boxed_logger(Box::new(FileLogger {
+        file: Mutex::new(file),
+    }))
+    .with_context(|| "Failed to init logger")?;
+    log::set_max_level(level);
+    Ok(())
+}
@@ -1,32 +1,83 @@
 mod cli;
-mod client;
-mod config;
-mod render;
-mod repl;
-mod term;
-#[macro_use]
-mod utils;
 
-use crate::cli::Cli;
-use crate::client::ChatGptClient;
-use crate::config::{Config, SharedConfig};
+use crate::cli::{
+    Cli, Command, GitCommand, ImportCommand, KeyringCommand, OutputFormat, RagCommand, RolesCommand,
+};
 
-use anyhow::{anyhow, Result};
+use aichat::client::{init_client, Client};
+use aichat::config::{sync_role_repo, truncated_reply, Config, Overrides, SharedConfig};
+use aichat::import::import_chatgpt;
+use aichat::rag::Rag;
+use aichat::render::{render_once, render_stream};
+use aichat::repl::{AbortSignal, Repl};
+use aichat::search::{render_hits, search, Matcher};
+use aichat::utils::{
+    cl100k_base_singleton, command_dangers, detect_shell, edit_text, extract_code, git_commit,
+    git_diff, last_code_block, load_files, set_clipboard_text,
+};
+use aichat::{batch, check, logger, serve, speech, term};
+
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Parser;
 use crossbeam::sync::WaitGroup;
+use inquire::{Password, PasswordDisplayMode, Select, Text};
 use is_terminal::IsTerminal;
+use log::LevelFilter;
 use parking_lot::Mutex;
-use render::{render_stream, MarkdownRender};
-use repl::{AbortSignal, Repl};
+use serde_json::json;
 use std::io::{stdin, Read};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 use std::{io::stdout, process::exit};
-use utils::cl100k_base_singleton;
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let text = cli.text();
-    let config = Arc::new(Mutex::new(Config::init(text.is_none())?));
+    if let Some(shell) = cli.completions {
+        // completions are generated without a config too, just without roles
+        let roles: Vec<String> = Config::init(false)
+            .map(|config| {
+                config
+                    .roles
+                    .iter()
+                    .map(|v| v.name.trim_matches('%').to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Cli::print_completions(shell, &roles);
+        return Ok(());
+    }
+    if let Some(Command::Check) = cli.command {
+        exit(check::run());
+    }
+    let mut text = cli.text();
+    let piped = !stdin().is_terminal();
+    let is_interactive = text.is_none() && cli.files.is_empty() && !piped;
+    let config = Arc::new(Mutex::new(Config::init(is_interactive)?));
+    let log_level = match cli.verbose {
+        true => LevelFilter::Debug,
+        false => config.lock().log_level.unwrap_or(LevelFilter::Off),
+    };
+    logger::init(log_level)?;
+    // `%name% text` applies the role to the text
+    let inline = match text.as_deref() {
+        Some(input) => config.lock().inline_role(input)?,
+        None => None,
+    };
+    let inline_role = match inline {
+        Some((role, input)) => {
+            text = Some(input);
+            Some(role)
+        }
+        None => None,
+    };
+    if !cli.files.is_empty() {
+        let files = load_files(&config, &cli.files)?;
+        text = Some(match text {
+            Some(text) => format!("{files}\n\n{text}"),
+            None => files,
+        });
+    }
     if cli.list_roles {
         config
             .lock()
@@ -35,51 +86,254 @@ fn main() -> Result<()> {
             .for_each(|v| println!("{}", v.name));
         exit(0);
     }
+    if cli.list_sessions {
+        for name in config.lock().list_sessions()? {
+            println!("{name}");
+        }
+        exit(0);
+    }
+    match &cli.command {
+        Some(Command::Rag(RagCommand::Add { name, paths })) => {
+            let client = init_client(config)?;
+            let mut rag = Rag::load(name)?;
+            rag.add(client.as_ref(), paths)?;
+            return rag.save();
+        }
+        Some(Command::Batch {
+            file,
+            concurrency,
+            output,
+        }) => {
+            let client = init_client(config)?;
+            return batch::run(client.as_ref(), file, *concurrency, output.as_deref());
+        }
+        Some(Command::Transcribe { file, chat }) => {
+            let client = init_client(config.clone())?;
+            let transcript = client.transcribe(Path::new(file))?;
+            if !chat {
+                println!("{transcript}");
+                return Ok(());
+            }
+            text = Some(transcript);
+        }
+        Some(Command::Image { prompt, output }) => {
+            let client = init_client(config.clone())?;
+            let data = client.generate_image(&prompt.join(" "))?;
+            let path = match output {
+                Some(v) => v.clone(),
+                None => format!("image-{}.png", chrono::Local::now().format("%Y%m%d-%H%M%S")),
+            };
+            std::fs::write(&path, &data).with_context(|| format!("Failed to write {path}"))?;
+            if stdout().is_terminal() {
+                term::print_image(&data)?;
+            }
+            println!("Saved {path}");
+            return Ok(());
+        }
+        Some(Command::Git(GitCommand::CommitMsg)) => {
+            let diff = git_diff(true)?;
+            if diff.trim().is_empty() {
+                bail!("Nothing staged, add changes with `git add` first");
+            }
+            let role = config.lock().find_role("commit-msg");
+            config.lock().role = role;
+            let client = init_client(config.clone())?;
+            let output = start_directive(client, config, &diff, None, OutputFormat::Text, false)?;
+            if stdout().is_terminal() {
+                commit_with_message(&output)?;
+            }
+            return Ok(());
+        }
+        Some(Command::Import(ImportCommand::Chatgpt { file })) => {
+            let (paths, skipped) = import_chatgpt(&config.lock(), Path::new(file))?;
+            println!(
+                "Imported {} conversations to {}",
+                paths.len(),
+                Config::sessions_dir()?.display()
+            );
+            if skipped > 0 {
+                println!("Skipped {skipped} conversations imported before");
+            }
+            return Ok(());
+        }
+        Some(Command::Keyring(KeyringCommand::Set)) => {
+            let config = config.lock();
+            let api_key = Password::new(&format!("Api key of {}:", config.client))
+                .with_display_mode(PasswordDisplayMode::Masked)
+                .without_confirmation()
+                .prompt()?;
+            config.set_keyring_api_key(api_key.trim())?;
+            println!("Stored the api key, set `keyring: true` to use it");
+            return Ok(());
+        }
+        Some(Command::Keyring(KeyringCommand::Delete)) => {
+            if config.lock().delete_keyring_api_key()? {
+                println!("Removed the api key");
+            } else {
+                println!("No api key stored for {}", config.lock().client);
+            }
+            return Ok(());
+        }
+        Some(Command::Roles(RolesCommand::Sync)) => {
+            let urls = config.lock().role_repos.clone();
+            if urls.is_empty() {
+                bail!("No role repos, list git or https urls in `role_repos` of the config");
+            }
+            let mut failed = 0;
+            for url in &urls {
+                match sync_role_repo(&config, url) {
+                    Ok(count) => println!("Synced {url}, roles: {count}"),
+                    Err(err) => {
+                        failed += 1;
+                        eprintln!("Failed to sync {url}, {err:#}");
+                    }
+                }
+            }
+            if failed > 0 {
+                bail!("{failed} of {} role repos failed to sync", urls.len());
+            }
+            return Ok(());
+        }
+        Some(Command::Search { query, regex }) => {
+            let matcher = Matcher::new(&query.join(" "), *regex)?;
+            let hits = search(&config.lock(), &matcher)?;
+            print!("{}", render_hits(&hits, Some(&matcher)));
+            return Ok(());
+        }
+        Some(Command::Serve {
+            address,
+            role,
+            tools,
+        }) => {
+            if let Some(name) = role {
+                let role = config
+                    .lock()
+                    .find_role(name)
+                    .ok_or_else(|| anyhow!("Unknown role '{name}'"))?;
+                config.lock().role = Some(role);
+            }
+            return serve::run(config, address, *tools);
+        }
+        Some(Command::Check) | None => {}
+    }
     let role = match &cli.role {
-        Some(name) => Some(
-            config
+        Some(input) => {
+            let (name, args) = input.split_once(' ').unwrap_or((input, ""));
+            let mut role = config
                 .lock()
                 .find_role(name)
-                .ok_or_else(|| anyhow!("Unknown role '{name}'"))?,
-        ),
+                .ok_or_else(|| anyhow!("Unknown role '{name}'"))?;
+            role.set_variables(args)?;
+            Some(role)
+        }
+        None if cli.execute => config.lock().find_role("shell"),
+        None if cli.code => config.lock().find_role("code"),
+        None if cli.prompt.is_none() && inline_role.is_none() => {
+            config.lock().find_default_role()?
+        }
         None => None,
     };
     config.lock().role = role;
-    if cli.no_highlight {
-        config.lock().highlight = false;
-    }
-    let no_stream = cli.no_stream;
-    let client = ChatGptClient::init(config.clone())?;
-    if atty::isnt(atty::Stream::Stdin) {
-        let mut input = String::new();
-        stdin().read_to_string(&mut input)?;
-        if let Some(text) = text {
-            input = format!("{text}\n{input}");
-        }
-        start_directive(client, config, &input, no_stream)
-    } else {
-        match text {
-            Some(text) => start_directive(client, config, &text, no_stream),
-            None => start_interactive(client, config),
-        }
+    if let Some(prompt) = &cli.prompt {
+        config.lock().create_temp_role(prompt)?;
+    }
+    if inline_role.is_some() {
+        config.lock().role = inline_role;
+    }
+    let mut overrides = Overrides {
+        model: cli.model.clone(),
+        temperature: cli.temperature,
+        seed: cli.seed,
+        proxy: cli.proxy.clone(),
+        dry_run: cli.dry_run,
+        resume: cli.resume,
+        no_cache: cli.no_cache,
+        no_highlight: cli.no_highlight,
+        no_stream: cli.no_stream,
+        light_theme: None,
+    };
+    let detect_theme = {
+        let config = config.lock();
+        config.highlight
+            && !cli.no_highlight
+            && config.theme.is_none()
+            && config.light_theme.is_none()
+    };
+    if detect_theme && stdout().is_terminal() {
+        overrides.light_theme = term::detect_light_background();
+    }
+    config.lock().apply_overrides(overrides);
+    if cli.info {
+        print!("{}", config.lock().info(cli.full)?);
+        return Ok(());
+    }
+    let output_file = cli.output.as_deref();
+    let format = cli.format;
+    let client = init_client(config.clone())?;
+    let input = match text {
+        _ if piped => read_stdin(text)?,
+        Some(text) => text,
+        None if format == OutputFormat::Json => bail!("--format json requires input text"),
+        None if cli.execute => bail!("--execute requires input text"),
+        None => return start_interactive(client, config),
+    };
+    let output = start_directive(
+        client,
+        config.clone(),
+        &input,
+        output_file,
+        format,
+        cli.code,
+    )?;
+    if cli.execute {
+        execute_command(&config, &output)?;
+    }
+    Ok(())
+}
+
+/// Piped content follows the instruction given as text, e.g. `cat error.log | aichat explain this`
+fn read_stdin(text: Option<String>) -> Result<String> {
+    let mut input = String::new();
+    stdin().read_to_string(&mut input)?;
+    let input = input.trim_end();
+    match text {
+        Some(text) if input.is_empty() => Ok(text),
+        Some(text) => Ok(format!("{text}\n{input}")),
+        None if input.is_empty() => bail!("No input from stdin"),
+        None => Ok(input.to_string()),
     }
 }
 
 fn start_directive(
-    client: ChatGptClient,
+    client: Box<dyn Client>,
     config: SharedConfig,
     input: &str,
-    no_stream: bool,
-) -> Result<()> {
+    output_file: Option<&str>,
+    format: OutputFormat,
+    code: bool,
+) -> Result<String> {
     let highlight = config.lock().highlight && stdout().is_terminal();
-    let output = if no_stream {
+    let mut truncated = false;
+    let output = if format == OutputFormat::Json {
+        let start = Instant::now();
         let output = client.send_message(input)?;
-        if highlight {
-            let mut markdown_render = MarkdownRender::new();
-            println!("{}", markdown_render.render(&output).trim());
-        } else {
-            println!("{}", output.trim());
-        }
+        let value = json!({
+            "input": input,
+            "output": output,
+            "model": client.get_model(),
+            "usage": config.lock().usage,
+            "duration_ms": start.elapsed().as_millis() as u64,
+        });
+        println!("{value}");
+        output
+    } else if code {
+        // printed without rendering so that it can be piped into a file or interpreter
+        let output = extract_code(&client.send_message(input)?);
+        println!("{output}");
+        output
+    } else if !config.lock().stream {
+        let output = client.send_message(input)?;
+        render_once(&output, &config, highlight)?;
         output
     } else {
         let wg = WaitGroup::new();
@@ -89,16 +343,112 @@ fn start_directive(
             abort_clone.set_ctrlc();
         })
         .expect("Error setting Ctrl-C handler");
-        let output = render_stream(input, &client, highlight, false, abort, wg.clone())?;
+        let output = render_stream(
+            input,
+            client.as_ref(),
+            highlight,
+            false,
+            abort.clone(),
+            wg.clone(),
+        )?;
         wg.wait();
+        truncated = abort.aborted();
         output
     };
-    config.lock().save_message(input, &output)
+    if let Some(path) = output_file {
+        std::fs::write(path, format!("{}\n", output.trim()))
+            .with_context(|| format!("Failed to write {path}"))?;
+    }
+    let saved = if truncated && !output.is_empty() {
+        truncated_reply(&output)
+    } else {
+        output.clone()
+    };
+    config.lock().save_message(input, &saved)?;
+    let speak_reply = config.lock().speak && format == OutputFormat::Text && !code && !truncated;
+    if speak_reply && !output.is_empty() {
+        speech::speak(client.as_ref(), &output)?;
+    }
+    Ok(output)
+}
+
+/// Let the user run, edit or copy the generated command, dangerous ones are confirmed again
+fn execute_command(config: &SharedConfig, output: &str) -> Result<()> {
+    let shell = detect_shell();
+    let mut command = last_code_block(output).unwrap_or_else(|| output.trim().to_string());
+    loop {
+        let answer = Select::new(
+            "Run this command?",
+            vec!["execute", "edit", "copy", "cancel"],
+        )
+        .prompt()?;
+        match answer {
+            "execute" => {
+                let dangers = {
+                    let config = config.lock();
+                    command_dangers(
+                        &command,
+                        &config.command_allowlist,
+                        &config.command_denylist,
+                    )?
+                };
+                if !dangers.is_empty() && !confirm_dangerous(&dangers)? {
+                    continue;
+                }
+                let code = shell.run(&command)?;
+                if code != 0 {
+                    exit(code);
+                }
+                return Ok(());
+            }
+            "edit" => {
+                command = edit_text(&command)?;
+                println!("{command}");
+            }
+            "copy" => return set_clipboard_text(&command),
+            _ => return Ok(()),
+        }
+    }
+}
+
+fn confirm_dangerous(dangers: &[String]) -> Result<bool> {
+    for danger in dangers {
+        println!("Warning: this command {danger}");
+    }
+    let answer = Text::new("Type `yes` to run it anyway:").prompt()?;
+    Ok(answer.trim() == "yes")
+}
+
+/// Let the user commit with the generated message, edit or copy it
+fn commit_with_message(output: &str) -> Result<()> {
+    let mut message = extract_code(output);
+    loop {
+        let answer = Select::new(
+            "Commit with this message?",
+            vec!["commit", "edit", "copy", "cancel"],
+        )
+        .prompt()?;
+        match answer {
+            "commit" => {
+                let code = git_commit(&message)?;
+                if code != 0 {
+                    exit(code);
+                }
+                return Ok(());
+            }
+            "edit" => {
+                message = edit_text(&message)?;
+                println!("{message}");
+            }
+            "copy" => return set_clipboard_text(&message),
+            _ => return Ok(()),
+        }
+    }
 }
 
-fn start_interactive(client: ChatGptClient, config: SharedConfig) -> Result<()> {
+fn start_interactive(client: Box<dyn Client>, config: SharedConfig) -> Result<()> {
     cl100k_base_singleton();
     config.lock().on_repl()?;
-    let mut repl = Repl::init(config.clone())?;
+    let mut repl = Repl::init(config.clone(), &client.models())?;
     repl.run(client, config)
 }
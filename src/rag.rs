@@ -0,0 +1,243 @@
+use crate::client::Client;
+use crate::config::Config;
+use crate::print_now;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+/// Chunks are cut at paragraph boundaries when they grow past this many chars
+const CHUNK_SIZE: usize = 1500;
+/// Number of chunks retrieved for each question
+const TOP_K: usize = 4;
+
+/// Document chunks and their embeddings, stored at `<data_dir>/rags/<name>.bin`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rag {
+    #[serde(skip)]
+    pub name: String,
+    /// Embedding model, vectors of different models cannot be compared
+    pub model: String,
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    pub source: String,
+    pub text: String,
+    pub vector: Vec<f32>,
+}
+
+impl Rag {
+    /// Load a rag, an unknown name gives an empty one
+    pub fn load(name: &str) -> Result<Self> {
+        let path = Config::rag_file(name)?;
+        let mut rag = if path.exists() {
+            let content = std::fs::read(&path)
+                .with_context(|| format!("Failed to load {}", path.display()))?;
+            bincode::deserialize(&content).with_context(|| format!("Invalid rag `{name}`"))?
+        } else {
+            Self::default()
+        };
+        rag.name = name.to_string();
+        Ok(rag)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Config::rag_file(&self.name)?;
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).with_context(|| "Failed to create rags dir")?;
+        }
+        let content = bincode::serialize(self).with_context(|| "Failed to serialize rag")?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Embed files, directories are walked recursively. Files added before are replaced.
+    pub fn add(&mut self, client: &dyn Client, paths: &[String]) -> Result<()> {
+        let model = client.get_embedding_model();
+        if self.chunks.is_empty() {
+            self.model = model;
+        } else if self.model != model {
+            bail!(
+                "Rag `{}` was built with {}, not {model}",
+                self.name,
+                self.model
+            );
+        }
+        let mut files = vec![];
+        for path in paths {
+            collect_files(Path::new(path), &mut files)?;
+        }
+        for file in files {
+            // skip binaries
+            let content = match read_to_string(&file) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            let source = file.canonicalize().unwrap_or(file).display().to_string();
+            let texts = split_chunks(&content);
+            let vectors = if texts.is_empty() {
+                vec![]
+            } else {
+                client.embeddings(&texts)?
+            };
+            self.chunks.retain(|v| v.source != source);
+            for (text, vector) in texts.into_iter().zip(vectors) {
+                self.chunks.push(Chunk {
+                    source: source.clone(),
+                    text,
+                    vector,
+                });
+            }
+            print_now!("Added {source}\n");
+        }
+        Ok(())
+    }
+
+    /// Prepend the chunks most similar to the input
+    pub fn augment(&self, client: &dyn Client, input: &str) -> Result<String> {
+        if self.chunks.is_empty() {
+            return Ok(input.to_string());
+        }
+        if client.get_embedding_model() != self.model {
+            bail!(
+                "Error: Rag `{}` was built with {}, switch back to use it",
+                self.name,
+                self.model
+            );
+        }
+        let vector = client
+            .embeddings(&[input.to_string()])?
+            .pop()
+            .ok_or_else(|| anyhow!("No embedding returned"))?;
+        let context = self
+            .search(&vector, TOP_K)
+            .iter()
+            .map(|v| format!("{}:\n{}", v.source, v.text))
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        Ok(format!(
+            "Answer using the context below when it is relevant.\n\n<context>\n{context}\n</context>\n\n{input}"
+        ))
+    }
+
+    fn search(&self, vector: &[f32], top_k: usize) -> Vec<&Chunk> {
+        let mut scored: Vec<(f32, &Chunk)> = self
+            .chunks
+            .iter()
+            .map(|v| (cosine_similarity(vector, &v.vector), v))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.into_iter().take(top_k).map(|(_, v)| v).collect()
+    }
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !path.is_dir() {
+        if !path.exists() {
+            bail!("Not found {}", path.display());
+        }
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = vec![];
+    for entry in read_dir(path).with_context(|| format!("Failed to read {}", path.display()))? {
+        let entry_path = entry?.path();
+        let hidden = entry_path
+            .file_name()
+            .and_then(|v| v.to_str())
+            .is_some_and(|v| v.starts_with('.'));
+        if !hidden {
+            entries.push(entry_path);
+        }
+    }
+    entries.sort();
+    for entry in entries {
+        collect_files(&entry, files)?;
+    }
+    Ok(())
+}
+
+/// Group paragraphs into chunks of about `CHUNK_SIZE` chars, longer paragraphs are cut
+fn split_chunks(text: &str) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    for paragraph in text.split("\n\n").map(|v| v.trim()) {
+        if paragraph.is_empty() {
+            continue;
+        }
+        if !chunk.is_empty() && chunk.len() + paragraph.len() > CHUNK_SIZE {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        let mut paragraph = paragraph;
+        while paragraph.len() > CHUNK_SIZE {
+            let mut index = CHUNK_SIZE;
+            while !paragraph.is_char_boundary(index) {
+                index -= 1;
+            }
+            chunks.push(paragraph[..index].to_string());
+            paragraph = &paragraph[index..];
+        }
+        if !chunk.is_empty() {
+            chunk.push_str("\n\n");
+        }
+        chunk.push_str(paragraph);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_chunks() {
+        assert_eq!(split_chunks("a\n\n\n\nb\n"), vec!["a\n\nb"]);
+        let long = "x".repeat(CHUNK_SIZE + 10);
+        let text = format!("a\n\n{long}\n\nb");
+        let chunks = split_chunks(&text);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0], "a");
+        assert_eq!(chunks[1].len(), CHUNK_SIZE);
+        assert_eq!(chunks[2], format!("{}\n\nb", "x".repeat(10)));
+    }
+
+    #[test]
+    fn test_search() {
+        let chunk = |text: &str, vector: Vec<f32>| Chunk {
+            source: "a.md".into(),
+            text: text.into(),
+            vector,
+        };
+        let rag = Rag {
+            chunks: vec![
+                chunk("east", vec![1.0, 0.0]),
+                chunk("north", vec![0.0, 1.0]),
+                chunk("north east", vec![1.0, 1.0]),
+            ],
+            ..Default::default()
+        };
+        let texts: Vec<&str> = rag
+            .search(&[0.1, 1.0], 2)
+            .iter()
+            .map(|v| v.text.as_str())
+            .collect();
+        assert_eq!(texts, vec!["north", "north east"]);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
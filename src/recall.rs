@@ -0,0 +1,166 @@
+use crate::client::Client;
+use crate::config::Config;
+use crate::print_now;
+use crate::rag::cosine_similarity;
+use crate::search::Exchange;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Chars of an exchange embedded, the start of a long one is enough to tell what it is about
+const EMBED_SIZE: usize = 4000;
+/// Number of exchanges recalled for a query
+const TOP_K: usize = 3;
+
+/// Embeddings of past exchanges, stored at `<data_dir>/recall.bin`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Recall {
+    /// Embedding model, vectors of different models cannot be compared
+    pub model: String,
+    pub memories: Vec<Memory>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Memory {
+    pub exchange: Exchange,
+    pub vector: Vec<f32>,
+}
+
+impl Recall {
+    pub fn load() -> Result<Self> {
+        let path = Config::recall_file()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content =
+            std::fs::read(&path).with_context(|| format!("Failed to load {}", path.display()))?;
+        bincode::deserialize(&content).with_context(|| "Invalid recall index")
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Config::recall_file()?;
+        let content = bincode::serialize(self).with_context(|| "Failed to serialize recall")?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Embed the exchanges not indexed yet and forget the ones gone from the history.
+    /// Everything is embedded again when the embedding model changed.
+    pub fn update(&mut self, client: &dyn Client, exchanges: Vec<Exchange>) -> Result<()> {
+        let model = client.get_embedding_model();
+        if self.model != model {
+            self.model = model;
+            self.memories.clear();
+        }
+        let keys: HashSet<(&str, &str)> = exchanges
+            .iter()
+            .map(|v| (v.input.as_str(), v.output.as_str()))
+            .collect();
+        self.memories
+            .retain(|v| keys.contains(&(v.exchange.input.as_str(), v.exchange.output.as_str())));
+        let indexed: HashSet<(String, String)> = self
+            .memories
+            .iter()
+            .map(|v| (v.exchange.input.clone(), v.exchange.output.clone()))
+            .collect();
+        let new: Vec<Exchange> = exchanges
+            .into_iter()
+            .filter(|v| !indexed.contains(&(v.input.clone(), v.output.clone())))
+            .collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+        print_now!("Indexing {} exchanges...\n", new.len());
+        let texts: Vec<String> = new.iter().map(embed_text).collect();
+        let vectors = client.embeddings(&texts)?;
+        for (exchange, vector) in new.into_iter().zip(vectors) {
+            self.memories.push(Memory { exchange, vector });
+        }
+        Ok(())
+    }
+
+    /// The past exchanges most similar to the query
+    pub fn find(&self, client: &dyn Client, query: &str) -> Result<Vec<&Exchange>> {
+        if self.memories.is_empty() {
+            return Ok(vec![]);
+        }
+        let vector = client
+            .embeddings(&[query.to_string()])?
+            .pop()
+            .ok_or_else(|| anyhow!("No embedding returned"))?;
+        Ok(self.search(&vector, TOP_K))
+    }
+
+    fn search(&self, vector: &[f32], top_k: usize) -> Vec<&Exchange> {
+        let mut scored: Vec<(f32, &Exchange)> = self
+            .memories
+            .iter()
+            .map(|v| (cosine_similarity(vector, &v.vector), &v.exchange))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.into_iter().take(top_k).map(|(_, v)| v).collect()
+    }
+}
+
+/// Prepend the recalled exchanges to the text
+pub fn recall_context(exchanges: &[&Exchange], text: &str) -> String {
+    let context = exchanges
+        .iter()
+        .map(|v| format!("Q: {}\nA: {}", v.input.trim(), v.output.trim()))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    format!("Earlier exchanges that may be relevant:\n\n<context>\n{context}\n</context>\n\n{text}")
+}
+
+fn embed_text(exchange: &Exchange) -> String {
+    format!("{}\n\n{}", exchange.input, exchange.output)
+        .chars()
+        .take(EMBED_SIZE)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(input: &str, output: &str) -> Exchange {
+        Exchange {
+            time: "2024-05-01T10:00:00+08:00".into(),
+            source: "messages.md".into(),
+            role: None,
+            input: input.into(),
+            output: output.into(),
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let memory = |input: &str, vector: Vec<f32>| Memory {
+            exchange: exchange(input, ""),
+            vector,
+        };
+        let recall = Recall {
+            model: "m".into(),
+            memories: vec![
+                memory("east", vec![1.0, 0.0]),
+                memory("north", vec![0.0, 1.0]),
+            ],
+        };
+        let inputs: Vec<&str> = recall
+            .search(&[0.1, 1.0], 1)
+            .iter()
+            .map(|v| v.input.as_str())
+            .collect();
+        assert_eq!(inputs, vec!["north"]);
+    }
+
+    #[test]
+    fn test_recall_context() {
+        let sort = exchange("how to sort a vec\n", "Use `sort`");
+        assert_eq!(
+            recall_context(&[&sort], "and in reverse?"),
+            "Earlier exchanges that may be relevant:\n\n<context>\nQ: how to sort a vec\nA: Use `sort`\n</context>\n\nand in reverse?"
+        );
+    }
+}
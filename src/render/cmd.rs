@@ -5,10 +5,15 @@ use crate::repl::{ReplyStreamEvent, SharedAbortSignal};
 
 use anyhow::Result;
 use crossbeam::channel::Receiver;
+use syntect::highlighting::Theme;
 
-pub fn cmd_render_stream(rx: Receiver<ReplyStreamEvent>, abort: SharedAbortSignal) -> Result<()> {
+pub fn cmd_render_stream(
+    rx: Receiver<ReplyStreamEvent>,
+    theme: Theme,
+    abort: SharedAbortSignal,
+) -> Result<()> {
     let mut buffer = String::new();
-    let mut markdown_render = MarkdownRender::new();
+    let mut markdown_render = MarkdownRender::init(theme);
     loop {
         if abort.aborted() {
             return Ok(());
@@ -113,13 +118,7 @@ impl Kind {
                 '~' => Kind::Tilde2,
                 _ => return None,
             },
-            3 => {
-                if chars == ['`', '`', '`'] {
-                    Kind::Backtick3
-                } else {
-                    return None;
-                }
-            }
+            3 if chars == ['`', '`', '`'] => Kind::Backtick3,
             _ => return None,
         };
         Some(kind)
@@ -1,8 +1,16 @@
+use crate::config::{Config, SharedConfig};
+
+use anyhow::{Context, Result};
 use crossterm::style::{Color, Stylize};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
-use syntect::highlighting::{Color as SyntectColor, FontStyle, Style, Theme};
-use syntect::parsing::SyntaxSet;
+use std::fs::File;
+use std::io::BufReader;
+use syntect::highlighting::{
+    Color as SyntectColor, FontStyle, HighlightIterator, HighlightState, Highlighter,
+    ScopeSelectors, Style, StyleModifier, Theme, ThemeItem, ThemeSettings,
+};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
 use syntect::{easy::HighlightLines, parsing::SyntaxReference};
 
 /// Monokai Extended
@@ -25,14 +33,16 @@ pub struct MarkdownRender {
     code_color: Color,
     md_syntax: SyntaxReference,
     code_syntax: Option<SyntaxReference>,
+    /// Carried across the lines of the current code block so that multi-line
+    /// strings and comments keep their colors while streaming
+    code_state: Option<(ParseState, HighlightState)>,
     prev_line_type: LineType,
 }
 
 impl MarkdownRender {
-    pub fn new() -> Self {
+    pub fn init(md_theme: Theme) -> Self {
         let syntax_set: SyntaxSet =
             bincode::deserialize_from(SYNTAXES).expect("invalid syntaxes binary");
-        let md_theme: Theme = bincode::deserialize_from(MD_THEME).expect("invalid md_theme binary");
         let code_color = get_code_color(&md_theme);
         let md_syntax = syntax_set.find_syntax_by_extension("md").unwrap().clone();
         let line_type = LineType::Normal;
@@ -42,6 +52,7 @@ impl MarkdownRender {
             code_color,
             md_syntax,
             code_syntax: None,
+            code_state: None,
             prev_line_type: line_type,
         }
     }
@@ -79,10 +90,12 @@ impl MarkdownRender {
                     } else {
                         self.find_syntax(&lang).cloned()
                     };
+                    self.code_state = self.code_syntax.as_ref().map(|v| self.new_code_state(v));
                 }
                 LineType::CodeBegin | LineType::CodeInner => {
                     self.prev_line_type = LineType::CodeEnd;
                     self.code_syntax = None;
+                    self.code_state = None;
                 }
             }
             self.render_line_inner(line, &self.md_syntax)
@@ -96,13 +109,14 @@ impl MarkdownRender {
                 LineType::CodeBegin => {
                     if self.code_syntax.is_none() {
                         if let Some(syntax) = self.syntax_set.find_syntax_by_first_line(line) {
+                            self.code_state = Some(self.new_code_state(syntax));
                             self.code_syntax = Some(syntax.clone());
                         }
                     }
                     self.prev_line_type = LineType::CodeInner;
-                    self.render_code_line(line)
+                    self.advance_code_line(line)
                 }
-                LineType::CodeInner => self.render_code_line(line),
+                LineType::CodeInner => self.advance_code_line(line),
             }
         }
     }
@@ -117,11 +131,51 @@ impl MarkdownRender {
         Some(format!("{ws}{}", as_terminal_escaped(&ranges)))
     }
 
+    /// Render a partial line of the code block without moving its state forward
     fn render_code_line(&self, line: &str) -> Option<String> {
-        self.code_syntax
-            .as_ref()
-            .map(|syntax| self.render_line_inner(line, syntax))
-            .unwrap_or_else(|| Some(format!("{}", line.with(self.code_color))))
+        match self.code_state.clone() {
+            Some((mut parse_state, mut highlight_state)) => {
+                self.render_code_line_inner(line, &mut parse_state, &mut highlight_state)
+            }
+            None => Some(format!("{}", line.with(self.code_color))),
+        }
+    }
+
+    fn advance_code_line(&mut self, line: &str) -> Option<String> {
+        match self.code_state.take() {
+            Some((mut parse_state, mut highlight_state)) => {
+                let output =
+                    self.render_code_line_inner(line, &mut parse_state, &mut highlight_state);
+                self.code_state = Some((parse_state, highlight_state));
+                output
+            }
+            None => Some(format!("{}", line.with(self.code_color))),
+        }
+    }
+
+    fn render_code_line_inner(
+        &self,
+        line: &str,
+        parse_state: &mut ParseState,
+        highlight_state: &mut HighlightState,
+    ) -> Option<String> {
+        // the syntaxes are compiled for newline terminated lines, line comments end on it
+        let line = format!("{line}\n");
+        let ops = parse_state.parse_line(&line, &self.syntax_set).ok()?;
+        let highlighter = Highlighter::new(&self.md_theme);
+        let ranges: Vec<(Style, &str)> =
+            HighlightIterator::new(highlight_state, &ops, &line, &highlighter)
+                .map(|(style, text)| (style, text.trim_end_matches('\n')))
+                .collect();
+        Some(as_terminal_escaped(&ranges))
+    }
+
+    fn new_code_state(&self, syntax: &SyntaxReference) -> (ParseState, HighlightState) {
+        let highlighter = Highlighter::new(&self.md_theme);
+        (
+            ParseState::new(syntax),
+            HighlightState::new(&highlighter, ScopeStack::new()),
+        )
     }
 
     fn find_syntax(&self, lang: &str) -> Option<&SyntaxReference> {
@@ -135,6 +189,90 @@ impl MarkdownRender {
     }
 }
 
+/// Load `config.theme` from the themes dir, or fall back to the builtin dark or light theme
+pub fn load_theme(config: &SharedConfig) -> Result<Theme> {
+    let (name, light) = {
+        let config = config.lock();
+        (config.theme.clone(), config.light_theme.unwrap_or_default())
+    };
+    match name {
+        Some(name) => {
+            let path = Config::theme_file(&name)?;
+            let file = File::open(&path)
+                .with_context(|| format!("Failed to load theme at {}", path.display()))?;
+            bincode::deserialize_from(BufReader::new(file))
+                .with_context(|| format!("Invalid theme at {}", path.display()))
+        }
+        None if light => Ok(light_theme()),
+        None => Ok(dark_theme()),
+    }
+}
+
+fn dark_theme() -> Theme {
+    bincode::deserialize_from(MD_THEME).expect("invalid md_theme binary")
+}
+
+fn light_theme() -> Theme {
+    let rgb = |v: u32| SyntectColor {
+        r: (v >> 16) as u8,
+        g: (v >> 8) as u8,
+        b: v as u8,
+        a: 0xff,
+    };
+    let scopes = [
+        ("comment", 0x6a737d, FontStyle::empty()),
+        ("string, markup.raw", 0x032f62, FontStyle::empty()),
+        (
+            "constant, support.constant, variable.other.constant",
+            0x005cc5,
+            FontStyle::empty(),
+        ),
+        ("keyword, storage", 0xd73a49, FontStyle::empty()),
+        (
+            "entity.name.function, support.function",
+            0x6f42c1,
+            FontStyle::empty(),
+        ),
+        (
+            "entity.name.type, entity.name.class, support.type, support.class",
+            0xe36209,
+            FontStyle::empty(),
+        ),
+        (
+            "markup.heading, entity.name.section",
+            0x005cc5,
+            FontStyle::BOLD,
+        ),
+        ("markup.bold", 0x24292e, FontStyle::BOLD),
+        ("markup.italic", 0x24292e, FontStyle::ITALIC),
+        ("markup.quote", 0x22863a, FontStyle::empty()),
+        ("punctuation.definition.list", 0xe36209, FontStyle::empty()),
+        ("markup.underline.link", 0x032f62, FontStyle::UNDERLINE),
+    ];
+    Theme {
+        name: Some("Light".into()),
+        author: None,
+        settings: ThemeSettings {
+            foreground: Some(rgb(0x24292e)),
+            background: Some(rgb(0xffffff)),
+            ..Default::default()
+        },
+        scopes: scopes
+            .into_iter()
+            .map(|(scope, color, font_style)| ThemeItem {
+                scope: scope
+                    .parse::<ScopeSelectors>()
+                    .expect("invalid scope selector"),
+                style: StyleModifier {
+                    foreground: Some(rgb(color)),
+                    background: None,
+                    font_style: Some(font_style),
+                },
+            })
+            .collect(),
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LineType {
     Normal,
@@ -217,13 +355,35 @@ mod tests {
         let syntax_set: SyntaxSet =
             bincode::deserialize_from(SYNTAXES).expect("invalid syntaxes.bin");
         assert!(syntax_set.find_syntax_by_extension("md").is_some());
-        let md_theme: Theme = bincode::deserialize_from(MD_THEME).expect("invalid md_theme binary");
-        assert_eq!(md_theme.name, Some("Monokai Extended".into()));
+        assert_eq!(dark_theme().name, Some("Monokai Extended".into()));
+        let light_theme = light_theme();
+        assert_eq!(
+            get_code_color(&light_theme),
+            convert_color(light_theme.scopes[1].style.foreground.unwrap())
+        );
     }
 
     #[test]
     fn test_render() {
-        let render = MarkdownRender::new();
+        let render = MarkdownRender::init(dark_theme());
         assert!(render.find_syntax("csharp").is_some());
     }
+
+    #[test]
+    fn test_render_code_block_state() {
+        let mut code = MarkdownRender::init(dark_theme());
+        code.render("```rust\nlet a = 1;");
+        let mut block_comment = MarkdownRender::init(dark_theme());
+        block_comment.render("```rust\n/* a multi-line");
+        let mut line_comment = MarkdownRender::init(dark_theme());
+        line_comment.render("```rust\n// a line comment");
+        assert_ne!(
+            block_comment.render_line_stateless("comment"),
+            code.render_line_stateless("comment")
+        );
+        assert_eq!(
+            line_comment.render_line_stateless("comment"),
+            code.render_line_stateless("comment")
+        );
+    }
 }
@@ -3,10 +3,11 @@ mod markdown;
 mod repl;
 
 use self::cmd::cmd_render_stream;
-pub use self::markdown::MarkdownRender;
+pub use self::markdown::{load_theme, MarkdownRender};
 use self::repl::repl_render_stream;
 
-use crate::client::ChatGptClient;
+use crate::client::Client;
+use crate::config::SharedConfig;
 use crate::print_now;
 use crate::repl::{ReplyStreamHandler, SharedAbortSignal};
 
@@ -17,20 +18,21 @@ use std::thread::spawn;
 
 pub fn render_stream(
     input: &str,
-    client: &ChatGptClient,
+    client: &dyn Client,
     highlight: bool,
     repl: bool,
     abort: SharedAbortSignal,
     wg: WaitGroup,
 ) -> Result<String> {
     let mut stream_handler = if highlight {
+        let theme = load_theme(client.get_config())?;
         let (tx, rx) = unbounded();
         let abort_clone = abort.clone();
         spawn(move || {
             let err = if repl {
-                repl_render_stream(rx, abort)
+                repl_render_stream(rx, theme, abort)
             } else {
-                cmd_render_stream(rx, abort)
+                cmd_render_stream(rx, theme, abort)
             };
             if let Err(err) = err {
                 let err = format!("{err:?}");
@@ -47,3 +49,14 @@ pub fn render_stream(
     let buffer = stream_handler.get_buffer();
     Ok(buffer.to_string())
 }
+
+/// Print a whole reply, used when not streaming
+pub fn render_once(output: &str, config: &SharedConfig, highlight: bool) -> Result<()> {
+    if highlight {
+        let mut markdown_render = MarkdownRender::init(load_theme(config)?);
+        print_now!("{}\n", markdown_render.render(output).trim());
+    } else {
+        print_now!("{}\n", output.trim());
+    }
+    Ok(())
+}
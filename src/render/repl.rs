@@ -14,13 +14,18 @@ use std::{
     io::{self, Stdout, Write},
     time::{Duration, Instant},
 };
+use syntect::highlighting::Theme;
 use unicode_width::UnicodeWidthStr;
 
-pub fn repl_render_stream(rx: Receiver<ReplyStreamEvent>, abort: SharedAbortSignal) -> Result<()> {
+pub fn repl_render_stream(
+    rx: Receiver<ReplyStreamEvent>,
+    theme: Theme,
+    abort: SharedAbortSignal,
+) -> Result<()> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
 
-    let ret = repl_render_stream_inner(rx, abort, &mut stdout);
+    let ret = repl_render_stream_inner(rx, theme, abort, &mut stdout);
 
     disable_raw_mode()?;
 
@@ -29,13 +34,14 @@ pub fn repl_render_stream(rx: Receiver<ReplyStreamEvent>, abort: SharedAbortSign
 
 fn repl_render_stream_inner(
     rx: Receiver<ReplyStreamEvent>,
+    theme: Theme,
     abort: SharedAbortSignal,
     writer: &mut Stdout,
 ) -> Result<()> {
     let mut last_tick = Instant::now();
     let tick_rate = Duration::from_millis(100);
     let mut buffer = String::new();
-    let mut markdown_render = MarkdownRender::new();
+    let mut markdown_render = MarkdownRender::init(theme);
     let terminal_columns = terminal::size()?.0;
     loop {
         if abort.aborted() {
@@ -113,7 +119,7 @@ fn repl_render_stream_inner(
 }
 
 fn recover_cursor(writer: &mut Stdout, terminal_columns: u16, buffer: &str) -> Result<()> {
-    let buffer_rows = (buffer.width() as u16 + terminal_columns - 1) / terminal_columns;
+    let buffer_rows = (buffer.width() as u16).div_ceil(terminal_columns);
     let (_, row) = cursor::position()?;
     if buffer_rows == 0 {
         queue!(writer, cursor::MoveTo(0, row))?;
@@ -1,48 +1,116 @@
-use crate::client::ChatGptClient;
-use crate::config::SharedConfig;
+use crate::client::{init_client, Client};
+use crate::config::{truncated_reply, Config, ContextStrategy, Role, SharedConfig, Usage};
 use crate::print_now;
-use crate::render::render_stream;
+use crate::recall::{recall_context, Recall};
+use crate::render::{render_once, render_stream};
+use crate::search::{load_exchanges, render_hits, search, Exchange, Matcher};
+use crate::speech::speak;
+use crate::utils::{
+    edit_text, get_clipboard_image, git_diff, last_code_block, load_files, set_clipboard_text,
+    wrap_code_block,
+};
 
 use super::abort::SharedAbortSignal;
+use super::settings::pick_settings;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
 use crossbeam::channel::Sender;
 use crossbeam::sync::WaitGroup;
+use inquire::Confirm;
+use log::warn;
+use parking_lot::Mutex;
 use std::cell::RefCell;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Instant;
+
+/// Asks for the rest of a reply cut off by the token limit
+const CONTINUE_PROMPT: &str =
+    "Continue exactly where your last reply stopped, without repeating anything.";
+/// Enough for a title of a few words
+const TITLE_MAX_TOKENS: usize = 20;
 
 pub enum ReplCmd {
     Submit(String),
+    AttachFiles(Vec<String>, String),
+    AttachDiff(bool, String),
+    PasteImage,
+    /// Send the transcript of an audio file, after the text if any
+    Transcribe(String, String),
+    /// Compose the input in an editor, starting from the previous input if true
+    Edit(bool),
+    Regenerate(Option<f64>),
     SetRole(String),
+    CreateRole(String),
+    EditRole(String),
+    DeleteRole(String),
     UpdateConfig(String),
+    /// Change settings from a list
+    Settings,
+    Reload,
     Prompt(String),
+    /// Show the prompt of `.prompt` and offer to clear it
+    ViewPrompt,
     ClearRole,
-    ViewInfo,
+    SetRag(String),
+    ClearRag,
+    /// Print the information, with the api key unmasked if true
+    ViewInfo(bool),
+    ListModels,
+    ViewUsage,
     StartConversation,
+    ListConversations,
+    SwitchConversation(usize),
+    CloseConversation(Option<usize>),
     EndConversatoin,
+    Undo,
+    Compact,
+    Continue,
+    SaveSession(Option<String>),
+    LoadSession(String),
+    ListSessions,
+    /// Search saved messages and sessions, with a regex if true
+    Search(String, bool),
+    /// Print the past exchanges similar to the query, or send the text along with them
+    Recall(String, Option<String>),
+    Export(String, String),
+    CopyReply,
+    CopyCode,
 }
 
 pub struct ReplCmdHandler {
-    client: ChatGptClient,
+    client: Box<dyn Client>,
     config: SharedConfig,
+    input: RefCell<String>,
     reply: RefCell<String>,
     abort: SharedAbortSignal,
 }
 
 impl ReplCmdHandler {
     pub fn init(
-        client: ChatGptClient,
+        client: Box<dyn Client>,
         config: SharedConfig,
         abort: SharedAbortSignal,
     ) -> Result<Self> {
+        let input = RefCell::new(String::new());
         let reply = RefCell::new(String::new());
         Ok(Self {
             client,
             config,
+            input,
             reply,
             abort,
         })
     }
 
+    pub fn get_abort(&self) -> SharedAbortSignal {
+        self.abort.clone()
+    }
+
     pub fn handle(&self, cmd: ReplCmd) -> Result<()> {
         match cmd {
             ReplCmd::Submit(input) => {
@@ -50,53 +118,508 @@ impl ReplCmdHandler {
                     self.reply.borrow_mut().clear();
                     return Ok(());
                 }
-                let highlight = self.config.lock().highlight;
-                let wg = WaitGroup::new();
-                let ret = render_stream(
-                    &input,
-                    &self.client,
-                    highlight,
-                    true,
-                    self.abort.clone(),
-                    wg.clone(),
-                );
-                wg.wait();
-                let buffer = ret?;
-                self.config.lock().save_message(&input, &buffer)?;
-                self.config.lock().save_conversation(&input, &buffer)?;
-                *self.reply.borrow_mut() = buffer;
+                let inline = self.config.lock().inline_role(&input)?;
+                if let Some((role, text)) = inline {
+                    return self.submit_with_role(role, text, input);
+                }
+                let rag = self.config.lock().rag.clone();
+                let content = match rag {
+                    Some(rag) => rag.augment(self.client.as_ref(), &input)?,
+                    None => input.clone(),
+                };
+                let strategy = self.config.lock().context_strategy;
+                if strategy != ContextStrategy::Error && self.config.lock().need_compact(&content) {
+                    if strategy == ContextStrategy::Truncate {
+                        let dropped = self.config.lock().truncate_conversation(&content);
+                        print_now!("Dropped {dropped} earlier messages of the conversation\n");
+                    } else {
+                        print_now!("Compacting the conversation...\n");
+                        self.compact_conversation()?;
+                    }
+                }
+                let (buffer, truncated) = self.print_reply(self.client.as_ref(), &content)?;
+                let saved = if truncated && !buffer.is_empty() {
+                    truncated_reply(&buffer)
+                } else {
+                    buffer.clone()
+                };
+                // the retrieved context is sent with this request only
+                self.config.lock().save_message(&input, &saved)?;
+                self.config.lock().save_conversation(&input, &saved)?;
+                *self.input.borrow_mut() = input;
+                *self.reply.borrow_mut() = buffer.clone();
+                self.hint_cut_off();
+                if !truncated {
+                    self.speak_reply(&buffer)?;
+                    self.title_conversation();
+                }
+            }
+            ReplCmd::Edit(last) => {
+                let initial = if last {
+                    self.input.borrow().clone()
+                } else {
+                    String::new()
+                };
+                let input = edit_text(&initial)?;
+                if input.trim().is_empty() {
+                    print_now!("\n");
+                    return Ok(());
+                }
+                print_now!("{}\n", input);
+                self.handle(ReplCmd::Submit(input))?;
+            }
+            ReplCmd::Regenerate(temperature) => {
+                let input = self.input.borrow().clone();
+                if input.is_empty() {
+                    bail!("Error: No message to regenerate");
+                }
+                // the exchange comes back if the resend fails
+                let popped = {
+                    let mut config = self.config.lock();
+                    // a message with an inline role was sent outside the conversation
+                    let inline = config.split_inline_role(&input).is_some();
+                    let popped = match config.conversation.as_mut().filter(|_| !inline) {
+                        Some(conversation) if !conversation.messages.is_empty() => {
+                            let saved = (conversation.messages.clone(), conversation.tokens);
+                            conversation.pop_last_exchange()?;
+                            Some(saved)
+                        }
+                        _ => None,
+                    };
+                    config.next_temperature = temperature;
+                    popped
+                };
+                let ret = self.handle(ReplCmd::Submit(input));
+                let mut config = self.config.lock();
+                config.next_temperature = None;
+                if let (Err(_), Some((messages, tokens))) = (&ret, popped) {
+                    if let Some(conversation) = config.conversation.as_mut() {
+                        conversation.messages = messages;
+                        conversation.tokens = tokens;
+                    }
+                }
+                ret?;
+            }
+            ReplCmd::AttachFiles(paths, text) => {
+                let files = load_files(&self.config, &paths)?;
+                let input = if text.is_empty() {
+                    files
+                } else {
+                    format!("{files}\n\n{text}")
+                };
+                self.handle(ReplCmd::Submit(input))?;
+            }
+            ReplCmd::AttachDiff(staged, text) => {
+                let diff = git_diff(staged)?;
+                if diff.trim().is_empty() {
+                    bail!("Error: No changes to diff");
+                }
+                let label = if staged {
+                    "git diff --staged"
+                } else {
+                    "git diff"
+                };
+                let diff = wrap_code_block(label, "diff", &diff);
+                let input = if text.is_empty() {
+                    diff
+                } else {
+                    format!("{diff}\n\n{text}")
+                };
+                self.handle(ReplCmd::Submit(input))?;
             }
             ReplCmd::SetRole(name) => {
                 let output = self.config.lock().change_role(&name)?;
                 print_now!("{}\n\n", output.trim_end());
             }
+            ReplCmd::CreateRole(name) => {
+                self.config.lock().create_role(&name)?;
+                print_now!("\n");
+            }
+            ReplCmd::EditRole(name) => {
+                self.config.lock().edit_role(&name)?;
+                print_now!("\n");
+            }
+            ReplCmd::DeleteRole(name) => {
+                self.config.lock().delete_role(&name)?;
+                print_now!("\n");
+            }
             ReplCmd::ClearRole => {
                 self.config.lock().clear_role()?;
                 print_now!("\n");
             }
+            ReplCmd::SetRag(name) => {
+                self.config.lock().set_rag(&name)?;
+                print_now!("\n");
+            }
+            ReplCmd::ClearRag => {
+                self.config.lock().rag = None;
+                print_now!("\n");
+            }
             ReplCmd::Prompt(prompt) => {
-                self.config.lock().create_temp_role(&prompt)?;
+                let mut config = self.config.lock();
+                config.create_temp_role(&prompt)?;
+                let preview = config.preview_prompt().unwrap_or_default();
+                print_now!("{preview}\n\n");
+            }
+            ReplCmd::ViewPrompt => {
+                let preview = self.config.lock().preview_prompt();
+                let preview = match preview {
+                    Some(v) => v,
+                    None => bail!("Error: No prompt, set one with `.prompt <text>`"),
+                };
+                print_now!("{preview}\n\n");
+                let clear = Confirm::new("Clear the prompt?")
+                    .with_default(false)
+                    .prompt()
+                    .unwrap_or_default();
+                if clear {
+                    self.config.lock().clear_role()?;
+                }
                 print_now!("\n");
             }
-            ReplCmd::ViewInfo => {
-                let output = self.config.lock().info()?;
+            ReplCmd::ViewInfo(full) => {
+                let output = self.config.lock().info(full)?;
                 print_now!("{}\n\n", output.trim_end());
             }
-            ReplCmd::UpdateConfig(input) => {
-                self.config.lock().update(&input)?;
+            ReplCmd::ViewUsage => {
+                let output = self.config.lock().usage_info()?;
+                print_now!("{}\n\n", output.trim_end());
+            }
+            ReplCmd::ListModels => {
+                let models = self.client.list_models()?;
+                print_now!("{}\n\n", models.join("\n"));
+            }
+            ReplCmd::Settings => {
+                pick_settings(&self.config, &self.client.models())?;
                 print_now!("\n");
             }
+            ReplCmd::Reload => {
+                let fresh = Config::load_again()?;
+                self.config.lock().reload(fresh);
+                print_now!("Reloaded {}\n\n", Config::config_file()?.display());
+            }
+            ReplCmd::UpdateConfig(input) => {
+                let output = self.config.lock().update(&input)?;
+                if output.is_empty() {
+                    print_now!("\n");
+                } else {
+                    print_now!("{}\n\n", output);
+                }
+            }
             ReplCmd::StartConversation => {
-                self.config.lock().start_conversation()?;
+                let parked = self.config.lock().start_conversation();
+                hint_parked(parked);
+                print_now!("\n");
+            }
+            ReplCmd::ListConversations => {
+                let list = self.config.lock().list_conversations();
+                if list.is_empty() {
+                    print_now!("No conversations\n\n");
+                } else {
+                    print_now!("{list}\n\n");
+                }
+            }
+            ReplCmd::SwitchConversation(id) => {
+                let parked = self.config.lock().switch_conversation(id)?;
+                hint_parked(parked);
+                print_now!("\n");
+            }
+            ReplCmd::CloseConversation(id) => {
+                self.config.lock().close_conversation(id)?;
                 print_now!("\n");
             }
             ReplCmd::EndConversatoin => {
                 self.config.lock().end_conversation();
                 print_now!("\n");
             }
+            ReplCmd::Undo => {
+                self.config.lock().undo_conversation()?;
+                // the last exchange is gone, there is nothing left to regenerate or copy
+                self.input.borrow_mut().clear();
+                self.reply.borrow_mut().clear();
+                print_now!("\n");
+            }
+            ReplCmd::Compact => {
+                self.compact_conversation()?;
+                print_now!("\n");
+            }
+            ReplCmd::SaveSession(name) => {
+                let mut config = self.config.lock();
+                let name = match name.or_else(|| config.session.clone()) {
+                    Some(name) => name,
+                    None => match config.titled_session_name()? {
+                        Some(name) => name,
+                        None => bail!("Error: The conversation has no title yet, name the session"),
+                    },
+                };
+                let path = config.save_session(&name)?;
+                print_now!("Saved session to {}\n\n", path.display());
+            }
+            ReplCmd::LoadSession(name) => {
+                self.config.lock().load_session(&name)?;
+                print_now!("\n");
+            }
+            ReplCmd::Export(format, path) => {
+                self.config
+                    .lock()
+                    .export_conversation(&format, Path::new(&path))?;
+                print_now!("Exported conversation to {}\n\n", path);
+            }
+            ReplCmd::CopyReply => {
+                let reply = self.reply.borrow();
+                if reply.is_empty() {
+                    bail!("Error: No reply to copy");
+                }
+                set_clipboard_text(&reply)?;
+                print_now!("\n");
+            }
+            ReplCmd::CopyCode => {
+                let code = match last_code_block(&self.reply.borrow()) {
+                    Some(code) => code,
+                    None => bail!("Error: No code block in the last reply"),
+                };
+                set_clipboard_text(&code)?;
+                print_now!("\n");
+            }
+            ReplCmd::Transcribe(path, text) => {
+                let transcript = self.client.transcribe(Path::new(&path))?;
+                print_now!("{transcript}\n\n");
+                let input = if text.is_empty() {
+                    transcript
+                } else {
+                    format!("{text}\n\n{transcript}")
+                };
+                self.handle(ReplCmd::Submit(input))?;
+            }
+            ReplCmd::PasteImage => {
+                let data = get_clipboard_image()?;
+                let url = format!(
+                    "data:image/png;base64,{}",
+                    general_purpose::STANDARD.encode(&data)
+                );
+                let mut config = self.config.lock();
+                config.images.push(url);
+                print_now!(
+                    "Attached the clipboard image, {} image(s) go with the next message\n\n",
+                    config.images.len()
+                );
+            }
+            ReplCmd::Continue => {
+                self.continue_reply()?;
+            }
+            ReplCmd::Search(query, regex) => {
+                let matcher = Matcher::new(&query, regex)?;
+                let hits = search(&self.config.lock(), &matcher)?;
+                if hits.is_empty() {
+                    print_now!("No matching messages\n\n");
+                } else {
+                    print_now!("{}", render_hits(&hits, Some(&matcher)));
+                }
+            }
+            ReplCmd::Recall(query, text) => {
+                let exchanges = load_exchanges(&self.config.lock())?;
+                let mut recall = Recall::load()?;
+                recall.update(self.client.as_ref(), exchanges)?;
+                recall.save()?;
+                let found = recall.find(self.client.as_ref(), &query)?;
+                if found.is_empty() {
+                    bail!("Error: No saved exchanges to recall, turn on `save` or save sessions");
+                }
+                match text {
+                    Some(text) => self.handle(ReplCmd::Submit(recall_context(&found, &text)))?,
+                    None => {
+                        let hits: Vec<Exchange> = found.into_iter().cloned().collect();
+                        print_now!("{}", render_hits(&hits, None));
+                    }
+                }
+            }
+            ReplCmd::ListSessions => {
+                let names = self.config.lock().list_sessions()?;
+                if names.is_empty() {
+                    print_now!("No saved sessions\n\n");
+                } else {
+                    let width = names.iter().map(|v| v.chars().count()).max().unwrap_or(0);
+                    let lines: Vec<String> = names
+                        .iter()
+                        .map(|name| {
+                            let line = format!("{name:<width$}  {}", Config::session_title(name));
+                            line.trim_end().to_string()
+                        })
+                        .collect();
+                    print_now!("{}\n\n", lines.join("\n"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the text of `%name% text` with the role, outside the conversation,
+    /// leaving the active role and conversation as they were
+    fn submit_with_role(&self, role: Role, text: String, input: String) -> Result<()> {
+        let (role, conversation) = {
+            let mut config = self.config.lock();
+            (config.role.replace(role), config.conversation.take())
+        };
+        let ret = self.handle(ReplCmd::Submit(text));
+        {
+            let mut config = self.config.lock();
+            config.role = role;
+            config.conversation = conversation;
+        }
+        ret?;
+        // `.regenerate` and `.edit` repeat it with the role
+        *self.input.borrow_mut() = input;
+        Ok(())
+    }
+
+    /// Ask for the rest of a reply cut off by the token limit, streamed after what was shown
+    /// and appended to the same reply
+    fn continue_reply(&self) -> Result<()> {
+        let input = self.input.borrow().clone();
+        let reply = self.reply.borrow().clone();
+        if reply.is_empty() {
+            bail!("Error: No reply to continue");
+        }
+        let config = self.config.lock().continue_config(&input, &reply)?;
+        let config = Arc::new(Mutex::new(config));
+        let client = init_client(config.clone())?;
+        let (rest, truncated) = self.print_reply(client.as_ref(), CONTINUE_PROMPT)?;
+        let (usage, finish_reason) = {
+            let config = config.lock();
+            (config.usage.clone(), config.finish_reason.clone())
+        };
+        {
+            let mut config = self.config.lock();
+            config.record_usage(&usage);
+            config.finish_reason = finish_reason;
+            config.save_message(CONTINUE_PROMPT, &rest)?;
+            config.extend_last_reply(&rest)?;
+        }
+        self.reply.borrow_mut().push_str(&rest);
+        self.hint_cut_off();
+        if !truncated {
+            self.speak_reply(&rest)?;
         }
         Ok(())
     }
+
+    fn speak_reply(&self, reply: &str) -> Result<()> {
+        if self.config.lock().speak && !reply.is_empty() {
+            speak(self.client.as_ref(), reply)?;
+        }
+        Ok(())
+    }
+
+    /// Send the content and print the reply, streamed unless `stream` is off.
+    /// Returns the reply and whether it was aborted.
+    fn print_reply(&self, client: &dyn Client, content: &str) -> Result<(String, bool)> {
+        let highlight = self.config.lock().highlight;
+        if self.config.lock().stream {
+            let wg = WaitGroup::new();
+            let ret = render_stream(
+                content,
+                client,
+                highlight,
+                true,
+                self.abort.clone(),
+                wg.clone(),
+            );
+            wg.wait();
+            Ok((ret?, self.abort.aborted()))
+        } else {
+            let output = client.send_message(content)?;
+            render_once(&output, &self.config, highlight)?;
+            print_now!("\n");
+            Ok((output, false))
+        }
+    }
+
+    fn hint_cut_off(&self) {
+        if self.config.lock().reply_cut_off() {
+            print_now!("The reply hit the max tokens limit, type .continue for the rest\n\n");
+        }
+    }
+
+    /// Name the conversation after its first exchange with a separate call to `title_model`.
+    /// A failure only leaves it untitled.
+    fn title_conversation(&self) {
+        let (prompt, config) = {
+            let config = self.config.lock();
+            if config.dry_run {
+                return;
+            }
+            let prompt = match config.conversation.as_ref().and_then(|v| v.title_prompt()) {
+                Some(v) => v,
+                None => return,
+            };
+            let mut config = config.clone();
+            config.model = config.title_model.clone().or_else(|| config.get_model());
+            config.role = None;
+            config.rag = None;
+            config.conversation = None;
+            config.tools = false;
+            config.cache = false;
+            config.max_tokens = Some(TITLE_MAX_TOKENS);
+            config.usage = Usage::default();
+            config.images = vec![];
+            (prompt, Arc::new(Mutex::new(config)))
+        };
+        let title = init_client(config.clone()).and_then(|client| client.send_message(&prompt));
+        let title = match title {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("Failed to title the conversation, {err}");
+                return;
+            }
+        };
+        let usage = config.lock().usage.clone();
+        let mut config = self.config.lock();
+        config.record_usage(&usage);
+        if let Some(conversation) = config.conversation.as_mut() {
+            conversation.set_title(&title);
+        }
+    }
+
+    /// Fold the earlier messages of the conversation into a summary, written by a separate
+    /// call without the conversation
+    fn compact_conversation(&self) -> Result<()> {
+        let (prompt, config) = {
+            let config = self.config.lock();
+            let conversation = match config.conversation.as_ref() {
+                Some(v) => v,
+                None => bail!("Error: Not in a conversation"),
+            };
+            let prompt = match conversation.compact_prompt() {
+                Some(v) => v,
+                None => bail!("Error: Not enough messages to compact"),
+            };
+            let mut config = config.clone();
+            config.model = config.get_model();
+            config.role = None;
+            config.rag = None;
+            config.conversation = None;
+            config.tools = false;
+            config.cache = false;
+            config.usage = Usage::default();
+            config.images = vec![];
+            (prompt, Arc::new(Mutex::new(config)))
+        };
+        let client = init_client(config.clone())?;
+        let summary = client.send_message(&prompt)?;
+        let usage = config.lock().usage.clone();
+        let mut config = self.config.lock();
+        config.record_usage(&usage);
+        if let Some(conversation) = config.conversation.as_mut() {
+            conversation.compact(&summary)?;
+        }
+        Ok(())
+    }
+}
+
+fn hint_parked(parked: Option<usize>) {
+    if let Some(id) = parked {
+        print_now!("Conversation #{id} is set aside, `.conversation switch {id}` to get back\n");
+    }
 }
 
 pub struct ReplyStreamHandler {
@@ -104,6 +627,10 @@ pub struct ReplyStreamHandler {
     buffer: String,
     abort: SharedAbortSignal,
     repl: bool,
+    /// Set once the first event arrives
+    received: Arc<AtomicBool>,
+    /// When the stream last sent anything, None until it starts, for the idle timeout
+    last_event: Arc<Mutex<Option<Instant>>>,
 }
 
 impl ReplyStreamHandler {
@@ -117,6 +644,8 @@ impl ReplyStreamHandler {
             abort,
             buffer: String::new(),
             repl,
+            received: Arc::new(AtomicBool::new(false)),
+            last_event: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -125,6 +654,7 @@ impl ReplyStreamHandler {
             return Ok(());
         }
         self.buffer.push_str(text);
+        self.received.store(true, Ordering::SeqCst);
         match self.sender.as_ref() {
             Some(tx) => {
                 let ret = tx
@@ -153,7 +683,7 @@ impl ReplyStreamHandler {
                 }
                 if self.repl {
                     print_now!("\n");
-                    if cfg!(macos) {
+                    if cfg!(target_os = "macos") {
                         print_now!("\n")
                     }
                 }
@@ -170,6 +700,20 @@ impl ReplyStreamHandler {
         self.abort.clone()
     }
 
+    pub fn get_received(&self) -> Arc<AtomicBool> {
+        self.received.clone()
+    }
+
+    pub fn get_last_event(&self) -> Arc<Mutex<Option<Instant>>> {
+        self.last_event.clone()
+    }
+
+    /// Called on every event of the stream, and after tools ran, to hold off the timeouts
+    pub fn touch(&self) {
+        self.received.store(true, Ordering::SeqCst);
+        *self.last_event.lock() = Some(Instant::now());
+    }
+
     fn safe_ret(&self, ret: Result<()>) -> Result<()> {
         if ret.is_err() && self.abort.aborted() {
             return Ok(());
@@ -0,0 +1,119 @@
+use crate::config::SharedConfig;
+
+use reedline::{Completer, Span, Suggestion};
+use std::cmp::Reverse;
+use std::fs::read_to_string;
+
+/// Escape of newlines in multi-line entries of history files, same as `FileBackedHistory`
+const NEWLINE_ESCAPE: &str = "<\\n>";
+
+/// Fuzzy search over the history in use, shown in a menu on Ctrl-R
+pub struct HistorySearch {
+    config: SharedConfig,
+}
+
+impl HistorySearch {
+    pub fn new(config: SharedConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl Completer for HistorySearch {
+    fn complete(&mut self, line: &str, _pos: usize) -> Vec<Suggestion> {
+        let path = self.config.lock().repl_history_file();
+        let entries = match path.map(read_to_string) {
+            Ok(Ok(content)) => content
+                .lines()
+                .map(|v| v.replace(NEWLINE_ESCAPE, "\n"))
+                .collect(),
+            _ => vec![],
+        };
+        search(&entries, line)
+            .into_iter()
+            .map(|value| Suggestion {
+                value,
+                description: None,
+                extra: None,
+                // the entry takes over the whole line
+                span: Span::new(0, line.len()),
+                append_whitespace: false,
+            })
+            .collect()
+    }
+}
+
+/// Entries matching the query, best match first, the most recent first among equals
+fn search(entries: &[String], query: &str) -> Vec<String> {
+    let mut matches: Vec<(usize, &String)> = vec![];
+    for entry in entries.iter().rev() {
+        if entry.trim().is_empty() || matches.iter().any(|(_, v)| *v == entry) {
+            continue;
+        }
+        if let Some(score) = fuzzy_score(entry, query) {
+            matches.push((score, entry));
+        }
+    }
+    // stable, so recency breaks ties
+    matches.sort_by_key(|v| Reverse(v.0));
+    matches.into_iter().map(|(_, v)| v.clone()).collect()
+}
+
+/// Whether the chars of the query appear in order in the text, ignoring case.
+/// Runs of consecutive chars and matches at word starts score higher.
+fn fuzzy_score(text: &str, query: &str) -> Option<usize> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut index = 0;
+    let mut last_match: Option<usize> = None;
+    for query_char in query.to_lowercase().chars().filter(|v| !v.is_whitespace()) {
+        let found = (index..text.len()).find(|&i| text[i] == query_char)?;
+        score += 1;
+        if last_match.is_some_and(|v| v + 1 == found) {
+            score += 4;
+        }
+        if found == 0 || !text[found - 1].is_alphanumeric() {
+            score += 2;
+        }
+        last_match = Some(found);
+        index = found + 1;
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert!(fuzzy_score("explain this error", "xyz").is_none());
+        assert!(fuzzy_score("explain this error", "eis").is_some());
+        assert!(
+            fuzzy_score("explain this error", "err") > fuzzy_score("explain this error", "eis")
+        );
+        assert_eq!(fuzzy_score("anything", ""), Some(0));
+    }
+
+    #[test]
+    fn test_search() {
+        let entries: Vec<String> = [
+            "translate to french",
+            "explain rust lifetimes",
+            "translate to french",
+            "tell a joke",
+        ]
+        .iter()
+        .map(|v| v.to_string())
+        .collect();
+        assert_eq!(search(&entries, "trfr"), vec!["translate to french"]);
+        assert_eq!(
+            search(&entries, ""),
+            vec![
+                "tell a joke",
+                "translate to french",
+                "explain rust lifetimes"
+            ]
+        );
+        assert_eq!(search(&entries, "rust")[0], "explain rust lifetimes");
+    }
+}
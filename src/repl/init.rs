@@ -1,51 +1,131 @@
+use super::history::HistorySearch;
 use super::REPL_COMMANDS;
 
-use crate::config::{Config, SharedConfig};
+use crate::config::SharedConfig;
 
 use anyhow::{Context, Result};
+use nu_ansi_term::{Color, Style};
 use reedline::{
-    default_emacs_keybindings, ColumnarMenu, DefaultCompleter, DefaultValidator, Emacs,
-    FileBackedHistory, KeyCode, KeyModifiers, Keybindings, Reedline, ReedlineEvent, ReedlineMenu,
+    default_emacs_keybindings, default_vi_insert_keybindings, default_vi_normal_keybindings,
+    ColumnarMenu, DefaultCompleter, DefaultHinter, DefaultValidator, EditMode, Emacs,
+    FileBackedHistory, KeyCode, KeyModifiers, Keybindings, ListMenu, Reedline, ReedlineEvent,
+    ReedlineMenu, Vi,
 };
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
 const MENU_NAME: &str = "completion_menu";
+const HISTORY_MENU_NAME: &str = "history_menu";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeybindingsType {
+    #[default]
+    Emacs,
+    Vi,
+}
+
+impl fmt::Display for KeybindingsType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            KeybindingsType::Emacs => "emacs",
+            KeybindingsType::Vi => "vi",
+        };
+        f.write_str(name)
+    }
+}
 
 pub struct Repl {
     pub editor: Reedline,
+    /// File of the history in use, see `Config::repl_history_file`
+    history_file: PathBuf,
+    models: Vec<String>,
+    /// Completions of the config the completer was made from, see `Config::repl_completions`
+    completions: Vec<String>,
 }
 
 impl Repl {
-    pub fn init(config: SharedConfig) -> Result<Self> {
-        let completer = Self::create_completer(config);
-        let keybindings = Self::create_keybindings();
-        let history = Self::create_history()?;
+    pub fn init(config: SharedConfig, models: &[String]) -> Result<Self> {
+        let keybindings_type = config.lock().keybindings;
+        let history_file = config.lock().repl_history_file()?;
+        let history = Self::create_history(&history_file)?;
+        let history_menu = Self::create_history_menu(config.clone());
+        let completions = config.lock().repl_completions();
+        let completer = Self::create_completer(&completions, models);
         let menu = Self::create_menu();
-        let edit_mode = Box::new(Emacs::new(keybindings));
+        let edit_mode = Self::create_edit_mode(keybindings_type);
         let editor = Reedline::create()
             .with_completer(Box::new(completer))
             .with_history(history)
             .with_menu(menu)
+            .with_menu(history_menu)
             .with_edit_mode(edit_mode)
             .with_quick_completions(true)
             .with_partial_completions(true)
             .with_validator(Box::new(DefaultValidator))
+            .with_hinter(Box::new(Self::create_hinter()))
             .with_ansi_colors(true);
-        Ok(Self { editor })
+        Ok(Self {
+            editor,
+            history_file,
+            models: models.to_vec(),
+            completions,
+        })
     }
 
-    fn create_completer(config: SharedConfig) -> DefaultCompleter {
+    /// Move to the history of the session when it changed, e.g. after `.session load`
+    pub fn switch_history(&mut self, config: &SharedConfig) -> Result<()> {
+        let path = config.lock().repl_history_file()?;
+        if path != self.history_file {
+            let _ = self.editor.sync_history();
+            let history = Self::create_history(&path)?;
+            let editor = std::mem::replace(&mut self.editor, Reedline::create());
+            self.editor = editor.with_history(history);
+            self.history_file = path;
+        }
+        Ok(())
+    }
+
+    /// Complete the roles of the config again when they changed, e.g. after `.reload`
+    pub fn refresh_completer(&mut self, config: &SharedConfig) {
+        let completions = config.lock().repl_completions();
+        if completions != self.completions {
+            let completer = Self::create_completer(&completions, &self.models);
+            let editor = std::mem::replace(&mut self.editor, Reedline::create());
+            self.editor = editor.with_completer(Box::new(completer));
+            self.completions = completions;
+        }
+    }
+
+    fn create_completer(completions: &[String], models: &[String]) -> DefaultCompleter {
         let mut completion: Vec<String> = REPL_COMMANDS
             .into_iter()
             .map(|(v, _)| v.to_string())
             .collect();
-        completion.extend(config.lock().repl_completions());
+        completion.extend_from_slice(completions);
+        completion.extend(models.iter().map(|v| format!(".set model {v}")));
         let mut completer = DefaultCompleter::with_inclusions(&['.', '-', '_']).set_min_word_len(2);
         completer.insert(completion.clone());
         completer
     }
 
-    fn create_keybindings() -> Keybindings {
-        let mut keybindings = default_emacs_keybindings();
+    fn create_edit_mode(keybindings_type: KeybindingsType) -> Box<dyn EditMode> {
+        match keybindings_type {
+            KeybindingsType::Emacs => {
+                let mut keybindings = default_emacs_keybindings();
+                Self::add_keybindings(&mut keybindings);
+                Box::new(Emacs::new(keybindings))
+            }
+            KeybindingsType::Vi => {
+                let mut insert_keybindings = default_vi_insert_keybindings();
+                Self::add_keybindings(&mut insert_keybindings);
+                Box::new(Vi::new(insert_keybindings, default_vi_normal_keybindings()))
+            }
+        }
+    }
+
+    fn add_keybindings(keybindings: &mut Keybindings) {
         keybindings.add_binding(
             KeyModifiers::NONE,
             KeyCode::Tab,
@@ -59,7 +139,14 @@ impl Repl {
             KeyCode::Char('l'),
             ReedlineEvent::ExecuteHostCommand(".clear screen".into()),
         );
-        keybindings
+        keybindings.add_binding(
+            KeyModifiers::CONTROL,
+            KeyCode::Char('r'),
+            ReedlineEvent::UntilFound(vec![
+                ReedlineEvent::Menu(HISTORY_MENU_NAME.to_string()),
+                ReedlineEvent::MenuNext,
+            ]),
+        );
     }
 
     fn create_menu() -> ReedlineMenu {
@@ -67,9 +154,26 @@ impl Repl {
         ReedlineMenu::EngineCompleter(Box::new(completion_menu))
     }
 
-    fn create_history() -> Result<Box<FileBackedHistory>> {
+    /// Dimmed suggestion from history, accepted with Right or End
+    fn create_hinter() -> DefaultHinter {
+        DefaultHinter::default().with_style(Style::new().italic().fg(Color::DarkGray))
+    }
+
+    /// Filtered by the whole line as it is typed
+    fn create_history_menu(config: SharedConfig) -> ReedlineMenu {
+        let menu = ListMenu::default()
+            .with_name(HISTORY_MENU_NAME)
+            .with_only_buffer_difference(false)
+            .with_page_size(10);
+        ReedlineMenu::WithCompleter {
+            menu: Box::new(menu),
+            completer: Box::new(HistorySearch::new(config)),
+        }
+    }
+
+    fn create_history(path: &Path) -> Result<Box<FileBackedHistory>> {
         Ok(Box::new(
-            FileBackedHistory::with_file(1000, Config::history_file()?)
+            FileBackedHistory::with_file(1000, path.to_path_buf())
                 .with_context(|| "Failed to setup history file")?,
         ))
     }
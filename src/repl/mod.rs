@@ -1,31 +1,108 @@
 mod abort;
 mod handler;
+mod history;
 mod init;
 mod prompt;
+mod settings;
 
 pub use self::abort::*;
 pub use self::handler::*;
-pub use self::init::Repl;
+pub use self::init::{KeybindingsType, Repl};
 use self::prompt::ReplPrompt;
 
-use crate::client::ChatGptClient;
-use crate::config::SharedConfig;
+use crate::client::Client;
+use crate::config::{watch_config, Config, SharedConfig};
 use crate::print_now;
 use crate::term;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use log::warn;
 use reedline::Signal;
 use std::borrow::Cow;
-use std::sync::Arc;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::rc::Rc;
 
-pub const REPL_COMMANDS: [(&str, &str); 11] = [
+pub const REPL_COMMANDS: [(&str, &str); 44] = [
     (".info", "Print the information"),
+    (
+        ".info full",
+        "Print the information with the api key unmasked",
+    ),
+    (".usage", "Print token usage and estimated cost"),
     (".set", "Modify the configuration temporarily"),
-    (".prompt", "Add a GPT prompt"),
+    (".settings", "Change settings from a list"),
+    (".reload", "Reload config.yaml and the roles"),
+    (".models", "List available models"),
+    (
+        ".prompt",
+        "Set a temporary prompt, without text view or clear it",
+    ),
+    (
+        ".file",
+        "Attach files or urls to the message, e.g. .file a.rs b.rs -- explain",
+    ),
+    (
+        ".diff",
+        "Send the git diff, e.g. .diff --staged -- review this",
+    ),
+    (
+        ".continue",
+        "Get the rest of a reply cut off by the token limit",
+    ),
+    (
+        ".transcribe",
+        "Send the transcript of an audio file, e.g. .transcribe memo.m4a -- summarize",
+    ),
+    (
+        ".paste-image",
+        "Attach the image in the clipboard to the next message",
+    ),
+    (
+        ".regenerate",
+        "Resend the last message, e.g. .regenerate --temperature 1.2",
+    ),
+    (".edit", "Compose the message in $EDITOR"),
+    (".edit last", "Edit the previous message in $EDITOR"),
     (".role", "Select a role"),
+    (".role create", "Create a role and save it to roles.yaml"),
+    (".role edit", "Edit a role in $EDITOR"),
+    (".role delete", "Delete a role from roles.yaml"),
     (".clear role", "Clear the currently selected role"),
+    (".rag", "Answer with documents retrieved from a rag"),
+    (".clear rag", "Stop retrieving from the rag"),
     (".conversation", "Start a conversation."),
+    (".conversation list", "List the conversations of this run"),
+    (
+        ".conversation switch",
+        "Switch to another conversation, e.g. .conversation switch 2",
+    ),
+    (
+        ".conversation close",
+        "Close a conversation, the current one without an id",
+    ),
     (".clear conversation", "End current conversation."),
+    (".undo", "Remove the last exchange from the conversation"),
+    (
+        ".compact",
+        "Summarize the earlier messages of the conversation",
+    ),
+    (".session save", "Save the conversation as a named session"),
+    (".session load", "Load a named session as the conversation"),
+    (".session list", "List saved sessions"),
+    (
+        ".search",
+        "Search saved messages and sessions, e.g. .search --regex sort_by",
+    ),
+    (
+        ".recall",
+        "Find similar past exchanges, e.g. .recall sorting -- and in reverse?",
+    ),
+    (".export md", "Export the conversation as markdown"),
+    (".export json", "Export the conversation as json"),
+    (".copy", "Copy the last reply to the clipboard"),
+    (".copy code", "Copy the last code block of the reply"),
+    (".source", "Run the commands and prompts of a file"),
     (".history", "Print the history"),
     (".clear history", "Clear the history"),
     (".help", "Print this help message"),
@@ -33,14 +110,22 @@ pub const REPL_COMMANDS: [(&str, &str); 11] = [
 ];
 
 impl Repl {
-    pub fn run(&mut self, client: ChatGptClient, config: SharedConfig) -> Result<()> {
+    pub fn run(&mut self, client: Box<dyn Client>, config: SharedConfig) -> Result<()> {
         let abort = AbortSignal::new();
         let handler = ReplCmdHandler::init(client, config.clone(), abort.clone())?;
-        let prompt = ReplPrompt::new(config);
+        let prompt = ReplPrompt::new(config.clone());
         print_now!("Welcome to aichat {}\n", env!("CARGO_PKG_VERSION"));
         print_now!("Type \".help\" for more information.\n");
         let mut already_ctrlc = false;
-        let handler = Arc::new(handler);
+        let handler = Rc::new(handler);
+        let replrc = Config::replrc_file()?;
+        if replrc.exists() && self.run_script(handler.clone(), &replrc, false)? {
+            return config.lock().on_repl_exit();
+        }
+        self.switch_history(&config)?;
+        let _watcher = watch_config(config.clone())
+            .map_err(|err| warn!("{err:#}, `.reload` to reload it"))
+            .ok();
         loop {
             if abort.aborted_ctrld() {
                 break;
@@ -53,6 +138,8 @@ impl Repl {
                 Ok(Signal::Success(line)) => {
                     already_ctrlc = false;
                     abort.reset();
+                    // history search reads the file
+                    let _ = self.editor.sync_history();
                     match self.handle_line(handler.clone(), line) {
                         Ok(quit) => {
                             if quit {
@@ -64,6 +151,10 @@ impl Repl {
                             print_now!("{}\n\n", err.trim());
                         }
                     }
+                    if let Err(err) = self.switch_history(&config) {
+                        print_now!("{}\n\n", format!("{err:?}").trim());
+                    }
+                    self.refresh_completer(&config);
                 }
                 Ok(Signal::CtrlC) => {
                     abort.set_ctrlc();
@@ -81,10 +172,48 @@ impl Repl {
                 _ => {}
             }
         }
-        Ok(())
+        config.lock().on_repl_exit()
+    }
+
+    /// Run the lines of a file as if typed, returns true if one of them is `.exit`.
+    /// `<config_dir>/replrc` takes only commands, a file run by `.source` takes prompts too
+    /// and each line is echoed.
+    fn run_script(
+        &mut self,
+        handler: Rc<ReplCmdHandler>,
+        path: &Path,
+        source: bool,
+    ) -> Result<bool> {
+        let content =
+            read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let abort = handler.get_abort();
+        for (index, line) in script_lines(&content) {
+            if source {
+                print_now!("〉{line}\n");
+            }
+            abort.reset();
+            let ret = match parse_command(&line) {
+                Some((".source", _)) => Err(anyhow!("Error: Cannot run .source from a file")),
+                None if !source => Err(anyhow!("Error: Not a REPL command")),
+                _ => self.handle_line(handler.clone(), line),
+            };
+            match ret {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(err) => {
+                    let err = format!("{err:?}");
+                    print_now!("{}:{index}: {}\n\n", path.display(), err.trim());
+                }
+            }
+            if abort.aborted() {
+                print_now!("Stopped running {}\n\n", path.display());
+                break;
+            }
+        }
+        Ok(false)
     }
 
-    fn handle_line(&mut self, handler: Arc<ReplCmdHandler>, line: String) -> Result<bool> {
+    fn handle_line(&mut self, handler: Rc<ReplCmdHandler>, line: String) -> Result<bool> {
         let line = clean_multiline_symbols(&line);
         match parse_command(&line) {
             Some((cmd, args)) => match cmd {
@@ -102,34 +231,141 @@ impl Repl {
                         print_now!("\n");
                     }
                     Some("role") => handler.handle(ReplCmd::ClearRole)?,
+                    Some("rag") => handler.handle(ReplCmd::ClearRag)?,
                     Some("conversation") => handler.handle(ReplCmd::EndConversatoin)?,
                     _ => dump_unknown_command(),
                 },
+                ".source" => match args {
+                    Some(path) => return self.run_script(handler, Path::new(path), true),
+                    None => print_now!("Usage: .source <file>\n\n"),
+                },
                 ".history" => {
                     self.editor.print_history()?;
                     print_now!("\n");
                 }
-                ".role" => match args {
-                    Some(name) => handler.handle(ReplCmd::SetRole(name.to_string()))?,
-                    None => print_now!("Usage: .role <name>\n\n"),
+                ".role" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    Some(("create", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::CreateRole(name.trim().to_string()))?
+                    }
+                    Some(("edit", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::EditRole(name.trim().to_string()))?
+                    }
+                    Some(("delete", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::DeleteRole(name.trim().to_string()))?
+                    }
+                    Some(_) => handler.handle(ReplCmd::SetRole(args.unwrap_or_default().into()))?,
+                    None => print_now!("Usage: .role <name>, .role create|edit|delete <name>\n\n"),
+                },
+                ".rag" => match args {
+                    Some(name) => handler.handle(ReplCmd::SetRag(name.to_string()))?,
+                    None => print_now!("Usage: .rag <name>\n\n"),
                 },
-                ".info" => {
-                    handler.handle(ReplCmd::ViewInfo)?;
+                ".info" => match args {
+                    None => handler.handle(ReplCmd::ViewInfo(false))?,
+                    Some("full") => handler.handle(ReplCmd::ViewInfo(true))?,
+                    _ => print_now!("Usage: .info [full]\n\n"),
+                },
+                ".usage" => {
+                    handler.handle(ReplCmd::ViewUsage)?;
+                }
+                ".models" => {
+                    handler.handle(ReplCmd::ListModels)?;
                 }
                 ".set" => {
                     handler.handle(ReplCmd::UpdateConfig(args.unwrap_or_default().to_string()))?
                 }
+                ".settings" => {
+                    handler.handle(ReplCmd::Settings)?;
+                }
+                ".reload" => {
+                    handler.handle(ReplCmd::Reload)?;
+                }
                 ".prompt" => {
                     let text = args.unwrap_or_default().to_string();
                     if text.is_empty() {
-                        print_now!("Usage: .prompt <text>.\n\n");
+                        handler.handle(ReplCmd::ViewPrompt)?;
                     } else {
                         handler.handle(ReplCmd::Prompt(text))?;
                     }
                 }
-                ".conversation" => {
-                    handler.handle(ReplCmd::StartConversation)?;
+                ".conversation" => match parse_conversation_args(args) {
+                    Some(cmd) => handler.handle(cmd)?,
+                    None => {
+                        print_now!("Usage: .conversation [list | switch <id> | close [<id>]]\n\n")
+                    }
+                },
+                ".file" => match args {
+                    Some(args) => {
+                        let (paths, text) = split_files_args(args);
+                        handler.handle(ReplCmd::AttachFiles(paths, text))?;
+                    }
+                    None => print_now!("Usage: .file <path>... [-- <text>]\n\n"),
+                },
+                ".diff" => match parse_diff_args(args) {
+                    Some((staged, text)) => handler.handle(ReplCmd::AttachDiff(staged, text))?,
+                    None => print_now!("Usage: .diff [--staged] [-- <text>]\n\n"),
+                },
+                ".transcribe" => match args {
+                    Some(args) => match split_files_args(args) {
+                        (paths, text) if paths.len() == 1 => {
+                            handler.handle(ReplCmd::Transcribe(paths[0].clone(), text))?
+                        }
+                        _ => print_now!("Usage: .transcribe <file> [-- <text>]\n\n"),
+                    },
+                    None => print_now!("Usage: .transcribe <file> [-- <text>]\n\n"),
+                },
+                ".paste-image" => {
+                    handler.handle(ReplCmd::PasteImage)?;
+                }
+                ".undo" => {
+                    handler.handle(ReplCmd::Undo)?;
+                }
+                ".compact" => {
+                    handler.handle(ReplCmd::Compact)?;
                 }
+                ".continue" => {
+                    handler.handle(ReplCmd::Continue)?;
+                }
+                ".regenerate" => match parse_regenerate_args(args) {
+                    Some(temperature) => handler.handle(ReplCmd::Regenerate(temperature))?,
+                    None => print_now!("Usage: .regenerate [--temperature <value>]\n\n"),
+                },
+                ".edit" => match args {
+                    None => handler.handle(ReplCmd::Edit(false))?,
+                    Some("last") => handler.handle(ReplCmd::Edit(true))?,
+                    _ => print_now!("Usage: .edit [last]\n\n"),
+                },
+                ".export" => match args.and_then(|v| v.split_once(' ')) {
+                    Some((format, path)) if !path.trim().is_empty() => handler
+                        .handle(ReplCmd::Export(format.to_string(), path.trim().to_string()))?,
+                    _ => print_now!("Usage: .export md|json <path>\n\n"),
+                },
+                ".search" => match parse_search_args(args) {
+                    Some((query, regex)) => handler.handle(ReplCmd::Search(query, regex))?,
+                    None => print_now!("Usage: .search [--regex] <query>\n\n"),
+                },
+                ".recall" => match parse_recall_args(args) {
+                    Some((query, text)) => handler.handle(ReplCmd::Recall(query, text))?,
+                    None => print_now!("Usage: .recall <query> [-- <text>]\n\n"),
+                },
+                ".copy" => match args {
+                    None => handler.handle(ReplCmd::CopyReply)?,
+                    Some("code") => handler.handle(ReplCmd::CopyCode)?,
+                    _ => print_now!("Usage: .copy [code]\n\n"),
+                },
+                ".session" => match args.map(|v| v.split_once(' ').unwrap_or((v, ""))) {
+                    Some(("save", name)) => {
+                        let name = Some(name.trim()).filter(|v| !v.is_empty());
+                        handler.handle(ReplCmd::SaveSession(name.map(|v| v.to_string())))?
+                    }
+                    Some(("load", name)) if !name.trim().is_empty() => {
+                        handler.handle(ReplCmd::LoadSession(name.trim().to_string()))?
+                    }
+                    Some(("list", _)) => handler.handle(ReplCmd::ListSessions)?,
+                    _ => print_now!(
+                        "Usage: .session save [name], .session load <name>, .session list\n\n"
+                    ),
+                },
                 _ => dump_unknown_command(),
             },
             None => {
@@ -161,7 +397,7 @@ Press Ctrl+C to abort readline, Ctrl+D to exit the REPL
     );
 }
 
-fn clean_multiline_symbols(line: &str) -> Cow<str> {
+fn clean_multiline_symbols(line: &str) -> Cow<'_, str> {
     let trimed_line = line.trim();
     match trimed_line.chars().next() {
         Some('{') | Some('[') | Some('(') => trimed_line[1..trimed_line.len() - 1].into(),
@@ -169,6 +405,121 @@ fn clean_multiline_symbols(line: &str) -> Cow<str> {
     }
 }
 
+/// Entries of a script with their line numbers. Empty lines and lines starting with `#`
+/// are skipped, a `{ ... }` block spans lines until its brackets balance, as in the
+/// multi-line editing mode.
+fn script_lines(content: &str) -> Vec<(usize, String)> {
+    let mut entries: Vec<(usize, String)> = vec![];
+    let mut block: Option<(usize, String)> = None;
+    for (index, line) in content.lines().enumerate() {
+        if let Some((_, text)) = block.as_mut() {
+            text.push('\n');
+            text.push_str(line);
+        } else {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            block = Some((index + 1, line.to_string()));
+        }
+        if let Some((start, text)) = block.take() {
+            if brackets_balanced(&text) {
+                entries.push((start, text));
+            } else {
+                block = Some((start, text));
+            }
+        }
+    }
+    entries.extend(block);
+    entries
+}
+
+fn brackets_balanced(text: &str) -> bool {
+    let (open, close) = match text.chars().next() {
+        Some('{') => ('{', '}'),
+        Some('(') => ('(', ')'),
+        Some('[') => ('[', ']'),
+        _ => return true,
+    };
+    let mut depth = 0;
+    for c in text.chars() {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+        }
+    }
+    depth <= 0
+}
+
+fn split_files_args(args: &str) -> (Vec<String>, String) {
+    let (paths, text) = match args.split_once(" -- ") {
+        Some((paths, text)) => (paths, text.trim()),
+        None => (args.trim_end_matches(" --"), ""),
+    };
+    let paths = paths.split_whitespace().map(|v| v.to_string()).collect();
+    (paths, text.to_string())
+}
+
+fn parse_conversation_args(args: Option<&str>) -> Option<ReplCmd> {
+    let args: Vec<&str> = args.unwrap_or_default().split_whitespace().collect();
+    let id = |v: &str| v.trim_start_matches('#').parse::<usize>().ok();
+    match args.as_slice() {
+        [] => Some(ReplCmd::StartConversation),
+        ["list"] => Some(ReplCmd::ListConversations),
+        ["switch", value] => id(value).map(ReplCmd::SwitchConversation),
+        ["close"] => Some(ReplCmd::CloseConversation(None)),
+        ["close", value] => id(value).map(|v| ReplCmd::CloseConversation(Some(v))),
+        _ => None,
+    }
+}
+
+/// `.diff [--staged] [-- <text>]`, None when the args are not understood
+fn parse_diff_args(args: Option<&str>) -> Option<(bool, String)> {
+    let (options, text) = split_files_args(&format!(" {}", args.unwrap_or_default()));
+    match options.as_slice() {
+        [] => Some((false, text)),
+        [option] if option == "--staged" => Some((true, text)),
+        _ => None,
+    }
+}
+
+fn parse_search_args(args: Option<&str>) -> Option<(String, bool)> {
+    let args = args?.trim();
+    let (query, regex) = match args.strip_prefix("--regex") {
+        Some(query) if query.is_empty() || query.starts_with(' ') => (query.trim(), true),
+        _ => (args, false),
+    };
+    if query.is_empty() {
+        return None;
+    }
+    Some((query.to_string(), regex))
+}
+
+fn parse_recall_args(args: Option<&str>) -> Option<(String, Option<String>)> {
+    let args = format!(" {} ", args?);
+    let (query, text) = match args.split_once(" -- ") {
+        Some((query, text)) => (query, Some(text.trim()).filter(|v| !v.is_empty())),
+        None => (args.as_str(), None),
+    };
+    let query = query.trim();
+    if query.is_empty() {
+        return None;
+    }
+    Some((query.to_string(), text.map(|v| v.to_string())))
+}
+
+fn parse_regenerate_args(args: Option<&str>) -> Option<Option<f64>> {
+    match args
+        .map(|v| v.split_whitespace().collect::<Vec<_>>())
+        .as_deref()
+    {
+        None => Some(None),
+        Some(["--temperature", value]) => value.parse().ok().map(Some),
+        _ => None,
+    }
+}
+
 fn parse_command(line: &str) -> Option<(&str, Option<&str>)> {
     let mut trimed_line = line.trim_start();
     if trimed_line.starts_with('.') {
@@ -212,4 +563,100 @@ mod tests {
             Some((".prompt", Some("abc")))
         );
     }
+
+    #[test]
+    fn test_parse_regenerate_args() {
+        assert_eq!(parse_regenerate_args(None), Some(None));
+        assert_eq!(
+            parse_regenerate_args(Some("--temperature 1.2")),
+            Some(Some(1.2))
+        );
+        assert_eq!(parse_regenerate_args(Some("--temperature")), None);
+    }
+
+    #[test]
+    fn test_split_files_args() {
+        assert_eq!(
+            split_files_args("a.rs b.rs -- explain these"),
+            (vec!["a.rs".into(), "b.rs".into()], "explain these".into())
+        );
+        assert_eq!(split_files_args("a.rs"), (vec!["a.rs".into()], "".into()));
+    }
+
+    #[test]
+    fn test_parse_conversation_args() {
+        assert!(matches!(
+            parse_conversation_args(None),
+            Some(ReplCmd::StartConversation)
+        ));
+        assert!(matches!(
+            parse_conversation_args(Some("switch #2")),
+            Some(ReplCmd::SwitchConversation(2))
+        ));
+        assert!(matches!(
+            parse_conversation_args(Some("close")),
+            Some(ReplCmd::CloseConversation(None))
+        ));
+        assert!(parse_conversation_args(Some("switch two")).is_none());
+    }
+
+    #[test]
+    fn test_parse_diff_args() {
+        assert_eq!(parse_diff_args(None), Some((false, "".into())));
+        assert_eq!(
+            parse_diff_args(Some("--staged -- review this")),
+            Some((true, "review this".into()))
+        );
+        assert_eq!(
+            parse_diff_args(Some("-- explain")),
+            Some((false, "explain".into()))
+        );
+        assert_eq!(parse_diff_args(Some("--cached")), None);
+    }
+
+    #[test]
+    fn test_parse_recall_args() {
+        assert_eq!(parse_recall_args(None), None);
+        assert_eq!(parse_recall_args(Some("-- text")), None);
+        assert_eq!(
+            parse_recall_args(Some("sorting a vec")),
+            Some(("sorting a vec".into(), None))
+        );
+        assert_eq!(
+            parse_recall_args(Some("sorting -- and in reverse?")),
+            Some(("sorting".into(), Some("and in reverse?".into())))
+        );
+    }
+
+    #[test]
+    fn test_parse_search_args() {
+        assert_eq!(parse_search_args(None), None);
+        assert_eq!(parse_search_args(Some("--regex")), None);
+        assert_eq!(
+            parse_search_args(Some("sort vec")),
+            Some(("sort vec".into(), false))
+        );
+        assert_eq!(
+            parse_search_args(Some("--regex sort_by\\(")),
+            Some(("sort_by\\(".into(), true))
+        );
+        assert_eq!(
+            parse_search_args(Some("--regexes")),
+            Some(("--regexes".into(), false))
+        );
+    }
+
+    #[test]
+    fn test_script_lines() {
+        let content = "# setup\n.role coder\n\n  .set temperature 0.2  \n{ explain\nfn main() {}\n}\nthanks\n";
+        assert_eq!(
+            script_lines(content),
+            vec![
+                (2, ".role coder".into()),
+                (4, ".set temperature 0.2".into()),
+                (5, "{ explain\nfn main() {}\n}".into()),
+                (8, "thanks".into()),
+            ]
+        );
+    }
 }
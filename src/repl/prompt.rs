@@ -13,7 +13,7 @@ impl ReplPrompt {
 }
 
 impl Prompt for ReplPrompt {
-    fn render_prompt_left(&self) -> Cow<str> {
+    fn render_prompt_left(&self) -> Cow<'_, str> {
         let config = self.0.lock();
         if let Some(role) = config.role.as_ref() {
             role.name.to_string().into()
@@ -22,16 +22,21 @@ impl Prompt for ReplPrompt {
         }
     }
 
-    fn render_prompt_right(&self) -> Cow<str> {
+    fn render_prompt_right(&self) -> Cow<'_, str> {
         let config = self.0.lock();
         if let Some(conversation) = config.conversation.as_ref() {
-            conversation.reamind_tokens().to_string().into()
+            let tokens = conversation.reamind_tokens(config.get_max_input_tokens());
+            if config.other_conversations.is_empty() {
+                tokens.to_string().into()
+            } else {
+                format!("#{} {tokens}", config.conversation_id).into()
+            }
         } else {
             Cow::Borrowed("")
         }
     }
 
-    fn render_prompt_indicator(&self, _prompt_mode: reedline::PromptEditMode) -> Cow<str> {
+    fn render_prompt_indicator(&self, _prompt_mode: reedline::PromptEditMode) -> Cow<'_, str> {
         let config = self.0.lock();
         if config.conversation.is_some() {
             Cow::Borrowed("＄")
@@ -40,14 +45,14 @@ impl Prompt for ReplPrompt {
         }
     }
 
-    fn render_prompt_multiline_indicator(&self) -> Cow<str> {
+    fn render_prompt_multiline_indicator(&self) -> Cow<'_, str> {
         Cow::Borrowed("")
     }
 
     fn render_prompt_history_search_indicator(
         &self,
         history_search: PromptHistorySearch,
-    ) -> Cow<str> {
+    ) -> Cow<'_, str> {
         let prefix = match history_search.status {
             PromptHistorySearchStatus::Passing => "",
             PromptHistorySearchStatus::Failing => "failing ",
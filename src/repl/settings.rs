@@ -0,0 +1,152 @@
+use crate::config::SharedConfig;
+use crate::print_now;
+
+use anyhow::Result;
+use inquire::validator::Validation;
+use inquire::{Confirm, InquireError, Password, PasswordDisplayMode, Select, Text};
+
+/// How the value of a setting is asked for
+#[derive(Debug, PartialEq)]
+enum Widget {
+    Toggle,
+    Choice(Vec<String>),
+    /// Number within the range, empty to unset
+    Number(f64, f64),
+    /// Whole number, empty to unset
+    Integer,
+    Text,
+    Secret,
+}
+
+/// Pick settings from a list and change them until Esc
+pub fn pick_settings(config: &SharedConfig, models: &[String]) -> Result<()> {
+    let mut cursor = 0;
+    loop {
+        let settings = config.lock().settings();
+        let options: Vec<String> = settings
+            .iter()
+            .map(|(key, value)| format!("{key:<20}{value}"))
+            .collect();
+        let answer = Select::new("Setting:", options)
+            .with_starting_cursor(cursor)
+            .with_page_size(15)
+            .with_help_message("Enter to change, Esc to leave")
+            .raw_prompt();
+        cursor = match answer {
+            Ok(v) => v.index,
+            Err(InquireError::OperationCanceled | InquireError::OperationInterrupted) => {
+                return Ok(())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let (key, current) = &settings[cursor];
+        let value = match ask_value(key, current, widget(key, models)) {
+            Ok(v) => v,
+            Err(InquireError::OperationCanceled | InquireError::OperationInterrupted) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if let Err(err) = config.lock().update(&format!("{key} {value}")) {
+            print_now!("{err}\n");
+        }
+    }
+}
+
+fn widget(key: &str, models: &[String]) -> Widget {
+    let choice = |values: &[&str]| Widget::Choice(values.iter().map(|v| v.to_string()).collect());
+    match key {
+        "save" | "cache" | "stream" | "speak" | "highlight" | "tools" | "dry_run" => Widget::Toggle,
+        "save_format" => choice(&["markdown", "jsonl"]),
+        "budget_action" => choice(&["warn", "refuse"]),
+        "context_strategy" => choice(&["error", "truncate", "summarize"]),
+        "light_theme" => choice(&["true", "false", "null"]),
+        "model" if !models.is_empty() => Widget::Choice(models.to_vec()),
+        "temperature" => Widget::Number(0.0, 2.0),
+        "top_p" => Widget::Number(0.0, 1.0),
+        "presence_penalty" | "frequency_penalty" => Widget::Number(-2.0, 2.0),
+        "monthly_budget" => Widget::Number(0.0, f64::MAX),
+        "max_tokens" | "max_input_tokens" | "seed" => Widget::Integer,
+        "api_key" => Widget::Secret,
+        _ => Widget::Text,
+    }
+}
+
+/// The new value as `.set` takes it, `null` to unset
+fn ask_value(key: &str, current: &str, widget: Widget) -> Result<String, InquireError> {
+    let message = format!("{key}:");
+    let current = if current == "-" { "" } else { current };
+    let value = match widget {
+        Widget::Toggle => Confirm::new(&message)
+            .with_default(current == "true")
+            .prompt()?
+            .to_string(),
+        Widget::Choice(values) => {
+            let cursor = values.iter().position(|v| v == current).unwrap_or(0);
+            Select::new(&message, values)
+                .with_starting_cursor(cursor)
+                .prompt()?
+        }
+        Widget::Number(min, max) => Text::new(&message)
+            .with_initial_value(current)
+            .with_help_message("Empty to unset")
+            .with_validator(move |input: &str| Ok(validate_number(input, min, max)))
+            .prompt()?,
+        Widget::Integer => Text::new(&message)
+            .with_initial_value(current)
+            .with_help_message("Empty to unset")
+            .with_validator(|input: &str| {
+                Ok(
+                    match input.trim().is_empty() || input.trim().parse::<i64>().is_ok() {
+                        true => Validation::Valid,
+                        false => Validation::Invalid("Expect a whole number".into()),
+                    },
+                )
+            })
+            .prompt()?,
+        Widget::Text => Text::new(&message)
+            .with_initial_value(current)
+            .with_help_message("Empty to unset")
+            .prompt()?,
+        Widget::Secret => Password::new(&message)
+            .with_display_mode(PasswordDisplayMode::Masked)
+            .without_confirmation()
+            .prompt()?,
+    };
+    let value = value.trim();
+    Ok(if value.is_empty() {
+        "null".into()
+    } else {
+        value.to_string()
+    })
+}
+
+fn validate_number(input: &str, min: f64, max: f64) -> Validation {
+    let input = input.trim();
+    if input.is_empty() {
+        return Validation::Valid;
+    }
+    match input.parse::<f64>() {
+        Ok(value) if (min..=max).contains(&value) => Validation::Valid,
+        _ if max == f64::MAX => Validation::Invalid(format!("Expect a number from {min}").into()),
+        _ => Validation::Invalid(format!("Expect a number from {min} to {max}").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_widget() {
+        let models = vec!["gpt-4o".to_string()];
+        assert_eq!(widget("stream", &models), Widget::Toggle);
+        assert_eq!(widget("model", &models), Widget::Choice(models.clone()));
+        assert_eq!(widget("model", &[]), Widget::Text);
+        assert_eq!(widget("temperature", &models), Widget::Number(0.0, 2.0));
+        assert_eq!(validate_number("1.5", 0.0, 2.0), Validation::Valid);
+        assert_eq!(validate_number("", 0.0, 2.0), Validation::Valid);
+        assert_eq!(
+            validate_number("3", 0.0, 2.0),
+            Validation::Invalid("Expect a number from 0 to 2".into())
+        );
+    }
+}
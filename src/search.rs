@@ -0,0 +1,277 @@
+use crate::config::{Config, Conversation, MessageRole};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local, SecondsFormat};
+use fancy_regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs::read_to_string;
+
+/// Lines of each side of an exchange shown in the results
+const SHOWN_LINES: usize = 3;
+/// Chars of a line shown in the results
+const LINE_WIDTH: usize = 120;
+
+/// A question and its reply from the saved messages or a session
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Exchange {
+    pub time: String,
+    /// `messages.md`, `messages.jsonl` or the name of the session
+    pub source: String,
+    pub role: Option<String>,
+    pub input: String,
+    pub output: String,
+}
+
+pub enum Matcher {
+    /// Every word appears, ignoring case
+    Words(Vec<String>),
+    Regex(Regex),
+}
+
+impl Matcher {
+    pub fn new(query: &str, regex: bool) -> Result<Self> {
+        if regex {
+            let regex = Regex::new(query).map_err(|err| anyhow!("Invalid regex, {err}"))?;
+            Ok(Self::Regex(regex))
+        } else {
+            Ok(Self::Words(
+                query.split_whitespace().map(|v| v.to_lowercase()).collect(),
+            ))
+        }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            Self::Words(words) => {
+                let text = text.to_lowercase();
+                words.iter().all(|v| text.contains(v))
+            }
+            Self::Regex(regex) => regex.is_match(text).unwrap_or(false),
+        }
+    }
+
+    /// Whether the line has any of the words, or matches the regex
+    fn is_line_match(&self, line: &str) -> bool {
+        match self {
+            Self::Words(words) => {
+                let line = line.to_lowercase();
+                words.iter().any(|v| line.contains(v))
+            }
+            Self::Regex(regex) => regex.is_match(line).unwrap_or(false),
+        }
+    }
+}
+
+/// Exchanges of the saved messages and sessions that match, oldest first
+pub fn search(config: &Config, matcher: &Matcher) -> Result<Vec<Exchange>> {
+    let hits = load_exchanges(config)?
+        .into_iter()
+        .filter(|v| matcher.is_match(&format!("{}\n{}", v.input, v.output)))
+        .collect();
+    Ok(hits)
+}
+
+/// Exchanges of the saved messages and sessions, oldest first
+pub fn load_exchanges(config: &Config) -> Result<Vec<Exchange>> {
+    let mut exchanges = vec![];
+    let path = Config::messages_file()?;
+    if path.exists() {
+        let content =
+            read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        exchanges.extend(parse_messages(&content));
+    }
+    let path = Config::messages_jsonl_file()?;
+    if path.exists() {
+        let content =
+            read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        exchanges.extend(parse_messages_jsonl(&content));
+    }
+    for name in config.list_sessions()? {
+        let path = Config::session_file(&name)?;
+        let conversation = read_to_string(&path)
+            .ok()
+            .and_then(|v| serde_yaml::from_str::<Conversation>(&v).ok());
+        if let Some(conversation) = conversation {
+            let time: DateTime<Local> = path.metadata()?.modified()?.into();
+            let time = time.to_rfc3339_opts(SecondsFormat::Secs, false);
+            exchanges.extend(session_exchanges(&name, &time, &conversation));
+        }
+    }
+    // with `save` on, an exchange of a session is in the saved messages too
+    let mut seen = HashSet::new();
+    exchanges.retain(|v| seen.insert((v.input.clone(), v.output.clone())));
+    exchanges.sort_by(|a, b| a.time.cmp(&b.time));
+    Ok(exchanges)
+}
+
+/// The exchanges with the matching lines of each side, or the first lines without a matcher
+pub fn render_hits(hits: &[Exchange], matcher: Option<&Matcher>) -> String {
+    let mut output = String::new();
+    for hit in hits {
+        let role = hit
+            .role
+            .as_ref()
+            .map(|v| format!(" ({v})"))
+            .unwrap_or_default();
+        output.push_str(&format!("# [{}] {}{role}\n", hit.time, hit.source));
+        for (name, text) in [("user", &hit.input), ("assistant", &hit.output)] {
+            for line in shown_lines(text, matcher) {
+                output.push_str(&format!("{name}: {line}\n"));
+            }
+        }
+        output.push('\n');
+    }
+    output
+}
+
+fn shown_lines(text: &str, matcher: Option<&Matcher>) -> Vec<String> {
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .collect();
+    let mut shown: Vec<&str> = lines
+        .iter()
+        .filter(|v| matcher.is_some_and(|matcher| matcher.is_line_match(v)))
+        .take(SHOWN_LINES)
+        .copied()
+        .collect();
+    if shown.is_empty() {
+        shown.extend(lines.first());
+    }
+    shown
+        .into_iter()
+        .map(|line| {
+            if line.chars().count() > LINE_WIDTH {
+                let line: String = line.chars().take(LINE_WIDTH - 1).collect();
+                format!("{line}…")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Exchanges written by `Config::save_message`
+fn parse_messages(content: &str) -> Vec<Exchange> {
+    let mut exchanges = vec![];
+    for block in content.split("# CHAT:[").skip(1) {
+        let (header, body) = block.split_once('\n').unwrap_or((block, ""));
+        let (time, role) = match header.split_once(']') {
+            Some((time, rest)) => {
+                let role = rest.trim().trim_start_matches('(').trim_end_matches(')');
+                (time, Some(role).filter(|v| !v.is_empty()))
+            }
+            None => continue,
+        };
+        let mut parts = body.split("\n--------\n");
+        let (input, output) = match (parts.next(), parts.next()) {
+            (Some(input), Some(output)) => (input, output),
+            _ => continue,
+        };
+        exchanges.push(Exchange {
+            time: time.to_string(),
+            source: "messages.md".into(),
+            role: role.map(|v| v.to_string()),
+            input: input.to_string(),
+            output: output.to_string(),
+        });
+    }
+    exchanges
+}
+
+/// Exchanges written by `Config::save_message` with `save_format: jsonl`
+fn parse_messages_jsonl(content: &str) -> Vec<Exchange> {
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter_map(|value| {
+            let field = |name: &str| value[name].as_str().map(|v| v.to_string());
+            Some(Exchange {
+                time: field("timestamp")?,
+                source: "messages.jsonl".into(),
+                role: field("role"),
+                input: field("input")?,
+                output: field("output")?,
+            })
+        })
+        .collect()
+}
+
+fn session_exchanges(name: &str, time: &str, conversation: &Conversation) -> Vec<Exchange> {
+    let role = conversation
+        .role
+        .as_ref()
+        .filter(|v| !v.is_temp())
+        .map(|v| v.name.clone());
+    let mut exchanges = vec![];
+    let mut input: Option<&str> = None;
+    for message in &conversation.messages {
+        match message.role {
+            MessageRole::User => input = Some(&message.content),
+            MessageRole::Assistant => {
+                if let Some(input) = input.take() {
+                    exchanges.push(Exchange {
+                        time: time.to_string(),
+                        source: name.to_string(),
+                        role: role.clone(),
+                        input: input.to_string(),
+                        output: message.content.clone(),
+                    });
+                }
+            }
+            MessageRole::System => {}
+        }
+    }
+    exchanges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGES: &str = "# CHAT:[2024-05-01T10:00:00+08:00]\nhow to sort a vec\n--------\nUse `sort`\n--------\n\n\
+# CHAT:[2024-05-02T10:00:00+08:00] (coder)\nreverse a string\n--------\ns.chars().rev()\n--------\n\n";
+
+    #[test]
+    fn test_parse_messages() {
+        let exchanges = parse_messages(MESSAGES);
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(exchanges[0].time, "2024-05-01T10:00:00+08:00");
+        assert_eq!(exchanges[0].role, None);
+        assert_eq!(exchanges[0].input, "how to sort a vec");
+        assert_eq!(exchanges[0].output, "Use `sort`");
+        assert_eq!(exchanges[1].role.as_deref(), Some("coder"));
+    }
+
+    #[test]
+    fn test_parse_messages_jsonl() {
+        let content = r#"{"timestamp":"2024-05-01T10:00:00+08:00","role":"coder","prompt":null,"model":"gpt-4o","input":"how to sort a vec","output":"Use `sort`","input_tokens":5,"output_tokens":3}
+not json
+"#;
+        let exchanges = parse_messages_jsonl(content);
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].role.as_deref(), Some("coder"));
+        assert_eq!(exchanges[0].input, "how to sort a vec");
+        assert_eq!(exchanges[0].output, "Use `sort`");
+    }
+
+    #[test]
+    fn test_render_hits() {
+        let exchanges = parse_messages(MESSAGES);
+        let matcher = Matcher::new("SORT vec", false).unwrap();
+        let hits: Vec<Exchange> = exchanges
+            .into_iter()
+            .filter(|v| matcher.is_match(&format!("{}\n{}", v.input, v.output)))
+            .collect();
+        assert_eq!(
+            render_hits(&hits, Some(&matcher)),
+            "# [2024-05-01T10:00:00+08:00] messages.md\nuser: how to sort a vec\nassistant: Use `sort`\n\n"
+        );
+        let matcher = Matcher::new(r"chars\(\)", true).unwrap();
+        assert!(matcher.is_match("s.chars().rev()"));
+        assert!(!matcher.is_match("s.chars"));
+    }
+}
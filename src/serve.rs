@@ -0,0 +1,483 @@
+use crate::client::init_client;
+use crate::config::{Config, Message, MessageRole, SharedConfig, Usage};
+use crate::repl::{AbortSignal, ReplyStreamEvent, ReplyStreamHandler};
+
+use anyhow::{Context, Result};
+use futures_util::stream;
+use hyper::header::{CONTENT_TYPE, HOST, ORIGIN};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::info;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+/// Chat page served at `/`, talks to the api below over SSE
+const PLAYGROUND: &str = include_str!("../assets/playground.html");
+
+/// Body of `POST /v1/chat/completions`, fields aichat does not support are ignored
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
+    /// A role name to answer with that role, otherwise a model name
+    model: Option<String>,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f64>,
+    max_tokens: Option<usize>,
+    top_p: Option<f64>,
+    presence_penalty: Option<f64>,
+    frequency_penalty: Option<f64>,
+    seed: Option<i64>,
+    stop: Option<Stop>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessage {
+    role: MessageRole,
+    content: Content,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    /// Only the text parts are kept
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentPart {
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Serve an OpenAI-compatible api until Ctrl-C.
+/// Requests may run local tools only with `allow_tools`.
+pub fn run(config: SharedConfig, address: &str, allow_tools: bool) -> Result<()> {
+    let addr: SocketAddr = address
+        .parse()
+        .with_context(|| format!("Invalid address {address}"))?;
+    if allow_tools {
+        // started once here, the copies of the config for each request share them
+        config.lock().get_tools()?;
+    } else {
+        config.lock().tools = false;
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to init tokio")?;
+    runtime.block_on(async {
+        let make_service = make_service_fn(move |_| {
+            let config = config.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(config.clone(), addr, req))) }
+        });
+        let server = Server::try_bind(&addr)
+            .with_context(|| format!("Failed to bind {addr}"))?
+            .serve(make_service);
+        info!("Listening on http://{addr}");
+        println!("Listening on http://{addr}, playground at http://{addr}/");
+        server
+            .with_graceful_shutdown(async {
+                let _ = tokio::signal::ctrl_c().await;
+            })
+            .await
+            .with_context(|| "Server error")
+    })
+}
+
+async fn handle(
+    config: SharedConfig,
+    addr: SocketAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let start = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let res = match (&method, path.as_str()) {
+        // a page of another site resolving to this address, i.e. dns rebinding
+        _ if !is_local_host(&req, &addr) => Ok(error_response(
+            StatusCode::FORBIDDEN,
+            "Host is not the address aichat listens on",
+        )),
+        // a web page must not make aichat send requests, only the playground may
+        _ if is_cross_origin(&req) => Ok(error_response(
+            StatusCode::FORBIDDEN,
+            "Cross-origin requests are not allowed",
+        )),
+        (&Method::POST, _) if !is_json(&req) => Ok(error_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Expect Content-Type: application/json",
+        )),
+        (&Method::POST, "/v1/chat/completions") => chat_completions(config, req).await,
+        (&Method::GET, "/v1/models") => list_models(config).await,
+        (&Method::GET, "/" | "/playground") => Ok(Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(PLAYGROUND))
+            .unwrap_or_default()),
+        _ => Ok(error_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let res = res.unwrap_or_else(|err| {
+        error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("{err:#}"))
+    });
+    info!(
+        "{method} {path} {} {}ms",
+        res.status().as_u16(),
+        start.elapsed().as_millis()
+    );
+    Ok(res)
+}
+
+async fn list_models(config: SharedConfig) -> Result<Response<Body>> {
+    let (models, roles) = tokio::task::spawn_blocking(move || {
+        let roles: Vec<String> = config
+            .lock()
+            .roles
+            .iter()
+            .map(|v| v.name.trim_matches('%').to_string())
+            .collect();
+        let client = init_client(config)?;
+        Ok::<_, anyhow::Error>((client.models(), roles))
+    })
+    .await??;
+    let data: Vec<Value> = models
+        .iter()
+        .map(|v| json!({"id": v, "object": "model", "owned_by": "aichat"}))
+        .chain(
+            roles
+                .iter()
+                .map(|v| json!({"id": v, "object": "model", "owned_by": "aichat-role"})),
+        )
+        .collect();
+    Ok(json_response(
+        StatusCode::OK,
+        &json!({"object": "list", "data": data}),
+    ))
+}
+
+async fn chat_completions(config: SharedConfig, req: Request<Body>) -> Result<Response<Body>> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let chat_req: ChatRequest = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(err) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid request, {err}"),
+            ))
+        }
+    };
+    let stream = chat_req.stream;
+    let (config, input) = match prepare_config(&config.lock(), chat_req) {
+        Ok(v) => v,
+        Err(err) => return Ok(error_response(StatusCode::BAD_REQUEST, &format!("{err:#}"))),
+    };
+    let config = Arc::new(Mutex::new(config));
+    if stream {
+        return Ok(chat_completions_streaming(config, input));
+    }
+    let (model, output, usage, finish_reason) = tokio::task::spawn_blocking(move || {
+        let client = init_client(config.clone())?;
+        let output = client.send_message(&input)?;
+        let model = client.get_model();
+        let config = config.lock();
+        config.save_message(&input, &output)?;
+        let finish_reason = finish_reason(config.finish_reason.as_deref());
+        Ok::<_, anyhow::Error>((model, output, config.usage.clone(), finish_reason))
+    })
+    .await??;
+    let value = json!({
+        "id": completion_id(),
+        "object": "chat.completion",
+        "created": chrono::Utc::now().timestamp(),
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": output},
+            "finish_reason": finish_reason,
+        }],
+        "usage": usage_json(&usage),
+    });
+    Ok(json_response(StatusCode::OK, &value))
+}
+
+/// Relay the reply as server-sent events in the shape of OpenAI chunks
+fn chat_completions_streaming(config: SharedConfig, input: String) -> Response<Body> {
+    let (tx, rx) = mpsc::channel::<String>(64);
+    tokio::task::spawn_blocking(move || {
+        let ret = (|| {
+            let client = init_client(config.clone())?;
+            let chunk = Chunk {
+                id: completion_id(),
+                created: chrono::Utc::now().timestamp(),
+                model: client.get_model(),
+            };
+            let (event_tx, event_rx) = crossbeam::channel::unbounded();
+            let abort = AbortSignal::new();
+            let forward = {
+                let tx = tx.clone();
+                let abort = abort.clone();
+                let chunk = chunk.clone();
+                std::thread::spawn(move || {
+                    for event in event_rx {
+                        if let ReplyStreamEvent::Text(text) = event {
+                            let data = chunk.build(json!({"content": text}), Value::Null);
+                            // the api client went away
+                            if tx.blocking_send(data).is_err() {
+                                abort.set_ctrlc();
+                            }
+                        }
+                    }
+                })
+            };
+            let _ = tx.blocking_send(chunk.build(json!({"role": "assistant"}), Value::Null));
+            let mut handler = ReplyStreamHandler::new(Some(event_tx), false, abort);
+            let ret = client.send_message_streaming(&input, &mut handler);
+            let output = handler.get_buffer().to_string();
+            // closes the channel so that forwarding ends
+            drop(handler);
+            let _ = forward.join();
+            ret?;
+            let config = config.lock();
+            config.save_message(&input, &output)?;
+            let finish_reason = finish_reason(config.finish_reason.as_deref());
+            Ok::<_, anyhow::Error>(chunk.build(json!({}), json!(finish_reason)))
+        })();
+        let data = match ret {
+            Ok(data) => data,
+            Err(err) => format!("data: {}\n\n", error_json(&format!("{err:#}"))),
+        };
+        let _ = tx.blocking_send(data);
+        let _ = tx.blocking_send("data: [DONE]\n\n".into());
+    });
+    let body = Body::wrap_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|data| (Ok::<_, Infallible>(data), rx))
+    }));
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap_or_default()
+}
+
+/// Fields shared by the chunks of one streamed reply
+#[derive(Debug, Clone)]
+struct Chunk {
+    id: String,
+    created: i64,
+    model: String,
+}
+
+impl Chunk {
+    fn build(&self, delta: Value, finish_reason: Value) -> String {
+        let value = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        });
+        format!("data: {value}\n\n")
+    }
+}
+
+/// A copy of the config for one request, with its role and parameters applied
+fn prepare_config(base: &Config, chat_req: ChatRequest) -> Result<(Config, String)> {
+    let mut config = base.clone();
+    config.usage = Usage::default();
+    if let Some(name) = chat_req.model.filter(|v| !v.is_empty()) {
+        match config.find_role(&name) {
+            Some(role) => config.role = Some(role),
+            None => config.model = Some(name),
+        }
+    }
+    let messages = chat_req
+        .messages
+        .into_iter()
+        .map(|v| {
+            let content = match v.content {
+                Content::Text(text) => text,
+                Content::Parts(parts) => parts
+                    .into_iter()
+                    .filter_map(|v| v.text)
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+            };
+            Message {
+                role: v.role,
+                content,
+                images: vec![],
+            }
+        })
+        .collect();
+    let stop = chat_req.stop.map(|v| match v {
+        Stop::One(v) => vec![v],
+        Stop::Many(v) => v,
+    });
+    // role settings take precedence over the config, so the request overrides both
+    if let Some(role) = config.role.as_mut() {
+        role.temperature = chat_req.temperature.or(role.temperature);
+        role.max_tokens = chat_req.max_tokens.or(role.max_tokens);
+        role.top_p = chat_req.top_p.or(role.top_p);
+        role.presence_penalty = chat_req.presence_penalty.or(role.presence_penalty);
+        role.frequency_penalty = chat_req.frequency_penalty.or(role.frequency_penalty);
+        role.stop = stop.clone().or(role.stop.take());
+    }
+    config.temperature = chat_req.temperature.or(config.temperature);
+    config.max_tokens = chat_req.max_tokens.or(config.max_tokens);
+    config.top_p = chat_req.top_p.or(config.top_p);
+    config.presence_penalty = chat_req.presence_penalty.or(config.presence_penalty);
+    config.frequency_penalty = chat_req.frequency_penalty.or(config.frequency_penalty);
+    config.stop = stop.or(config.stop.take());
+    config.seed = chat_req.seed.or(config.seed);
+    let input = config.set_api_messages(messages)?;
+    Ok((config, input))
+}
+
+/// The finish reason recorded by the client in the terms of OpenAI
+fn finish_reason(reason: Option<&str>) -> &'static str {
+    match reason {
+        Some("length" | "max_tokens" | "MAX_TOKENS") => "length",
+        Some("content_filter" | "SAFETY" | "RECITATION") => "content_filter",
+        _ => "stop",
+    }
+}
+
+/// Sent by browsers, the playground has the origin of the server itself
+fn is_cross_origin(req: &Request<Body>) -> bool {
+    let origin = match req.headers().get(ORIGIN) {
+        Some(v) => v.to_str().unwrap_or_default(),
+        None => return false,
+    };
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    origin != format!("http://{host}")
+}
+
+/// The `Host` names the bound address, so the origin check can be trusted
+fn is_local_host(req: &Request<Body>, addr: &SocketAddr) -> bool {
+    let host = match req.headers().get(HOST).and_then(|v| v.to_str().ok()) {
+        Some(v) => v,
+        None => return false,
+    };
+    let port = addr.port();
+    host == addr.to_string()
+        || host == format!("127.0.0.1:{port}")
+        || host == format!("localhost:{port}")
+}
+
+fn is_json(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"))
+}
+
+fn completion_id() -> String {
+    format!("chatcmpl-{}", chrono::Utc::now().timestamp_nanos())
+}
+
+fn usage_json(usage: &Usage) -> Value {
+    json!({
+        "prompt_tokens": usage.prompt_tokens,
+        "completion_tokens": usage.completion_tokens,
+        "total_tokens": usage.prompt_tokens + usage.completion_tokens,
+    })
+}
+
+fn error_json(message: &str) -> Value {
+    json!({"error": {"message": message, "type": "aichat_error"}})
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &error_json(message))
+}
+
+fn json_response(status: StatusCode, value: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap_or_else(|_| Response::new(Body::empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_config() {
+        let mut base: Config = serde_yaml::from_str("{temperature: 0.5}").unwrap();
+        base.roles = serde_yaml::from_str("[{name: calc, prompt: You are a calculator}]").unwrap();
+        let chat_req: ChatRequest = serde_json::from_value(json!({
+            "model": "calc",
+            "messages": [{"role": "user", "content": [{"type": "text", "text": "1 + 1 = ?"}]}],
+            "temperature": 0.1,
+            "stop": "\n",
+        }))
+        .unwrap();
+        let (config, input) = prepare_config(&base, chat_req).unwrap();
+        assert_eq!(input, "1 + 1 = ?");
+        assert_eq!(config.role.as_ref().unwrap().name, "calc");
+        assert_eq!(config.get_temperature(), Some(0.1));
+        assert_eq!(config.get_stop(), Some(vec!["\n".to_string()]));
+
+        let chat_req: ChatRequest = serde_json::from_value(json!({
+            "model": "gpt-4",
+            "messages": [{"role": "user", "content": "hi"}],
+        }))
+        .unwrap();
+        let (config, _) = prepare_config(&base, chat_req).unwrap();
+        assert!(config.role.is_none());
+        assert_eq!(config.get_model(), Some("gpt-4".into()));
+        assert_eq!(config.get_temperature(), Some(0.5));
+    }
+
+    #[test]
+    fn test_request_checks() {
+        let req = |origin: Option<&str>| {
+            let mut builder = Request::builder()
+                .header(HOST, "127.0.0.1:8000")
+                .header(CONTENT_TYPE, "application/json");
+            if let Some(origin) = origin {
+                builder = builder.header(ORIGIN, origin);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        assert!(!is_cross_origin(&req(None)));
+        assert!(!is_cross_origin(&req(Some("http://127.0.0.1:8000"))));
+        assert!(is_cross_origin(&req(Some("https://evil.example"))));
+        let addr: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        assert!(is_local_host(&req(None), &addr));
+        let rebound = Request::builder()
+            .header(HOST, "evil.example:8000")
+            .header(ORIGIN, "http://evil.example:8000")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!is_cross_origin(&rebound));
+        assert!(!is_local_host(&rebound, &addr));
+        let localhost = Request::builder()
+            .header(HOST, "localhost:8000")
+            .body(Body::empty())
+            .unwrap();
+        assert!(is_local_host(&localhost, &addr));
+        assert!(is_json(&req(None)));
+        assert_eq!(finish_reason(Some("MAX_TOKENS")), "length");
+        assert_eq!(finish_reason(Some("end_turn")), "stop");
+        assert_eq!(finish_reason(None), "stop");
+    }
+}
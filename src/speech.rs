@@ -0,0 +1,199 @@
+use crate::client::Client;
+
+use anyhow::{bail, Context, Result};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Read the reply aloud, through `speak_command`, the client's speech endpoint or a local engine
+pub fn speak(client: &dyn Client, text: &str) -> Result<()> {
+    let text = speakable_text(text);
+    if text.is_empty() {
+        return Ok(());
+    }
+    let speak_command = client.get_config().lock().speak_command.clone();
+    if let Some(command) = speak_command {
+        let (program, args) = split_command(&command);
+        if !pipe_to(program, &args, &text)? {
+            bail!("Error: Failed to run `{command}`");
+        }
+        return Ok(());
+    }
+    match client.speech(&text)? {
+        Some(audio) => play(&audio),
+        None => say(&text),
+    }
+}
+
+/// Drop code blocks and markdown markup that make no sense read aloud
+pub fn speakable_text(text: &str) -> String {
+    let mut lines = vec![];
+    let mut in_code = false;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            continue;
+        }
+        let line: String = line
+            .trim_start_matches(|c: char| c == '#' || c == '>' || c.is_whitespace())
+            .chars()
+            .filter(|c| !matches!(c, '*' | '_' | '`'))
+            .collect();
+        let line = line.trim();
+        if !line.is_empty() {
+            lines.push(line.to_string());
+        }
+    }
+    lines.join("\n")
+}
+
+fn play(audio: &[u8]) -> Result<()> {
+    let path = env::temp_dir().join(format!("aichat-speech-{}.wav", std::process::id()));
+    fs::write(&path, audio)
+        .with_context(|| format!("Failed to write audio to {}", path.display()))?;
+    let file = path.display().to_string();
+    let candidates = player_commands(&file);
+    let mut played = false;
+    for (program, args) in &candidates {
+        let status = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if matches!(status, Ok(status) if status.success()) {
+            played = true;
+            break;
+        }
+    }
+    let _ = fs::remove_file(&path);
+    if !played {
+        let names: Vec<&str> = candidates.iter().map(|(v, _)| *v).collect();
+        bail!(
+            "Error: No audio player available, install one of {}",
+            names.join(", ")
+        )
+    }
+    Ok(())
+}
+
+fn say(text: &str) -> Result<()> {
+    let candidates = engine_commands();
+    for (program, args) in &candidates {
+        if pipe_to(program, args, text)? {
+            return Ok(());
+        }
+    }
+    let names: Vec<&str> = candidates.iter().map(|(v, _)| *v).collect();
+    bail!(
+        "Error: No speech engine available, install one of {}",
+        names.join(", ")
+    )
+}
+
+/// Run the program with the text on stdin, false when it is missing or fails
+fn pipe_to(program: &str, args: &[&str], text: &str) -> Result<bool> {
+    let child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => return Ok(false),
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(text.as_bytes())
+            .with_context(|| format!("Failed to write to {program}"))?;
+    }
+    let status = child
+        .wait()
+        .with_context(|| format!("Failed to run {program}"))?;
+    Ok(status.success())
+}
+
+fn split_command(command: &str) -> (&str, Vec<&str>) {
+    let mut parts = command.split_whitespace();
+    let program = parts.next().unwrap_or_default();
+    (program, parts.collect())
+}
+
+fn player_commands(file: &str) -> Vec<(&'static str, Vec<String>)> {
+    let file = file.to_string();
+    if cfg!(target_os = "macos") {
+        vec![("afplay", vec![file])]
+    } else if cfg!(windows) {
+        vec![(
+            "powershell",
+            vec![
+                "-NoProfile".into(),
+                "-Command".into(),
+                format!("(New-Object Media.SoundPlayer '{file}').PlaySync()"),
+            ],
+        )]
+    } else {
+        vec![
+            ("paplay", vec![file.clone()]),
+            ("aplay", vec!["-q".into(), file.clone()]),
+            (
+                "ffplay",
+                vec![
+                    "-nodisp".into(),
+                    "-autoexit".into(),
+                    "-loglevel".into(),
+                    "quiet".into(),
+                    file.clone(),
+                ],
+            ),
+            ("mpv", vec!["--really-quiet".into(), file]),
+        ]
+    }
+}
+
+fn engine_commands() -> Vec<(&'static str, Vec<&'static str>)> {
+    if cfg!(target_os = "macos") {
+        vec![("say", vec![])]
+    } else if cfg!(windows) {
+        vec![(
+            "powershell",
+            vec![
+                "-NoProfile",
+                "-Command",
+                "Add-Type -AssemblyName System.Speech; (New-Object System.Speech.Synthesis.SpeechSynthesizer).Speak([Console]::In.ReadToEnd())",
+            ],
+        )]
+    } else {
+        vec![
+            ("espeak-ng", vec!["--stdin"]),
+            ("espeak", vec!["--stdin"]),
+            ("spd-say", vec!["-e", "-w"]),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speakable_text() {
+        let text =
+            "# Title\n\nSome **bold** and `code`.\n\n```rust\nfn main() {}\n```\n> quoted _text_";
+        assert_eq!(
+            speakable_text(text),
+            "Title\nSome bold and code.\nquoted text"
+        );
+    }
+
+    #[test]
+    fn test_split_command() {
+        assert_eq!(split_command("espeak --stdin"), ("espeak", vec!["--stdin"]));
+        assert_eq!(split_command("say"), ("say", vec![]));
+    }
+}
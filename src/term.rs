@@ -1,8 +1,10 @@
 use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
 use crossterm::{
     cursor, queue, style,
     terminal::{self, disable_raw_mode, enable_raw_mode, ClearType},
 };
+use std::env;
 use std::io::{self, Stdout, Write};
 
 pub fn clear_screen(keep_lines: u16) -> Result<()> {
@@ -30,3 +32,172 @@ fn clear_screen_inner(writer: &mut Stdout, keep_lines: u16) -> Result<()> {
     writer.flush()?;
     Ok(())
 }
+
+/// Whether the terminal background is light, from `$COLORFGBG` or by asking the terminal
+pub fn detect_light_background() -> Option<bool> {
+    if let Ok(value) = env::var("COLORFGBG") {
+        if let Some(light) = parse_colorfgbg(&value) {
+            return Some(light);
+        }
+    }
+    query_background().and_then(|v| parse_osc11_reply(&v))
+}
+
+/// The last field is the background as an ansi color index, e.g. `15;0` or `0;default;15`
+fn parse_colorfgbg(value: &str) -> Option<bool> {
+    let bg: u8 = value.rsplit(';').next()?.parse().ok()?;
+    Some(matches!(bg, 7 | 9..=15))
+}
+
+/// Reply looks like `ESC ] 11 ; rgb:ffff/ffff/ffff BEL`
+fn parse_osc11_reply(reply: &str) -> Option<bool> {
+    let (_, rgb) = reply.split_once("rgb:")?;
+    let rgb = rgb.trim_end_matches(['\x07', '\\', '\x1b']);
+    let channels: Vec<f64> = rgb
+        .split('/')
+        .map(|v| {
+            let max = 16f64.powi(v.len() as i32) - 1.0;
+            u32::from_str_radix(v, 16).ok().map(|c| c as f64 / max)
+        })
+        .collect::<Option<_>>()?;
+    if channels.len() != 3 {
+        return None;
+    }
+    let luminance = 0.299 * channels[0] + 0.587 * channels[1] + 0.114 * channels[2];
+    Some(luminance > 0.5)
+}
+
+/// Show a png inline with the kitty or iTerm2 image protocol, false when the terminal has neither
+pub fn print_image(data: &[u8]) -> Result<bool> {
+    let output = match image_protocol() {
+        Some(ImageProtocol::Kitty) => kitty_image(data),
+        Some(ImageProtocol::Iterm) => iterm_image(data),
+        None => return Ok(false),
+    };
+    let mut stdout = io::stdout();
+    stdout.write_all(output.as_bytes())?;
+    stdout.write_all(b"\n")?;
+    stdout.flush()?;
+    Ok(true)
+}
+
+enum ImageProtocol {
+    Kitty,
+    Iterm,
+}
+
+fn image_protocol() -> Option<ImageProtocol> {
+    let term = env::var("TERM").unwrap_or_default();
+    let program = env::var("TERM_PROGRAM").unwrap_or_default();
+    if env::var("KITTY_WINDOW_ID").is_ok() || term == "xterm-kitty" || program == "ghostty" {
+        Some(ImageProtocol::Kitty)
+    } else if matches!(program.as_str(), "iTerm.app" | "WezTerm") {
+        Some(ImageProtocol::Iterm)
+    } else {
+        None
+    }
+}
+
+/// The png is sent base64 encoded in chunks of at most 4096 bytes, `m=1` marks more to come
+fn kitty_image(data: &[u8]) -> String {
+    let encoded = general_purpose::STANDARD.encode(data);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(4096).collect();
+    let mut output = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = (i + 1 < chunks.len()) as u8;
+        let chunk = std::str::from_utf8(chunk).unwrap_or_default();
+        if i == 0 {
+            output.push_str(&format!("\x1b_Gf=100,a=T,m={more};{chunk}\x1b\\"));
+        } else {
+            output.push_str(&format!("\x1b_Gm={more};{chunk}\x1b\\"));
+        }
+    }
+    output
+}
+
+fn iterm_image(data: &[u8]) -> String {
+    let encoded = general_purpose::STANDARD.encode(data);
+    format!("\x1b]1337;File=inline=1;size={}:{encoded}\x07", data.len())
+}
+
+#[cfg(unix)]
+fn query_background() -> Option<String> {
+    use std::fs::OpenOptions;
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::time::{Duration, Instant};
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .ok()?;
+    enable_raw_mode().ok()?;
+    let ret = (|| {
+        tty.write_all(b"\x1b]11;?\x07").ok()?;
+        tty.flush().ok()?;
+        let start = Instant::now();
+        let mut reply = vec![];
+        // terminals without OSC 11 support never answer, so give up after a short while
+        while let Some(remain) = TIMEOUT.checked_sub(start.elapsed()) {
+            let mut fds = libc::pollfd {
+                fd: tty.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ready = unsafe { libc::poll(&mut fds, 1, remain.as_millis() as libc::c_int) };
+            if ready <= 0 {
+                break;
+            }
+            let mut buf = [0u8; 64];
+            let n = tty.read(&mut buf).ok()?;
+            reply.extend_from_slice(&buf[..n]);
+            if reply.ends_with(b"\x07") || reply.ends_with(b"\x1b\\") {
+                return String::from_utf8(reply).ok();
+            }
+        }
+        None
+    })();
+    disable_raw_mode().ok()?;
+    ret
+}
+
+#[cfg(not(unix))]
+fn query_background() -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_colorfgbg() {
+        assert_eq!(parse_colorfgbg("15;0"), Some(false));
+        assert_eq!(parse_colorfgbg("0;default;15"), Some(true));
+        assert_eq!(parse_colorfgbg("default;default"), None);
+    }
+
+    #[test]
+    fn test_parse_osc11_reply() {
+        assert_eq!(
+            parse_osc11_reply("\x1b]11;rgb:ffff/ffff/ffff\x07"),
+            Some(true)
+        );
+        assert_eq!(
+            parse_osc11_reply("\x1b]11;rgb:1e1e/1e1e/2e2e\x1b\\"),
+            Some(false)
+        );
+        assert_eq!(parse_osc11_reply("\x1b]11;?\x07"), None);
+    }
+
+    #[test]
+    fn test_kitty_image() {
+        assert_eq!(kitty_image(b"png"), "\x1b_Gf=100,a=T,m=0;cG5n\x1b\\");
+        let output = kitty_image(&[0; 4000]);
+        assert!(output.starts_with("\x1b_Gf=100,a=T,m=1;"));
+        assert!(output.contains("\x1b\\\x1b_Gm=0;"));
+    }
+}
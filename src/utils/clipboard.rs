@@ -0,0 +1,59 @@
+use anyhow::{bail, Context, Result};
+use arboard::{Clipboard, Error};
+use image::{ImageBuffer, ImageFormat, Rgba};
+use std::io::Cursor;
+
+/// Put text on the system clipboard, X11, Wayland, macOS and Windows alike
+pub fn set_text(text: &str) -> Result<()> {
+    let mut clipboard = open()?;
+    clipboard
+        .set_text(text)
+        .with_context(|| "Failed to copy to the clipboard")
+}
+
+/// Read an image from the system clipboard as png bytes
+pub fn get_image() -> Result<Vec<u8>> {
+    let mut clipboard = open()?;
+    let image = match clipboard.get_image() {
+        Ok(image) => image,
+        Err(Error::ContentNotAvailable | Error::ConversionFailure) => {
+            bail!("Error: No image in the clipboard")
+        }
+        Err(err) => bail!("Error: Failed to read the clipboard, {err}"),
+    };
+    encode_png(
+        image.width as u32,
+        image.height as u32,
+        image.bytes.into_owned(),
+    )
+}
+
+fn open() -> Result<Clipboard> {
+    match Clipboard::new() {
+        Ok(clipboard) => Ok(clipboard),
+        Err(err) => bail!("Error: No clipboard available, {err}"),
+    }
+}
+
+/// The clipboard hands out raw rgba pixels, models want an encoded image
+fn encode_png(width: u32, height: u32, rgba: Vec<u8>) -> Result<Vec<u8>> {
+    let buffer = ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, rgba)
+        .with_context(|| "Invalid image in the clipboard")?;
+    let mut data = Cursor::new(vec![]);
+    buffer
+        .write_to(&mut data, ImageFormat::Png)
+        .with_context(|| "Failed to encode the clipboard image")?;
+    Ok(data.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_png() {
+        let data = encode_png(2, 1, vec![255, 0, 0, 255, 0, 0, 255, 255]).unwrap();
+        assert!(data.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert!(encode_png(2, 2, vec![0; 4]).is_err());
+    }
+}
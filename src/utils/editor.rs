@@ -0,0 +1,36 @@
+use anyhow::{bail, Context, Result};
+use std::env;
+use std::fs::{read_to_string, remove_file, write};
+use std::process::{self, Command};
+
+/// Open `$VISUAL` or `$EDITOR` on a temp file holding `initial`, return what was saved
+pub fn edit_text(initial: &str) -> Result<String> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| default_editor().into());
+    // allow editors with arguments, e.g. `code --wait`
+    let mut parts = editor.split_whitespace();
+    let program = match parts.next() {
+        Some(v) => v,
+        None => bail!("Error: $EDITOR is empty"),
+    };
+    let path = env::temp_dir().join(format!("aichat-{}.md", process::id()));
+    write(&path, initial).with_context(|| format!("Failed to write {}", path.display()))?;
+    let status = Command::new(program).args(parts).arg(&path).status();
+    let content = read_to_string(&path);
+    let _ = remove_file(&path);
+    let status = status.with_context(|| format!("Failed to run editor `{editor}`"))?;
+    if !status.success() {
+        bail!("Error: Editor `{editor}` exited with {status}");
+    }
+    let content = content.with_context(|| "Failed to read edited text")?;
+    Ok(content.trim_end().to_string())
+}
+
+fn default_editor() -> &'static str {
+    if cfg!(windows) {
+        "notepad"
+    } else {
+        "vi"
+    }
+}
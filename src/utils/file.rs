@@ -0,0 +1,368 @@
+use crate::client::{build_plain_http_client, init_runtime};
+use crate::config::SharedConfig;
+use crate::utils::count_tokens;
+
+use anyhow::{bail, Context, Result};
+use futures_util::StreamExt;
+use ignore::WalkBuilder;
+use std::fs::{read_to_string, File};
+use std::io::Read;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Default cap of the bytes downloaded from a url
+const FETCH_MAX_BYTES: usize = 1_000_000;
+
+/// Read files or fetch urls and wrap each of them in a fenced code block headed by its path.
+/// The text of pdf and docx files is extracted, `report.pdf:3-10` picks pages of a pdf,
+/// directories bring their text files.
+pub fn load_files(config: &SharedConfig, paths: &[String]) -> Result<String> {
+    let mut blocks = vec![];
+    for path in paths {
+        if is_url(path) {
+            let content = fetch_url(config, path)?;
+            blocks.push(wrap_code_block(path, "", &content));
+            continue;
+        }
+        if Path::new(path).is_dir() {
+            blocks.push(load_dir(config, path)?);
+            continue;
+        }
+        let (file, pages) = split_page_range(path)?;
+        let ext = Path::new(file)
+            .extension()
+            .and_then(|v| v.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        if pages.is_some() && ext != "pdf" {
+            bail!("Error: Page ranges only apply to pdf files, not `{file}`");
+        }
+        let block = match ext.as_str() {
+            "pdf" => wrap_code_block(path, "", &read_pdf(file, pages)?),
+            "docx" => wrap_code_block(path, "", &read_docx(file)?),
+            _ => {
+                let content =
+                    read_to_string(file).with_context(|| format!("Failed to read {file}"))?;
+                wrap_code_block(path, &ext, &content)
+            }
+        };
+        blocks.push(block);
+    }
+    Ok(blocks.join("\n\n"))
+}
+
+/// Text files of a directory, following `.gitignore` and skipping hidden files and binaries.
+/// Past half the input budget, a tree of all files comes first and only the files that fit follow.
+fn load_dir(config: &SharedConfig, dir: &str) -> Result<String> {
+    let budget = config.lock().get_max_input_tokens() / 2;
+    let walker = WalkBuilder::new(dir)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    let mut files = vec![];
+    for entry in walker {
+        let entry = entry.with_context(|| format!("Failed to read {dir}"))?;
+        if !entry.file_type().is_some_and(|v| v.is_file()) {
+            continue;
+        }
+        let path = entry.path();
+        let content = match read_text(path) {
+            Some(v) => v,
+            None => continue,
+        };
+        let lang = path
+            .extension()
+            .and_then(|v| v.to_str())
+            .unwrap_or_default();
+        let block = wrap_code_block(&path.display().to_string(), lang, &content);
+        let tokens = count_tokens(&block);
+        let name = path.strip_prefix(dir).unwrap_or(path).to_path_buf();
+        files.push((name, block, tokens));
+    }
+    if files.is_empty() {
+        bail!("Error: No text files in {dir}");
+    }
+    let total: usize = files.iter().map(|v| v.2).sum();
+    if total <= budget {
+        let blocks: Vec<String> = files.into_iter().map(|v| v.1).collect();
+        return Ok(blocks.join("\n\n"));
+    }
+    let mut used = 0;
+    let mut blocks = vec![];
+    let mut entries = vec![];
+    for (name, block, tokens) in files {
+        let attached = used + tokens <= budget;
+        if attached {
+            used += tokens;
+            blocks.push(block);
+        }
+        entries.push((name, tokens, attached));
+    }
+    let summary = format!(
+        "{dir} has {} files of {total} tokens, over the budget of {budget}, only the files marked with * are attached",
+        entries.len()
+    );
+    let tree = wrap_code_block(&summary, "", &render_tree(&entries));
+    blocks.insert(0, tree);
+    Ok(blocks.join("\n\n"))
+}
+
+/// None for binaries, told apart by a nul byte at the start or invalid utf-8
+fn read_text(path: &Path) -> Option<String> {
+    let data = std::fs::read(path).ok()?;
+    if data[..data.len().min(8000)].contains(&0) {
+        return None;
+    }
+    String::from_utf8(data).ok()
+}
+
+/// One line per directory and file, indented by depth, files with their tokens
+fn render_tree(entries: &[(PathBuf, usize, bool)]) -> String {
+    let mut lines = vec![];
+    let mut shown: Vec<String> = vec![];
+    for (path, tokens, attached) in entries {
+        let mut parts: Vec<String> = path
+            .components()
+            .map(|v| v.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let name = parts.pop().unwrap_or_default();
+        let common = shown.iter().zip(&parts).take_while(|(a, b)| a == b).count();
+        shown.truncate(common);
+        for part in &parts[common..] {
+            lines.push(format!("{}{part}/", "  ".repeat(shown.len())));
+            shown.push(part.clone());
+        }
+        let mark = if *attached { "* " } else { "" };
+        lines.push(format!(
+            "{}{mark}{name} ({tokens} tokens)",
+            "  ".repeat(shown.len())
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Split `report.pdf:3-10` or `report.pdf:3` into the file and its pages, counted from 1
+fn split_page_range(path: &str) -> Result<(&str, Option<RangeInclusive<usize>>)> {
+    let (file, range) = match path.rsplit_once(':') {
+        Some(v) if !Path::new(path).exists() && !v.1.is_empty() => v,
+        _ => return Ok((path, None)),
+    };
+    if !range.chars().all(|c| c.is_ascii_digit() || c == '-') {
+        return Ok((path, None));
+    }
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let pages = match (start.parse::<usize>(), end.parse::<usize>()) {
+        (Ok(start), Ok(end)) if start >= 1 && start <= end => start..=end,
+        _ => bail!("Error: Invalid page range `{range}`, expected e.g. `3-10`"),
+    };
+    Ok((file, Some(pages)))
+}
+
+fn read_pdf(file: &str, pages: Option<RangeInclusive<usize>>) -> Result<String> {
+    let data = std::fs::read(file).with_context(|| format!("Failed to read {file}"))?;
+    let texts = pdf_extract::extract_text_from_mem_by_pages(&data)
+        .with_context(|| format!("Failed to extract the text of {file}"))?;
+    let texts = match pages {
+        Some(pages) => {
+            if *pages.start() > texts.len() {
+                bail!("Error: {file} has only {} pages", texts.len());
+            }
+            let end = (*pages.end()).min(texts.len());
+            texts[pages.start() - 1..end].to_vec()
+        }
+        None => texts,
+    };
+    Ok(texts
+        .iter()
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n"))
+}
+
+fn read_docx(file: &str) -> Result<String> {
+    let extract = || -> Result<String> {
+        let mut archive = zip::ZipArchive::new(File::open(file)?)?;
+        let mut xml = String::new();
+        archive
+            .by_name("word/document.xml")?
+            .read_to_string(&mut xml)?;
+        Ok(docx_text(&xml))
+    };
+    extract().with_context(|| format!("Failed to extract the text of {file}"))
+}
+
+/// Text of the runs in `word/document.xml`, one line per paragraph
+fn docx_text(xml: &str) -> String {
+    let mut paragraphs = vec![];
+    let mut paragraph = String::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(v) => start + v,
+            None => break,
+        };
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+        let name = tag.split_whitespace().next().unwrap_or_default();
+        match name {
+            "w:t" if !tag.ends_with('/') => {
+                let close = rest.find("</w:t>").unwrap_or(rest.len());
+                paragraph.push_str(&unescape_xml(&rest[..close]));
+                rest = &rest[close..];
+            }
+            // tab stops of the paragraph style carry attributes, tabs in the text do not
+            "w:tab/" => paragraph.push('\t'),
+            "w:br" | "w:br/" | "w:cr/" => paragraph.push('\n'),
+            "/w:p" => paragraphs.push(std::mem::take(&mut paragraph)),
+            _ => {}
+        }
+    }
+    paragraphs.join("\n").trim().to_string()
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Download a page through the configured proxy, html is converted to text
+fn fetch_url(config: &SharedConfig, url: &str) -> Result<String> {
+    let max_bytes = config.lock().fetch_max_bytes.unwrap_or(FETCH_MAX_BYTES);
+    let client = build_plain_http_client(config)?;
+    let runtime = init_runtime()?;
+    let (content_type, body, truncated) = runtime
+        .block_on(async {
+            let res = client.get(url).send().await?;
+            let status = res.status();
+            if !status.is_success() {
+                bail!("Request failed with {status}");
+            }
+            let content_type = res
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            let mut body = vec![];
+            let mut truncated = false;
+            let mut stream = res.bytes_stream();
+            while let Some(chunk) = stream.next().await {
+                body.extend_from_slice(&chunk?);
+                if body.len() > max_bytes {
+                    body.truncate(max_bytes);
+                    truncated = true;
+                    break;
+                }
+            }
+            Ok((content_type, body, truncated))
+        })
+        .with_context(|| format!("Failed to fetch {url}"))?;
+    let mut text = decode_body(&content_type, &body);
+    if truncated {
+        text.push_str("\n[truncated]");
+    }
+    Ok(text)
+}
+
+fn decode_body(content_type: &str, body: &[u8]) -> String {
+    if content_type.contains("html") {
+        html2text::from_read(body, 100)
+    } else {
+        String::from_utf8_lossy(body).to_string()
+    }
+}
+
+pub fn wrap_code_block(path: &str, lang: &str, content: &str) -> String {
+    // the fence must be longer than any backtick run inside the content
+    let mut max_run = 0;
+    let mut run = 0;
+    for ch in content.chars() {
+        if ch == '`' {
+            run += 1;
+            max_run = max_run.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    let fence = "`".repeat((max_run + 1).max(3));
+    format!("{path}:\n{fence}{lang}\n{}\n{fence}", content.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_code_block() {
+        assert_eq!(
+            wrap_code_block("src/main.rs", "rs", "fn main() {}\n"),
+            "src/main.rs:\n```rs\nfn main() {}\n```"
+        );
+        assert_eq!(
+            wrap_code_block("README.md", "md", "```sh\nls\n```"),
+            "README.md:\n````md\n```sh\nls\n```\n````"
+        );
+    }
+
+    #[test]
+    fn test_split_page_range() {
+        assert_eq!(
+            split_page_range("report.pdf:3-10").unwrap(),
+            ("report.pdf", Some(3..=10))
+        );
+        assert_eq!(
+            split_page_range("report.pdf:4").unwrap(),
+            ("report.pdf", Some(4..=4))
+        );
+        assert_eq!(
+            split_page_range("report.pdf").unwrap(),
+            ("report.pdf", None)
+        );
+        assert_eq!(
+            split_page_range("C:\\notes.txt").unwrap(),
+            ("C:\\notes.txt", None)
+        );
+        assert!(split_page_range("report.pdf:10-3").is_err());
+        assert!(split_page_range("report.pdf:0").is_err());
+    }
+
+    #[test]
+    fn test_render_tree() {
+        let entries = vec![
+            (PathBuf::from("Cargo.toml"), 300, true),
+            (PathBuf::from("src/client/mod.rs"), 5000, false),
+            (PathBuf::from("src/client/openai.rs"), 4000, false),
+            (PathBuf::from("src/main.rs"), 2000, true),
+        ];
+        assert_eq!(
+            render_tree(&entries),
+            "* Cargo.toml (300 tokens)\nsrc/\n  client/\n    mod.rs (5000 tokens)\n    openai.rs (4000 tokens)\n  * main.rs (2000 tokens)"
+        );
+    }
+
+    #[test]
+    fn test_docx_text() {
+        let xml = r#"<w:document><w:body><w:p><w:pPr><w:tabs><w:tab w:val="left" w:pos="720"/></w:tabs></w:pPr><w:r><w:t>Hello</w:t></w:r><w:r><w:tab/><w:t xml:space="preserve">Tom &amp; Jerry</w:t></w:r></w:p><w:p><w:r><w:t>Line</w:t><w:br/><w:t>two</w:t></w:r></w:p></w:body></w:document>"#;
+        assert_eq!(docx_text(xml), "Hello\tTom & Jerry\nLine\ntwo");
+    }
+
+    #[test]
+    fn test_decode_body() {
+        assert!(is_url("https://example.com/page"));
+        assert!(!is_url("src/main.rs"));
+        let html = b"<html><head><script>var x;</script></head><body><h1>Title</h1><p>Hello <b>world</b></p></body></html>";
+        let text = decode_body("text/html; charset=utf-8", html);
+        assert!(text.contains("Title"));
+        assert!(text.contains("Hello world"));
+        assert!(!text.contains("<p>"));
+        assert_eq!(decode_body("text/plain", b"<p>"), "<p>");
+    }
+}
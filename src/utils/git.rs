@@ -0,0 +1,39 @@
+use anyhow::{bail, Context, Result};
+use std::process::{Command, Stdio};
+
+/// Changes of the working tree, or of the index with `staged`
+pub fn git_diff(staged: bool) -> Result<String> {
+    // outside a repository git diff compares paths instead, so check first
+    let inside = Command::new("git")
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .with_context(|| "Failed to run git, is it installed?")?;
+    if !inside.status.success() {
+        bail!("Not in a git repository");
+    }
+    let mut args = vec!["diff", "--no-color"];
+    if staged {
+        args.push("--staged");
+    }
+    let output = Command::new("git")
+        .args(&args)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| "Failed to run git, is it installed?")?;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        bail!("git diff failed, {}", err.trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Run `git commit` with the message, returns its exit code
+pub fn git_commit(message: &str) -> Result<i32> {
+    let status = Command::new("git")
+        .args(["commit", "-m", message])
+        .status()
+        .with_context(|| "Failed to run git, is it installed?")?;
+    Ok(status.code().unwrap_or_default())
+}
@@ -1,6 +1,16 @@
+mod clipboard;
+mod editor;
+mod file;
+mod git;
+mod shell;
 mod tiktoken;
 
-pub use self::tiktoken::{cl100k_base_singleton, count_tokens, text_to_tokens, tokens_to_text};
+pub use self::clipboard::{get_image as get_clipboard_image, set_text as set_clipboard_text};
+pub use self::editor::edit_text;
+pub use self::file::{load_files, wrap_code_block};
+pub use self::git::{git_commit, git_diff};
+pub use self::shell::{command_dangers, detect_os, detect_shell};
+pub use self::tiktoken::{cl100k_base_singleton, count_tokens};
 
 use chrono::prelude::*;
 use crossterm::style::{Color, Stylize};
@@ -27,3 +37,57 @@ pub fn now() -> String {
 pub fn emphasis(text: &str) -> String {
     text.stylize().with(Color::White).to_string()
 }
+
+/// Body of the last fenced code block, an unterminated block counts too
+pub fn last_code_block(text: &str) -> Option<String> {
+    code_blocks(text).pop()
+}
+
+/// Just the code of a reply, the bodies of its fenced code blocks or the whole reply without any
+pub fn extract_code(text: &str) -> String {
+    let blocks = code_blocks(text);
+    if blocks.is_empty() {
+        text.trim().to_string()
+    } else {
+        blocks.join("\n").trim_end().to_string()
+    }
+}
+
+fn code_blocks(text: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut current: Option<Vec<&str>> = None;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            match current.take() {
+                Some(lines) => blocks.push(lines.join("\n")),
+                None => current = Some(vec![]),
+            }
+        } else if let Some(lines) = current.as_mut() {
+            lines.push(line);
+        }
+    }
+    if let Some(lines) = current {
+        blocks.push(lines.join("\n"));
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_code_block() {
+        let text = "a\n```sh\nls\n```\nb\n```rust\nfn main() {}\n\n```\nc";
+        assert_eq!(last_code_block(text), Some("fn main() {}\n".into()));
+        assert_eq!(last_code_block("```\nls"), Some("ls".into()));
+        assert_eq!(last_code_block("no code"), None);
+    }
+
+    #[test]
+    fn test_extract_code() {
+        let text = "Here you go:\n```sh\nls\n```\nthen\n```sh\npwd\n```\nDone.";
+        assert_eq!(extract_code(text), "ls\npwd");
+        assert_eq!(extract_code("  echo hi\n"), "echo hi");
+    }
+}